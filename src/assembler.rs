@@ -0,0 +1,626 @@
+use std::collections::HashMap;
+
+use crate::bus::Mem;
+use crate::cpu::{AddressingMode, OpCode, Variant};
+
+// CPU の命令表を使った小さな 6502 アセンブラ (テスト用)
+//
+//   .org $8000
+//   start:  LDX #$00
+//   loop:   LDA table,X
+//           STA ($10),Y
+//           INX
+//           BNE loop
+//   table:  .byte $01, $02, %00000011
+//           .word start
+//   PTR = $10
+//
+// 数値は $ (16 進)、% (2 進)、10 進。式は label+1 や <label / >label (下位/上位バイト) が書ける。
+// a:$0010 のように a: を付けると、1 バイトに収まっても絶対アドレスにする (ca65 と同じ)。
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    pub labels: HashMap<String, u16>,
+}
+
+impl Program {
+    pub fn load<M: Mem>(&self, mem: &mut M) {
+        for segment in self.segments.iter() {
+            for (i, v) in segment.bytes.iter().enumerate() {
+                mem.mem_write(segment.origin.wrapping_add(i as u16), *v);
+            }
+        }
+    }
+
+    pub fn label(&self, name: &str) -> u16 {
+        self.labels[name]
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, String> {
+    assemble_for(Variant::Ricoh2A03, source)
+}
+
+// variant の命令表でアセンブルする (65C02 の命令を使うとき)
+pub fn assemble_for(variant: Variant, source: &str) -> Result<Program, String> {
+    let mut statements = vec![];
+    for (n, line) in source.lines().enumerate() {
+        parse_line(n + 1, line, &mut statements).map_err(|e| format!("line {}: {}", n + 1, e))?;
+    }
+
+    // 1 パス目: アドレスを決める。この時点で値が分からないオペランドは 2 バイトとみなす
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut sized = vec![];
+    let mut pc: u16 = 0;
+    for (line, statement) in statements {
+        let at_line = |e: String| format!("line {}: {}", line, e);
+        match statement {
+            Statement::Label(name) => {
+                if labels.insert(name.clone(), pc).is_some() {
+                    return Err(at_line(format!("label {} is already defined", name)));
+                }
+            }
+            Statement::Constant(name, expr) => {
+                let value = expr.eval(&labels, pc).map_err(at_line)?;
+                labels.insert(name, value);
+            }
+            Statement::Org(expr) => {
+                pc = expr.eval(&labels, pc).map_err(at_line)?;
+                sized.push((line, pc, Sized::Org));
+            }
+            Statement::Bytes(exprs) => {
+                sized.push((line, pc, Sized::Bytes(exprs.clone())));
+                pc = pc.wrapping_add(exprs.len() as u16);
+            }
+            Statement::Words(exprs) => {
+                sized.push((line, pc, Sized::Words(exprs.clone())));
+                pc = pc.wrapping_add(exprs.len() as u16 * 2);
+            }
+            Statement::Instruction(mnemonic, operand) => {
+                let op = select_opcode(variant.opcodes(), &mnemonic, &operand, &labels, pc)
+                    .map_err(at_line)?;
+                sized.push((line, pc, Sized::Instruction(op.clone(), operand)));
+                pc = pc.wrapping_add(op.bytes);
+            }
+        }
+    }
+
+    // 2 パス目: 出力する
+    let mut segments: Vec<Segment> = vec![];
+    for (line, pc, statement) in sized {
+        let at_line = |e: String| format!("line {}: {}", line, e);
+        let bytes = match statement {
+            Sized::Org => {
+                segments.push(Segment {
+                    origin: pc,
+                    bytes: vec![],
+                });
+                continue;
+            }
+            Sized::Bytes(exprs) => {
+                let mut bytes = vec![];
+                for expr in exprs {
+                    bytes.push(byte(expr.eval(&labels, pc).map_err(at_line)?).map_err(at_line)?);
+                }
+                bytes
+            }
+            Sized::Words(exprs) => {
+                let mut bytes = vec![];
+                for expr in exprs {
+                    let value = expr.eval(&labels, pc).map_err(at_line)?;
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                bytes
+            }
+            Sized::Instruction(op, operand) => {
+                encode(&op, &operand, &labels, pc).map_err(at_line)?
+            }
+        };
+        if segments.is_empty() {
+            segments.push(Segment {
+                origin: pc,
+                bytes: vec![],
+            });
+        }
+        segments.last_mut().unwrap().bytes.extend(bytes);
+    }
+    segments.retain(|segment| !segment.bytes.is_empty());
+
+    Ok(Program { segments, labels })
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Label(String),
+    Constant(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Instruction(String, Operand),
+}
+
+enum Sized {
+    Org,
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Instruction(OpCode, Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    DirectX(Expr),
+    DirectY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Number(u16),
+    Label(String),
+    Here,
+}
+
+// [a:] [<|>] term (+|- term)*
+#[derive(Debug, Clone, PartialEq)]
+struct Expr {
+    absolute: bool,
+    part: Option<char>,
+    terms: Vec<(bool, Term)>,
+}
+
+impl Expr {
+    fn parse(text: &str) -> Result<Expr, String> {
+        let text = text.trim();
+        let (absolute, text) = match text.strip_prefix("a:").or(text.strip_prefix("A:")) {
+            Some(rest) => (true, rest.trim()),
+            None => (false, text),
+        };
+        let (part, text) = match text.chars().next() {
+            Some(c @ '<') | Some(c @ '>') => (Some(c), text[1..].trim()),
+            _ => (None, text),
+        };
+        if text.is_empty() {
+            return Err("missing operand".to_string());
+        }
+        let mut terms = vec![];
+        let mut negative = false;
+        let mut current = String::new();
+        for c in text.chars() {
+            if (c == '+' || c == '-') && !current.trim().is_empty() {
+                terms.push((negative, Term::parse(current.trim())?));
+                current.clear();
+                negative = c == '-';
+            } else if c == '-' {
+                negative = !negative;
+            } else {
+                current.push(c);
+            }
+        }
+        terms.push((negative, Term::parse(current.trim())?));
+        Ok(Expr {
+            absolute,
+            part,
+            terms,
+        })
+    }
+
+    fn known(&self, labels: &HashMap<String, u16>) -> bool {
+        self.terms.iter().all(|(_, term)| match term {
+            Term::Label(name) => labels.contains_key(name),
+            _ => true,
+        })
+    }
+
+    fn eval(&self, labels: &HashMap<String, u16>, pc: u16) -> Result<u16, String> {
+        let mut value: u16 = 0;
+        for (negative, term) in self.terms.iter() {
+            let v = match term {
+                Term::Number(n) => *n,
+                Term::Here => pc,
+                Term::Label(name) => match labels.get(name) {
+                    Some(v) => *v,
+                    None => return Err(format!("undefined label {}", name)),
+                },
+            };
+            value = if *negative {
+                value.wrapping_sub(v)
+            } else {
+                value.wrapping_add(v)
+            };
+        }
+        Ok(match self.part {
+            Some('<') => value & 0xFF,
+            Some('>') => value >> 8,
+            _ => value,
+        })
+    }
+}
+
+impl Term {
+    fn parse(text: &str) -> Result<Term, String> {
+        let number = if let Some(hex) = text.strip_prefix('$') {
+            u16::from_str_radix(hex, 16)
+        } else if let Some(bin) = text.strip_prefix('%') {
+            u16::from_str_radix(bin, 2)
+        } else if text == "*" {
+            return Ok(Term::Here);
+        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+            text.parse::<u16>()
+        } else if !text.is_empty() && text.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Ok(Term::Label(text.to_string()));
+        } else {
+            return Err(format!("invalid expression {:?}", text));
+        };
+        number
+            .map(Term::Number)
+            .map_err(|_| format!("invalid number {:?}", text))
+    }
+}
+
+fn parse_line(
+    line_number: usize,
+    line: &str,
+    statements: &mut Vec<(usize, Statement)>,
+) -> Result<(), String> {
+    let code = match line.find(';') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let mut rest = code.trim();
+    let mut push = |statement: Statement| statements.push((line_number, statement));
+
+    // label:
+    if let Some(pos) = rest.find(':') {
+        let name = rest[..pos].trim();
+        if is_identifier(name) {
+            push(Statement::Label(name.to_string()));
+            rest = rest[pos + 1..].trim();
+        }
+    }
+    if rest.is_empty() {
+        return Ok(());
+    }
+
+    // NAME = expr
+    if let Some(pos) = rest.find('=') {
+        let name = rest[..pos].trim();
+        if !is_identifier(name) {
+            return Err(format!("invalid constant name {:?}", name));
+        }
+        push(Statement::Constant(
+            name.to_string(),
+            Expr::parse(&rest[pos + 1..])?,
+        ));
+        return Ok(());
+    }
+
+    let (word, args) = match rest.find(char::is_whitespace) {
+        Some(pos) => (&rest[..pos], rest[pos..].trim()),
+        None => (rest, ""),
+    };
+    let statement = match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(Expr::parse(args)?),
+        ".byte" | ".db" => Statement::Bytes(parse_list(args)?),
+        ".word" | ".dw" => Statement::Words(parse_list(args)?),
+        _ if word.starts_with('.') => return Err(format!("unknown directive {}", word)),
+        _ => Statement::Instruction(word.to_ascii_uppercase(), parse_operand(args)?),
+    };
+    push(statement);
+    Ok(())
+}
+
+fn is_identifier(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn parse_list(args: &str) -> Result<Vec<Expr>, String> {
+    args.split(',').map(Expr::parse).collect()
+}
+
+fn parse_operand(args: &str) -> Result<Operand, String> {
+    let compact: String = args.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = compact.to_ascii_uppercase();
+    let without = |suffix: &str, start: usize| &compact[start..compact.len() - suffix.len()];
+    if compact.is_empty() {
+        Ok(Operand::None)
+    } else if upper == "A" {
+        Ok(Operand::Accumulator)
+    } else if upper.starts_with('#') {
+        Ok(Operand::Immediate(Expr::parse(&compact[1..])?))
+    } else if upper.starts_with('(') && upper.ends_with(",X)") {
+        Ok(Operand::IndirectX(Expr::parse(without(",X)", 1))?))
+    } else if upper.starts_with('(') && upper.ends_with("),Y") {
+        Ok(Operand::IndirectY(Expr::parse(without("),Y", 1))?))
+    } else if upper.starts_with('(') && upper.ends_with(')') {
+        Ok(Operand::Indirect(Expr::parse(without(")", 1))?))
+    } else if upper.ends_with(",X") {
+        Ok(Operand::DirectX(Expr::parse(without(",X", 0))?))
+    } else if upper.ends_with(",Y") {
+        Ok(Operand::DirectY(Expr::parse(without(",Y", 0))?))
+    } else {
+        Ok(Operand::Direct(Expr::parse(&compact)?))
+    }
+}
+
+// 公式の命令を優先する。オペランドが 1 バイトに収まると分かっていればゼロページを使う
+fn select_opcode(
+    ops: &[OpCode],
+    mnemonic: &str,
+    operand: &Operand,
+    labels: &HashMap<String, u16>,
+    pc: u16,
+) -> Result<OpCode, String> {
+    let candidates: Vec<&OpCode> = ops
+        .iter()
+        .filter(|op| op.name == mnemonic)
+        .chain(
+            ops.iter()
+                .filter(|op| op.name.strip_prefix('*') == Some(mnemonic)),
+        )
+        .collect();
+    if candidates.is_empty() {
+        return Err(format!("unknown instruction {}", mnemonic));
+    }
+    let find = |mode: AddressingMode| candidates.iter().find(|op| op.addressing_mode == mode);
+
+    let zero_page = |expr: &Expr| {
+        !expr.absolute && expr.known(labels) && expr.eval(labels, pc).map(|v| v <= 0xFF) == Ok(true)
+    };
+    let modes = match operand {
+        Operand::None => vec![AddressingMode::Implied, AddressingMode::Accumulator],
+        Operand::Accumulator => vec![AddressingMode::Accumulator],
+        Operand::Immediate(_) => vec![AddressingMode::Immediate],
+        Operand::Direct(expr) if zero_page(expr) => vec![
+            AddressingMode::Relative,
+            AddressingMode::ZeroPage,
+            AddressingMode::Absolute,
+        ],
+        Operand::Direct(_) => vec![
+            AddressingMode::Relative,
+            AddressingMode::Absolute,
+            AddressingMode::ZeroPage,
+        ],
+        Operand::DirectX(expr) if zero_page(expr) => {
+            vec![AddressingMode::ZeroPage_X, AddressingMode::Absolute_X]
+        }
+        Operand::DirectX(_) => vec![AddressingMode::Absolute_X, AddressingMode::ZeroPage_X],
+        Operand::DirectY(expr) if zero_page(expr) => {
+            vec![AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y]
+        }
+        Operand::DirectY(_) => vec![AddressingMode::Absolute_Y, AddressingMode::ZeroPage_Y],
+        // JMP ($1234) と 65C02 の LDA ($10)、LDA ($10,X) と 65C02 の JMP ($1234,X)
+        Operand::Indirect(_) => vec![AddressingMode::Indirect, AddressingMode::ZeroPage_Indirect],
+        Operand::IndirectX(_) => vec![
+            AddressingMode::Indirect_X,
+            AddressingMode::Absolute_Indirect_X,
+        ],
+        Operand::IndirectY(_) => vec![AddressingMode::Indirect_Y],
+    };
+    for mode in modes {
+        if let Some(op) = find(mode) {
+            return Ok((*op).clone());
+        }
+    }
+    Err(format!(
+        "{} does not support operand {:?}",
+        mnemonic, operand
+    ))
+}
+
+fn encode(
+    op: &OpCode,
+    operand: &Operand,
+    labels: &HashMap<String, u16>,
+    pc: u16,
+) -> Result<Vec<u8>, String> {
+    let expr = match operand {
+        Operand::None | Operand::Accumulator => return Ok(vec![op.code]),
+        Operand::Immediate(expr)
+        | Operand::Direct(expr)
+        | Operand::DirectX(expr)
+        | Operand::DirectY(expr)
+        | Operand::Indirect(expr)
+        | Operand::IndirectX(expr)
+        | Operand::IndirectY(expr) => expr,
+    };
+    let value = expr.eval(labels, pc)?;
+    match op.addressing_mode {
+        AddressingMode::Relative => {
+            let offset = value as i32 - (pc as i32 + 2);
+            if !(-128..=127).contains(&offset) {
+                return Err(format!("branch target ${:04X} is out of range", value));
+            }
+            Ok(vec![op.code, offset as i8 as u8])
+        }
+        _ if op.bytes == 2 => Ok(vec![op.code, byte(value)?]),
+        _ => {
+            let [lo, hi] = value.to_le_bytes();
+            Ok(vec![op.code, lo, hi])
+        }
+    }
+}
+
+fn byte(value: u16) -> Result<u8, String> {
+    // -1 なども 1 バイトとして書けるようにする
+    if value <= 0xFF || value >= 0xFF80 {
+        Ok(value as u8)
+    } else {
+        Err(format!("value ${:04X} does not fit in a byte", value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::CPU;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        let program = assemble(source).unwrap();
+        assert_eq!(program.segments.len(), 1);
+        program.segments[0].bytes.clone()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        assert_eq!(bytes("LDA #$05"), vec![0xA9, 0x05]);
+        assert_eq!(bytes("lda $10"), vec![0xA5, 0x10]);
+        assert_eq!(bytes("LDA $0010"), vec![0xA5, 0x10]);
+        assert_eq!(bytes("LDA $1234"), vec![0xAD, 0x34, 0x12]);
+        assert_eq!(bytes("LDA $10,X"), vec![0xB5, 0x10]);
+        assert_eq!(bytes("LDA $1234, x"), vec![0xBD, 0x34, 0x12]);
+        assert_eq!(bytes("LDX $10,Y"), vec![0xB6, 0x10]);
+        assert_eq!(bytes("LDA $10,Y"), vec![0xB9, 0x10, 0x00]);
+        assert_eq!(bytes("LDA ($10,X)"), vec![0xA1, 0x10]);
+        assert_eq!(bytes("LDA ($10),Y"), vec![0xB1, 0x10]);
+        assert_eq!(bytes("JMP ($1234)"), vec![0x6C, 0x34, 0x12]);
+        assert_eq!(bytes("ASL A"), vec![0x0A]);
+        assert_eq!(bytes("ASL"), vec![0x0A]);
+        assert_eq!(bytes("INX"), vec![0xE8]);
+        assert_eq!(bytes("LDA #%1010"), vec![0xA9, 0x0A]);
+        assert_eq!(bytes("LDA #10"), vec![0xA9, 0x0A]);
+        assert_eq!(bytes("LDA a:$10"), vec![0xAD, 0x10, 0x00]);
+        assert_eq!(bytes("LDA a:$00F0,X"), vec![0xBD, 0xF0, 0x00]);
+    }
+
+    #[test]
+    fn test_cmos_instructions() {
+        let program = assemble_for(
+            Variant::Cmos65C02,
+            "BRA next\nnext: STZ $10\nLDA ($10)\nJMP ($1234,X)\nPHX",
+        )
+        .unwrap();
+        assert_eq!(
+            program.segments[0].bytes,
+            vec![0x80, 0x00, 0x64, 0x10, 0xB2, 0x10, 0x7C, 0x34, 0x12, 0xDA]
+        );
+        // NMOS にはない
+        assert!(assemble("STZ $10").is_err());
+    }
+
+    #[test]
+    fn test_unofficial_opcodes() {
+        assert_eq!(bytes("LAX $10"), vec![0xA7, 0x10]);
+        assert_eq!(bytes("NOP"), vec![0xEA]);
+        assert_eq!(bytes("NOP $10"), vec![0x04, 0x10]);
+        assert_eq!(bytes("SBC #$01"), vec![0xE9, 0x01]);
+    }
+
+    #[test]
+    fn test_labels_and_directives() {
+        let program = assemble(
+            "
+            .org $0600
+            PTR = $10
+            start:  LDX #<table     ; comment
+                    LDA table+1
+                    STA PTR
+            loop:   DEX
+                    BNE loop
+                    BEQ end
+                    JMP start
+            end:    RTS
+            table:  .byte $01, 2, %11, -1
+                    .word start, >table
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.label("start"), 0x0600);
+        assert_eq!(program.label("PTR"), 0x10);
+        assert_eq!(
+            program.segments,
+            vec![Segment {
+                origin: 0x0600,
+                bytes: vec![
+                    0xA2, 0x10, // LDX #<table
+                    0xAD, 0x11, 0x06, // LDA table+1 (前方参照なので絶対)
+                    0x85, 0x10, // STA PTR
+                    0xCA, // loop: DEX
+                    0xD0, 0xFD, // BNE loop
+                    0xF0, 0x03, // BEQ end
+                    0x4C, 0x00, 0x06, // JMP start
+                    0x60, // end: RTS
+                    0x01, 0x02, 0x03, 0xFF, // table
+                    0x00, 0x06, 0x06, 0x00,
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_multiple_segments() {
+        let program = assemble(".org $8000\nreset: NOP\n.org $FFFC\n.word reset, 0").unwrap();
+        assert_eq!(
+            program.segments,
+            vec![
+                Segment {
+                    origin: 0x8000,
+                    bytes: vec![0xEA]
+                },
+                Segment {
+                    origin: 0xFFFC,
+                    bytes: vec![0x00, 0x80, 0x00, 0x00]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble("NOP\nFOO #1").unwrap_err(),
+            "line 2: unknown instruction FOO"
+        );
+        assert_eq!(
+            assemble("JMP nowhere").unwrap_err(),
+            "line 1: undefined label nowhere"
+        );
+        assert!(assemble("STA #$01").is_err());
+        assert!(assemble(".org $0600\nBNE far\n.org $0700\nfar: NOP").is_err());
+        assert!(assemble("x: NOP\nx: NOP").is_err());
+    }
+
+    #[test]
+    fn test_run_assembled_program() {
+        let program = assemble(
+            "
+            .org $0600
+                    LDX #$00
+                    LDY #$00
+            loop:   TXA
+                    STA $0200,Y
+                    INX
+                    INX
+                    INY
+                    CPY #$08
+                    BNE loop
+                    BRK
+            ",
+        )
+        .unwrap();
+        let mut bus = Bus::new(test_rom());
+        program.load(&mut bus);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        crate::cpu::test::run_to_brk(&mut cpu);
+        for i in 0..8 {
+            assert_eq!(cpu.peek(0x0200 + i), i as u8 * 2);
+        }
+    }
+}
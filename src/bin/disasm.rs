@@ -0,0 +1,73 @@
+use famicom_project::cartridge::load_rom;
+use famicom_project::cpu::Variant;
+use famicom_project::disasm::{disassemble, disassemble_rom, listing_with_symbols, vectors};
+use famicom_project::symbols::SymbolTable;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+fn usage() -> ! {
+    eprintln!("usage: disasm [--recursive] [--symbols=file] <file.nes>");
+    std::process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let recursive = args.iter().any(|arg| arg == "--recursive" || arg == "-r");
+    let path = match args.iter().find(|arg| !arg.starts_with('-')) {
+        Some(path) => path,
+        None => usage(),
+    };
+    let rom = load_rom(path);
+    // .dbg / .nl / .mlb (何個でも)
+    let mut symbols = SymbolTable::new();
+    for arg in &args {
+        if let Some(file) = arg.strip_prefix("--symbols=") {
+            match SymbolTable::load(file) {
+                Ok(table) => symbols.extend(table),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    let banks: Vec<&[u8]> = rom.prg_rom.chunks(PRG_BANK_SIZE).collect();
+
+    if recursive {
+        // prg_byte と同じく、32KiB 以下ならそのまま、それより大きければ先頭と最後のバンクが見える
+        let bank_of = |addr: u16| {
+            if addr < 0x8000 {
+                return None;
+            }
+            let offset = (addr - 0x8000) as usize;
+            if rom.prg_rom.len() <= 0x8000 {
+                Some(offset % rom.prg_rom.len() / PRG_BANK_SIZE)
+            } else if offset < PRG_BANK_SIZE {
+                Some(0)
+            } else {
+                Some(banks.len() - 1)
+            }
+        };
+        let symbol = |addr: u16| symbols.lookup(addr, bank_of(addr)).map(|s| s.to_string());
+        print!(
+            "{}",
+            listing_with_symbols(disassemble_rom(&rom).values(), &vectors(&rom), symbol)
+        );
+        return;
+    }
+
+    // 最後のバンクは $C000 に、それ以外は $8000 に置いて逆アセンブルする
+    for (i, bank) in banks.iter().enumerate() {
+        let base = if i == banks.len() - 1 { 0xC000 } else { 0x8000 };
+        let symbol = |addr: u16| {
+            let bank = if addr >= 0x8000 { Some(i) } else { None };
+            symbols.lookup(addr, bank).map(|s| s.to_string())
+        };
+        println!("; bank {} (${:04X})", i, base);
+        print!(
+            "{}",
+            listing_with_symbols(&disassemble(Variant::Ricoh2A03, bank, base), &[], symbol)
+        );
+        println!();
+    }
+}
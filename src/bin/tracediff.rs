@@ -0,0 +1,49 @@
+use famicom_project::tracediff::{diff, parse_log, report};
+
+fn usage() -> ! {
+    eprintln!("usage: tracediff [-n N] [-c CONTEXT] <expected.log> <actual.log>");
+    std::process::exit(2);
+}
+
+fn read(path: &str) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(2);
+    })
+}
+
+fn main() {
+    let mut limit = 1;
+    let mut context = 5;
+    let mut paths = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => {
+                limit = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "-c" => {
+                context = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+
+    let expected = parse_log(&read(&paths[0]));
+    let actual = parse_log(&read(&paths[1]));
+    let divergences = diff(&expected, &actual, limit);
+    print!("{}", report(&expected, &actual, &divergences, context));
+    if !divergences.is_empty() || expected.len() != actual.len() {
+        std::process::exit(1);
+    }
+}
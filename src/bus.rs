@@ -1,15 +1,120 @@
+use crate::ppu::Ppu;
 use crate::rom::Rom;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+
+// マップされていないアドレスへのアクセス (デバッグ用にフックに渡す)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmappedAccess {
+    Read(u16),
+    Write(u16, u8),
+    // PRG ROM への書き込み。マッパーはまだないので捨てる
+    RomWrite(u16, u8),
+}
+
+// 電源投入時の RAM の中身。未初期化の RAM を読むバグを見つけるのに切り替える
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamInit {
+    Fill(u8),
+    // 同じシードなら同じ中身になる
+    Random(u64),
+    // よくある実機のパターン ($00 x4, $FF x4 の繰り返し)
+    Pattern,
+}
+
+impl Default for RamInit {
+    fn default() -> Self {
+        RamInit::Fill(0x00)
+    }
+}
+
+impl RamInit {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamInit::Fill(value) => ram.fill(value),
+            RamInit::Random(seed) => {
+                // 再現できるように rand ではなく xorshift を使う
+                let mut state = seed ^ 0x9E37_79B9_7F4A_7C15;
+                for v in ram.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    *v = (state >> 32) as u8;
+                }
+            }
+            RamInit::Pattern => {
+                for (i, v) in ram.iter_mut().enumerate() {
+                    *v = if i & 0x04 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+        }
+    }
+}
+
+// --ram=00 / ff / pattern / random / random:1234
+impl std::str::FromStr for RamInit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        if let Some(seed) = s.strip_prefix("random:") {
+            return seed
+                .parse()
+                .map(RamInit::Random)
+                .map_err(|_| format!("invalid seed: {}", seed));
+        }
+        match s.as_str() {
+            "random" => {
+                let seed = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0);
+                Ok(RamInit::Random(seed))
+            }
+            "pattern" => Ok(RamInit::Pattern),
+            _ => u8::from_str_radix(&s, 16)
+                .map(RamInit::Fill)
+                .map_err(|_| format!("unknown RAM init: {}", s)),
+        }
+    }
+}
 
 pub struct Bus {
     cpu_vram: [u8; 2048],
+    // カートリッジの PRG RAM ($6000-$7FFF)
+    prg_ram: [u8; 0x2000],
     rom: Rom,
+    ppu: Ppu,
+    // 最後にデータバスに乗った値。何もつながっていないアドレスを読むとこれが見える
+    open_bus: u8,
+    access_log: Option<Box<dyn FnMut(UnmappedAccess)>>,
 }
 
 impl Bus {
     pub fn new(rom: Rom) -> Self {
         Bus {
             cpu_vram: [0; 2048],
+            prg_ram: [0; 0x2000],
             rom: rom,
+            ppu: Ppu::new(),
+            open_bus: 0,
+            access_log: None,
+        }
+    }
+
+    pub fn set_access_log<F>(&mut self, log: F)
+    where
+        F: FnMut(UnmappedAccess) + 'static,
+    {
+        self.access_log = Some(Box::new(log));
+    }
+
+    pub fn open_bus(&self) -> u8 {
+        self.open_bus
+    }
+
+    fn log(&mut self, access: UnmappedAccess) {
+        if let Some(log) = self.access_log.as_mut() {
+            log(access);
         }
     }
 
@@ -21,41 +126,119 @@ impl Bus {
         }
         self.rom.prg_rom[addr as usize]
     }
+
+    fn prg_rom_checksum(&self) -> u32 {
+        self.rom
+            .prg_rom
+            .iter()
+            .fold(0u32, |sum, v| sum.wrapping_mul(31).wrapping_add(*v as u32))
+    }
+}
+
+impl Snapshot for Bus {
+    fn save(&self, w: &mut StateWriter) {
+        // 別のカートリッジのステートを読み込まないように
+        w.write_u32(self.prg_rom_checksum());
+        w.write_bytes(&self.cpu_vram);
+        w.write_bytes(&self.prg_ram);
+        w.write_u8(self.open_bus);
+        self.ppu.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if r.read_u32()? != self.prg_rom_checksum() {
+            return Err("Save state was made with a different cartridge".to_string());
+        }
+        r.read_into(&mut self.cpu_vram)?;
+        r.read_into(&mut self.prg_ram)?;
+        self.open_bus = r.read_u8()?;
+        self.ppu.load(r)
+    }
 }
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const JOYPAD1: u16 = 0x4016;
+const JOYPAD2: u16 = 0x4017;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 
 const PRG_ROM: u16 = 0x8000;
 const PRG_ROM_END: u16 = 0xFFFF;
 
 pub trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+    fn mem_read(&mut self, addr: u16) -> u8;
     fn mem_write(&mut self, addr: u16, data: u8);
+
+    // 副作用なしで読む (トレース、逆アセンブル、デバッガ用)。
+    // レジスタを読んでもフラグやバッファが変わってはいけない
+    fn peek(&self, addr: u16) -> u8;
+
+    fn peek_u16(&self, addr: u16) -> u16 {
+        let lo = self.peek(addr) as u16;
+        let hi = self.peek(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    // CPU::power_on / CPU::reset から呼ばれる
+    fn power_on(&mut self, _ram: RamInit) {}
+
+    fn reset(&mut self) {}
+
+    // PPU などから NMI が来ていたら true (CPU が命令の境界で見る)
+    fn poll_nmi(&mut self) -> bool {
+        false
+    }
+
+    // フレームが終わっていたら true (CPU が命令の後に見て frame_complete を呼ぶ)
+    fn poll_frame(&mut self) -> bool {
+        false
+    }
+
+    // 以下はトレース用。ないものは None
+
+    // PPU の (スキャンライン, ドット)
+    fn ppu_position(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    // addr にマップされている PRG ROM のバンク
+    fn prg_bank(&self, _addr: u16) -> Option<usize> {
+        None
+    }
 }
 
 impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
-        match addr {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        // CPU は毎サイクルバスにアクセスするので、アクセスごとに 1 サイクル進める
+        self.ppu.tick(1);
+        let value = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0010_0000_0000_0111;
-                todo!("PPU is not supported yet")
+                self.ppu.read_register(mirror_down_addr)
             }
+            // コントローラはまだないので下位ビットは 0。上位 3 ビットはオープンバス
+            JOYPAD1 | JOYPAD2 => self.open_bus & 0xE0,
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
             PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
             _ => {
-                println!("Ignoreing mem access at {}", addr);
-                0
+                self.log(UnmappedAccess::Read(addr));
+                self.open_bus
             }
-        }
+        };
+        self.open_bus = value;
+        value
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.ppu.tick(1);
+        self.open_bus = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
@@ -63,14 +246,232 @@ impl Mem for Bus {
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0010_0000_0000_0111;
-                todo!("PPU is not supported yet")
+                self.ppu.write_register(mirror_down_addr, data);
             }
-            PRG_ROM..=PRG_ROM_END => {
-                panic!("Attempt to write to Cartrige ROM space")
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            // RMW 命令の空書き込みや、バグのある ROM の書き込みで止まらないように
+            PRG_ROM..=PRG_ROM_END => self.log(UnmappedAccess::RomWrite(addr, data)),
+            _ => self.log(UnmappedAccess::Write(addr, data)),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
+                self.cpu_vram[mirror_down_addr as usize]
             }
-            _ => {
-                println!("Ignoreing mem write-access at {}", addr)
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
+                self.ppu.peek_register(addr & 0b_0010_0000_0000_0111)
             }
+            JOYPAD1 | JOYPAD2 => self.open_bus & 0xE0,
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
+            // APU などのレジスタは読むと状態が変わるのでオープンバスの値を返しておく
+            _ => self.open_bus,
+        }
+    }
+
+    // RAM は電源投入時だけ初期化する (リセットでは消えない)。
+    // PRG RAM はバッテリーバックアップのこともあるので触らない。
+    // APU はまだないので、レジスタの初期化 (電源投入とリセットで違う) もまだない
+    fn power_on(&mut self, ram: RamInit) {
+        ram.fill(&mut self.cpu_vram);
+        self.open_bus = 0;
+        self.ppu.power_on();
+    }
+
+    fn reset(&mut self) {
+        self.ppu.reset();
+    }
+
+    fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    fn poll_frame(&mut self) -> bool {
+        self.ppu.poll_frame()
+    }
+
+    fn ppu_position(&self) -> Option<(u16, u16)> {
+        Some(self.ppu.position())
+    }
+
+    // マッパーはまだないので 16KiB ごとの番号 (ミラーは畳む)
+    fn prg_bank(&self, addr: u16) -> Option<usize> {
+        if addr < PRG_ROM || self.rom.prg_rom.is_empty() {
+            return None;
+        }
+        Some((addr - PRG_ROM) as usize % self.rom.prg_rom.len() / 0x4000)
+    }
+}
+
+// カートリッジを使わない 64KiB のフラットなメモリ (CPU のテスト用)
+pub struct FlatBus {
+    memory: Vec<u8>,
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus {
+            memory: vec![0; 0x10000],
+        }
+    }
+
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        for (i, v) in data.iter().enumerate() {
+            self.memory[addr.wrapping_add(i as u16) as usize] = *v;
+        }
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mem for FlatBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+    }
+}
+
+impl Snapshot for FlatBus {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.memory);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_into(&mut self.memory)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_ram_init() {
+        let mut ram = [0x55; 16];
+        RamInit::Fill(0xFF).fill(&mut ram);
+        assert_eq!(ram, [0xFF; 16]);
+
+        RamInit::Pattern.fill(&mut ram);
+        assert_eq!(ram[..8], [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(ram[8..], ram[..8]);
+
+        let mut other = [0; 16];
+        RamInit::Random(1).fill(&mut ram);
+        RamInit::Random(1).fill(&mut other);
+        assert_eq!(ram, other);
+        RamInit::Random(2).fill(&mut other);
+        assert_ne!(ram, other);
+
+        assert_eq!("ff".parse(), Ok(RamInit::Fill(0xFF)));
+        assert_eq!("random:7".parse(), Ok(RamInit::Random(7)));
+        assert_eq!("Pattern".parse(), Ok(RamInit::Pattern));
+        assert!("zzz".parse::<RamInit>().is_err());
+    }
+
+    #[test]
+    fn test_power_on_fills_ram() {
+        let mut bus = Bus::new(test_rom());
+        bus.mem_write(0x0000, 0x12);
+        bus.power_on(RamInit::Pattern);
+        assert_eq!(bus.peek(0x0000), 0x00);
+        assert_eq!(bus.peek(0x0004), 0xFF);
+        // ミラーも同じ
+        assert_eq!(bus.peek(0x1804), 0xFF);
+        // リセットでは消えない
+        bus.mem_write(0x0000, 0x12);
+        bus.reset();
+        assert_eq!(bus.peek(0x0000), 0x12);
+    }
+
+    #[test]
+    fn test_prg_ram_and_ppu_status() {
+        let mut bus = Bus::new(test_rom());
+        bus.mem_write(0x6000, 0x80);
+        bus.mem_write(0x7FFF, 0x12);
+        assert_eq!(bus.mem_read(0x6000), 0x80);
+        assert_eq!(bus.peek(0x7FFF), 0x12);
+        // VBlank を待つループが終わる
+        let mut polls = 0;
+        while bus.mem_read(0x2002) & 0x80 == 0 {
+            polls += 1;
+            assert!(polls < 30000);
         }
+        // ミラーから読んでも同じレジスタ
+        assert_eq!(bus.mem_read(0x3FFA) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = Bus::new(test_rom());
+        bus.mem_write(0x0010, 0x5A);
+        assert_eq!(bus.mem_read(0x0010), 0x5A);
+        // 何もつながっていない所は最後に読んだ値が見える
+        assert_eq!(bus.mem_read(0x5000), 0x5A);
+        bus.mem_write(0x0011, 0xC3);
+        assert_eq!(bus.mem_read(0x4018), 0xC3);
+        assert_eq!(bus.peek(0x4018), 0xC3);
+        // $4016 は上位 3 ビットだけオープンバス
+        assert_eq!(bus.mem_read(0x4016), 0xC0);
+        assert_eq!(bus.open_bus(), 0xC0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_access_log() {
+        let mut bus = Bus::new(test_rom());
+        let log = Rc::new(RefCell::new(vec![]));
+        let sink = log.clone();
+        bus.set_access_log(move |access| sink.borrow_mut().push(access));
+
+        bus.mem_write(0x0000, 0x01);
+        bus.mem_write(0x4000, 0x02);
+        bus.mem_read(0x5000);
+        bus.peek(0x5001);
+        // ROM への書き込みはパニックせずに捨てられる
+        let rom = bus.peek(0x8000);
+        bus.mem_write(0x8000, rom.wrapping_add(1));
+        assert_eq!(bus.peek(0x8000), rom);
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                UnmappedAccess::Write(0x4000, 0x02),
+                UnmappedAccess::Read(0x5000),
+                UnmappedAccess::RomWrite(0x8000, rom.wrapping_add(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rmw_on_rom() {
+        // INC $8000 は元の値と結果を ROM に書く
+        let mut bus = Bus::new(test_rom());
+        for (i, v) in [0xEE, 0x00, 0x80].iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *v);
+        }
+        let rom = bus.peek(0x8000);
+        let mut cpu = crate::cpu::CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        assert!(matches!(
+            cpu.step(),
+            crate::cpu::StepResult::Executed { .. }
+        ));
+        assert_eq!(cpu.peek(0x8000), rom);
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::opscodes::{call, CPU_OPS_CODES};
+use std::collections::BTreeSet;
 
-use crate::bus::{Bus, Mem};
+use crate::opscodes::{call, CMOS_OPS_CODES, CPU_OPS_CODES};
+
+use crate::bus::{Bus, Mem, RamInit};
+use crate::disasm::{binary, disasm_with_labels, find_op};
+use crate::observer::Observer;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+use crate::symbols::SymbolTable;
 
 #[derive(Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
@@ -16,6 +22,9 @@ pub enum AddressingMode {
     Indirect,
     Indirect_X,
     Indirect_Y,
+    // 65C02 のみ: LDA ($44) と JMP ($4400,X)
+    ZeroPage_Indirect,
+    Absolute_Indirect_X,
     Relative,
     Implied,
     NoneAddressing,
@@ -59,7 +68,40 @@ const FLAG_NEGATIVE: u8 = 1 << 7;
 
 const SIGN_BIT: u8 = 1 << 7;
 
-pub struct CPU {
+// どの 6502 として動かすか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant {
+    // ファミコンの CPU。D フラグは立つが BCD 演算はしない
+    Ricoh2A03,
+    // 素の NMOS 6502。BCD 演算あり (N/V/Z は 2 進の結果から)
+    Nmos6502,
+    // CMOS 版。命令とアドレッシングモードが増え、JMP ($xxFF) のバグが直っている
+    Cmos65C02,
+}
+
+impl Variant {
+    pub fn opcodes(&self) -> &'static [OpCode] {
+        match self {
+            Variant::Ricoh2A03 | Variant::Nmos6502 => &CPU_OPS_CODES,
+            Variant::Cmos65C02 => &CMOS_OPS_CODES,
+        }
+    }
+}
+
+impl std::str::FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "2a03" => Ok(Variant::Ricoh2A03),
+            "nmos" | "6502" => Ok(Variant::Nmos6502),
+            "65c02" | "cmos" => Ok(Variant::Cmos65C02),
+            _ => Err(format!("unknown CPU variant: {}", s)),
+        }
+    }
+}
+
+pub struct CPU<B: Mem = Bus> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
@@ -67,40 +109,417 @@ pub struct CPU {
     pub program_counter: u16,
     pub stack_pointer: u8,
     // pub memory: [u8; 0x10000], // 0xFFFF
-    pub bus: Bus,
+    pub bus: B,
+    pub variant: Variant,
+    // リセットからの累計サイクル数
+    pub cycles: u64,
+    // JAM を実行して止まっている (リセットするまで何もしない)
+    pub halted: bool,
+    // frame_complete が呼ばれた回数
+    pub frames: u64,
+    pub breakpoints: BTreeSet<u16>,
+    // JSR/RTS と割り込み/RTI から作ったコールスタック (外側から順)
+    pub call_stack: Vec<Frame>,
+    // ブレークポイントで止まった後、同じ PC から再開するときは 1 回だけ無視する
+    skip_breakpoint: Option<u16>,
+    pub fault_policy: FaultPolicy,
+    // 命令の実行中に起きたフォルト。step() の最後に CpuFault にする
+    fault: Option<(FaultKind, AddressingMode)>,
+    // NMI はエッジで受け付けたら処理するまで覚えておく。IRQ はレベル
+    pub(crate) nmi_pending: bool,
+    pub(crate) irq_line: bool,
+    observers: Vec<Box<dyn Observer<B>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Jsr,
+    Brk,
+    Interrupt(Interrupt),
+}
+
+// シャドウコールスタックの 1 段。JSR/BRK/割り込みで積み、RTS/RTI などで SP が戻ったら捨てる
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // JSR/BRK 命令のアドレスか、割り込まれた命令のアドレス
+    pub from: u16,
+    // 飛び先 (サブルーチンや割り込みハンドラの先頭)
+    pub to: u16,
+    // 積む前の SP。戻り先は $0100+sp から下に積まれている
+    pub sp: u8,
+}
+
+impl Frame {
+    // 積んだバイト数 (戻り先 2 バイトと、割り込みならステータス)
+    pub fn size(&self) -> u8 {
+        match self.kind {
+            FrameKind::Jsr => 2,
+            FrameKind::Brk | FrameKind::Interrupt(_) => 3,
+        }
+    }
+}
+
+// A, X, Y, P, SP とサイクル数
+pub(crate) type Registers = (u8, u8, u8, u8, u8, u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultKind {
+    // テーブルにない命令
+    UnknownOpcode,
+    // 命令がそのアドレッシングモードに対応していない
+    UnsupportedMode,
+    // テーブルにはあるが実装がない
+    Unimplemented(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuFault {
+    pub pc: u16,
+    pub opcode: u8,
+    pub mode: AddressingMode,
+    pub kind: FaultKind,
+}
+
+impl std::fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "CPU fault at ${:04X} (opcode ${:02X}, {:?}): {:?}",
+            self.pc, self.opcode, self.mode, self.kind
+        )
+    }
 }
 
-impl Mem for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.bus.mem_read(addr)
+// フォルトが起きたときの動作
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultPolicy {
+    // レジスタを命令を実行する前の状態に戻して StepResult::Fault を返す
+    // (フォルトより前のバスアクセスは取り消さない)
+    Halt,
+    // 標準エラーに出して NOP として続ける
+    LogAndContinue,
+    // 黙って NOP として続ける
+    TreatAsNop,
+}
+
+// step() の結果
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    Executed { opcode: u8, cycles: u16 },
+    Halted { pc: u16 },
+    Breakpoint { pc: u16 },
+    Fault(CpuFault),
+    // 命令の代わりに割り込みシーケンスを実行した
+    Interrupt { kind: Interrupt, cycles: u16 },
+}
+
+impl<B: Mem> Mem for CPU<B> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let value = self.bus.mem_read(addr);
+        for observer in self.observers.iter_mut() {
+            observer.memory_read(addr, value);
+        }
+        value
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        // フォルトした後の書き込みは捨てる (同じ命令でもフォルトより前の書き込みはバスに届いている)
+        if self.fault.is_some() {
+            return;
+        }
+        for observer in self.observers.iter_mut() {
+            observer.memory_write(addr, data);
+        }
         self.bus.mem_write(addr, data)
     }
 }
 
-impl CPU {
-    pub fn new(bus: Bus) -> Self {
+impl<B: Mem> Snapshot for CPU<B> {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.register_a);
+        w.write_u8(self.register_x);
+        w.write_u8(self.register_y);
+        w.write_u8(self.status);
+        w.write_u16(self.program_counter);
+        w.write_u8(self.stack_pointer);
+        w.write_u64(self.cycles);
+        w.write_bool(self.halted);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.irq_line);
+        w.write_u8(self.variant as u8);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.register_a = r.read_u8()?;
+        self.register_x = r.read_u8()?;
+        self.register_y = r.read_u8()?;
+        self.status = r.read_u8()?;
+        self.program_counter = r.read_u16()?;
+        self.stack_pointer = r.read_u8()?;
+        self.cycles = r.read_u64()?;
+        self.halted = r.read_bool()?;
+        self.nmi_pending = r.read_bool()?;
+        self.irq_line = r.read_bool()?;
+        // 違う CPU のステートを読み込んだら、その CPU として続ける
+        self.variant = match r.read_u8()? {
+            0 => Variant::Ricoh2A03,
+            1 => Variant::Nmos6502,
+            2 => Variant::Cmos65C02,
+            n => return Err(format!("unknown CPU variant {} in save state", n)),
+        };
+        self.call_stack.clear();
+        Ok(())
+    }
+}
+
+impl<B: Mem + Snapshot> CPU<B> {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.section(b"CPU ", self);
+        w.section(b"BUS ", &self.bus);
+        w.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        // 途中で失敗しても中途半端な状態にならないように戻す
+        let backup = self.save_state();
+        let result = self.load_sections(data);
+        if result.is_err() {
+            self.load_sections(&backup).unwrap();
+        }
+        result
+    }
+
+    fn load_sections(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader::new(data)?;
+        r.section(b"CPU ", self)?;
+        r.section(b"BUS ", &mut self.bus)?;
+        r.finish()
+    }
+}
+
+impl<B: Mem> CPU<B> {
+    pub fn new(bus: B) -> Self {
         CPU {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: FLAG_INTERRRUPT | FLAG_BREAK2, // FIXME あってる？
+            // 電源を入れてリセットシーケンスが終わったときの値 (power_on と同じ)
+            status: FLAG_INTERRRUPT | FLAG_BREAK2,
             program_counter: 0,
-            stack_pointer: 0xFD, // FIXME あってる？
+            stack_pointer: 0xFD,
             // memory: [0x00; 0x10000],
             bus: bus,
+            variant: Variant::Ricoh2A03,
+            cycles: 0,
+            halted: false,
+            frames: 0,
+            breakpoints: BTreeSet::new(),
+            call_stack: vec![],
+            skip_breakpoint: None,
+            fault_policy: FaultPolicy::Halt,
+            fault: None,
+            nmi_pending: false,
+            irq_line: false,
+            observers: vec![],
+        }
+    }
+
+    // 登録した順に呼ぶ
+    pub fn add_observer(&mut self, observer: Box<dyn Observer<B>>) {
+        self.observers.push(observer);
+    }
+
+    // 最後に登録したものを外す
+    pub(crate) fn remove_observer(&mut self) -> Option<Box<dyn Observer<B>>> {
+        self.observers.pop()
+    }
+
+    // フレームの終わり (PPU やフロントエンドが呼ぶ)
+    pub fn frame_complete(&mut self) {
+        self.frames += 1;
+        self.notify(|observer, cpu| observer.frame_complete(cpu));
+    }
+
+    pub(crate) fn before_instruction(&mut self) {
+        self.notify(|observer, cpu| observer.before_instruction(cpu));
+    }
+
+    pub(crate) fn after_instruction(&mut self, result: &StepResult) {
+        self.track_call_stack(result);
+        if let StepResult::Interrupt { kind, .. } = result {
+            self.notify(|observer, cpu| observer.interrupt(cpu, *kind));
+        }
+        self.notify(|observer, cpu| observer.after_instruction(cpu, result));
+        if self.bus.poll_frame() {
+            self.frame_complete();
+        }
+    }
+
+    // 積んだものが SP より上 (pop 済み) になった段を捨ててから、JSR/BRK/割り込みなら 1 段積む。
+    // 戻り先はスタックに積まれた値から求める
+    fn track_call_stack(&mut self, result: &StepResult) {
+        let sp = self.stack_pointer;
+        while matches!(self.call_stack.last(), Some(frame) if frame.sp <= sp) {
+            self.call_stack.pop();
+        }
+        let kind = match result {
+            StepResult::Executed { opcode: 0x20, .. } => FrameKind::Jsr,
+            StepResult::Executed { opcode: 0x00, .. } => FrameKind::Brk,
+            StepResult::Interrupt { kind, .. } => FrameKind::Interrupt(*kind),
+            _ => return,
+        };
+        let top = 0x0100 + sp as u16;
+        let from = match kind {
+            // JSR は戻り先 - 1 (命令の最後のバイト) を積む
+            FrameKind::Jsr => self.peek_u16(top + 1).wrapping_sub(2),
+            // BRK は次の次のアドレス、割り込みは割り込まれた命令のアドレスを積む
+            FrameKind::Brk => self.peek_u16(top + 2).wrapping_sub(2),
+            FrameKind::Interrupt(_) => self.peek_u16(top + 2),
+        };
+        let mut frame = Frame {
+            kind,
+            from,
+            to: self.program_counter,
+            sp,
+        };
+        frame.sp = sp.wrapping_add(frame.size());
+        self.call_stack.push(frame);
+    }
+
+    // オブザーバに CPU を渡すために一旦取り出す。
+    // その間のバスアクセス (オブザーバ自身の読み書き) は通知しない
+    fn notify<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut dyn Observer<B>, &mut Self),
+    {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in observers.iter_mut() {
+            f(observer.as_mut(), self);
+        }
+        // 呼んでいる間に登録されたものは後ろに付ける
+        observers.append(&mut self.observers);
+        self.observers = observers;
+    }
+
+    // NMI は立ち下がりエッジで受け付けるので、呼ぶたびに 1 回だけ割り込む
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    // IRQ はレベルで、下げるまで I フラグが 0 なら割り込み続ける
+    pub fn set_irq(&mut self, level: bool) {
+        self.irq_line = level;
+    }
+
+    // バスから来た NMI (PPU の VBlank) を取り込む
+    pub(crate) fn latch_nmi(&mut self) {
+        if self.bus.poll_nmi() {
+            self.nmi_pending = true;
+        }
+    }
+
+    pub(crate) fn poll_interrupt(&self) -> Option<Interrupt> {
+        if self.nmi_pending {
+            Some(Interrupt::Nmi)
+        } else if self.irq_line && self.status & FLAG_INTERRRUPT == 0 {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        }
+    }
+
+    // 割り込み (と BRK) でプッシュするステータス
+    pub(crate) fn interrupt_status(&self, brk: bool) -> u8 {
+        let status = self.status | FLAG_BREAK2;
+        if brk {
+            status | FLAG_BREAK
+        } else {
+            status & !FLAG_BREAK
+        }
+    }
+
+    // ベクタを読む直前に NMI が来ていたら NMI のベクタに化ける
+    pub(crate) fn interrupt_vector(&mut self) -> u16 {
+        self.status = self.status | FLAG_INTERRRUPT;
+        // 65C02 は割り込みで D フラグを下ろす
+        if self.variant == Variant::Cmos65C02 {
+            self.status = self.status & !FLAG_DECIMAL;
+        }
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            0xFFFA
+        } else {
+            0xFFFE
+        }
+    }
+
+    // 割り込みシーケンス (7 サイクル)。命令の代わりに実行する
+    fn interrupt(&mut self, kind: Interrupt) -> StepResult {
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        self._push_u16(self.program_counter);
+        self._push(self.interrupt_status(false));
+        let vector = self.interrupt_vector();
+        self.program_counter = self.mem_read_u16(vector);
+        self.cycles += 7;
+        StepResult::Interrupt { kind, cycles: 7 }
+    }
+
+    // 命令の境界で止まる理由 (JAM, ブレークポイント) があれば返す
+    pub(crate) fn boundary_stop(&mut self) -> Option<StepResult> {
+        let pc = self.program_counter;
+        if self.halted {
+            return Some(StepResult::Halted { pc });
+        }
+        if self.breakpoints.contains(&pc) && self.skip_breakpoint != Some(pc) {
+            self.skip_breakpoint = Some(pc);
+            return Some(StepResult::Breakpoint { pc });
+        }
+        self.skip_breakpoint = None;
+        None
+    }
+
+    // 命令の実行中に呼ぶ。最初のフォルトだけ覚えておく
+    pub(crate) fn raise_fault(&mut self, kind: FaultKind, mode: &AddressingMode) {
+        if self.fault.is_none() {
+            self.fault = Some((kind, mode.clone()));
         }
     }
 
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    // 読み込み命令用。インデックスでページをまたいだときだけ空読みする
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
+        self.operand_address(mode, false)
+    }
+
+    // 書き込み命令/RMW 命令用。インデックス付きのときは必ず空読みする
+    fn get_write_address(&mut self, mode: &AddressingMode) -> u16 {
+        self.operand_address(mode, true)
+    }
+
+    // 実機と同じ順番でバスを読む。
+    // 空読みは下位バイトだけ足した (上位バイトの繰り上がり前の) アドレスから
+    fn operand_address(&mut self, mode: &AddressingMode, write: bool) -> u16 {
         match mode {
-            AddressingMode::Implied => {
-                panic!("AddressingMode::Implied");
-            }
-            AddressingMode::Accumulator => {
-                panic!("AddressingMode::Accumulator");
+            AddressingMode::Implied
+            | AddressingMode::Accumulator
+            | AddressingMode::NoneAddressing => {
+                self.raise_fault(FaultKind::UnsupportedMode, mode);
+                0
             }
             // LDA #$44 => a9 44
             AddressingMode::Immediate => self.program_counter,
@@ -114,6 +533,7 @@ impl CPU {
             // LDA $44,X => b5 44
             AddressingMode::ZeroPage_X => {
                 let pos = self.mem_read(self.program_counter);
+                self.mem_read(pos as u16); // 空読み
                 let addr = pos.wrapping_add(self.register_x) as u16;
                 addr
             }
@@ -121,6 +541,7 @@ impl CPU {
             // LDX $44,Y => b6 44
             AddressingMode::ZeroPage_Y => {
                 let pos = self.mem_read(self.program_counter);
+                self.mem_read(pos as u16); // 空読み
                 let addr = pos.wrapping_add(self.register_y) as u16;
                 addr
             }
@@ -129,6 +550,7 @@ impl CPU {
             AddressingMode::Absolute_X => {
                 let base = self.mem_read_u16(self.program_counter);
                 let addr = base.wrapping_add(self.register_x as u16);
+                self.indexed_dummy_read(base, addr, write);
                 addr
             }
 
@@ -136,54 +558,83 @@ impl CPU {
             AddressingMode::Absolute_Y => {
                 let base = self.mem_read_u16(self.program_counter);
                 let addr = base.wrapping_add(self.register_y as u16);
+                self.indexed_dummy_read(base, addr, write);
                 addr
             }
-            // JMP -> same Absolute
+            // JMP ($4400) => 6c 00 44
+            // NMOS は $xxFF のとき上位バイトを同じページの $xx00 から読む。65C02 では直っている
             AddressingMode::Indirect => {
                 let base = self.mem_read_u16(self.program_counter);
-                let addr = self.mem_read_u16(base);
-                addr
+                self.mem_read_indirect(base)
+            }
+
+            // JMP ($4400,X) => 7c 00 44
+            AddressingMode::Absolute_Indirect_X => {
+                let base = self.mem_read_u16(self.program_counter);
+                let ptr = base.wrapping_add(self.register_x as u16);
+                self.mem_read_u16(ptr)
             }
 
             // LDA ($44,X) => a1 44
             AddressingMode::Indirect_X => {
                 let base = self.mem_read(self.program_counter);
+                self.mem_read(base as u16); // 空読み
                 let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let addr = self.mem_read_u16(ptr as u16);
+                let addr = self.mem_read_zero_page_u16(ptr);
                 addr
             }
 
             // LDA ($44),Y => b1 44
             AddressingMode::Indirect_Y => {
                 let base = self.mem_read(self.program_counter);
-                let deref_base = self.mem_read_u16(base as u16);
+                let deref_base = self.mem_read_zero_page_u16(base);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
+                self.indexed_dummy_read(deref_base, deref, write);
                 deref
             }
 
+            // LDA ($44) => b2 44
+            AddressingMode::ZeroPage_Indirect => {
+                let base = self.mem_read(self.program_counter);
+                self.mem_read_zero_page_u16(base)
+            }
+
             // BCC *+4 => 90 04
             AddressingMode::Relative => {
                 let base = self.mem_read(self.program_counter);
                 let np = (base as i8) as i32 + self.program_counter as i32;
                 return np as u16;
             }
-
-            AddressingMode::NoneAddressing => {
-                panic!("mode {:?} is not supported", mode);
-            }
         }
     }
 
-    pub fn mem_read_u16(&self, pos: u16) -> u16 {
-        // FIXME
-        if pos == 0xFF || pos == 0x02FF {
-            let lo = self.mem_read(pos) as u16;
-            let hi = self.mem_read(pos & 0xFF00) as u16;
-            return (hi << 8) | (lo as u16);
+    fn indexed_dummy_read(&mut self, base: u16, addr: u16, write: bool) {
+        if write || base & 0xFF00 != addr & 0xFF00 {
+            self.mem_read((base & 0xFF00) | (addr & 0x00FF));
         }
-        let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
-        (hi << 8) | (lo as u16)
+    }
+
+    pub fn mem_read_u16(&mut self, pos: u16) -> u16 {
+        read_u16(pos, |addr| self.mem_read(addr))
+    }
+
+    // ゼロページのポインタ ($nn,X)/($nn),Y/($nn) は $FF の次が $00
+    fn mem_read_zero_page_u16(&mut self, ptr: u8) -> u16 {
+        read_zero_page_u16(ptr, |addr| self.mem_read(addr))
+    }
+
+    pub fn peek_zero_page_u16(&self, ptr: u8) -> u16 {
+        read_zero_page_u16(ptr, |addr| self.peek(addr))
+    }
+
+    // JMP ($4400) の飛び先
+    fn mem_read_indirect(&mut self, pos: u16) -> u16 {
+        let variant = self.variant;
+        read_indirect_u16(variant, pos, |addr| self.mem_read(addr))
+    }
+
+    pub fn peek_indirect(&self, pos: u16) -> u16 {
+        read_indirect_u16(self.variant, pos, |addr| self.peek(addr))
     }
 
     pub fn mem_write_u16(&mut self, pos: u16, data: u16) {
@@ -199,17 +650,38 @@ impl CPU {
         self.run();
     }
 
-    pub fn reset(&mut self) {
+    // 電源投入: レジスタは 0、SP は 0 からリセットシーケンスで 3 減って $FD になる
+    pub fn power_on(&mut self, ram: RamInit) {
+        self.bus.power_on(ram);
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
-        // FIXME あってる？
         self.status = FLAG_INTERRRUPT | FLAG_BREAK2;
-        self.stack_pointer = 0xFD;
+        self.stack_pointer = 0x00;
+        self.cycles = 0;
+        self.irq_line = false;
+        self.reset();
+    }
 
+    // リセット: A/X/Y と RAM はそのまま。割り込みと同じシーケンスだが
+    // スタックには書かずに読むだけなので、SP が 3 減って I が立つ
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        for _ in 0..3 {
+            self.mem_read(0x0100 + self.stack_pointer as u16);
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        }
+        self.status |= FLAG_INTERRRUPT;
         self.program_counter = self.mem_read_u16(0xFFFC);
-        // FIXME FOR TEST
-        self.program_counter = 0xC000;
+
+        self.halted = false;
+        self.skip_breakpoint = None;
+        self.call_stack.clear();
+        self.nmi_pending = false;
+        // リセットシーケンスに 7 サイクルかかる
+        self.cycles += 7;
     }
 
     pub fn load(&mut self) {
@@ -222,88 +694,377 @@ impl CPU {
 
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<B>),
     {
         loop {
-            let opscode = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-
-            // println!("OPS: {:X}", opscode);
-            let op = self.find_ops(opscode);
-            match op {
-                Some(op) => {
-                    // FIXME FOR TEST
-                    if op.name == "BRK" {
-                        return;
-                    }
-                    callback(self);
-                    call(self, &op);
-                }
-                _ => {} // panic!("no implementation {:<02X}", opscode),
+            // 命令の境界で呼ぶ (ここでステートを読み込んでも命令の途中にならない)
+            callback(self);
+            match self.step() {
+                StepResult::Executed { .. } | StepResult::Interrupt { .. } => {}
+                _ => return,
             }
         }
     }
 
-    fn find_ops(&mut self, opscode: u8) -> Option<OpCode> {
-        for op in CPU_OPS_CODES.iter() {
-            if op.code == opscode {
-                return Some(op.clone());
+    // 命令を実行するたびに predicate を呼び、true を返したら止める。
+    // 命令以外の結果 (JAM, 未知の命令, ブレークポイント) でも止まる
+    pub fn run_until<F>(&mut self, mut predicate: F) -> StepResult
+    where
+        F: FnMut(&CPU<B>) -> bool,
+    {
+        loop {
+            let result = self.step();
+            match result {
+                StepResult::Executed { .. } | StepResult::Interrupt { .. } if !predicate(self) => {}
+                _ => return result,
+            }
+        }
+    }
+
+    // 少なくとも n サイクル実行する (命令の途中では止まらない)
+    pub fn run_cycles(&mut self, n: u64) -> StepResult {
+        let target = self.cycles + n;
+        self.run_until(|cpu| cpu.cycles >= target)
+    }
+
+    // 1 命令だけ実行する
+    // 割り込みは命令の前にだけ見る (ポーリングのタイミングまで合わせるなら CycleCpu)
+    pub fn step(&mut self) -> StepResult {
+        if let Some(result) = self.boundary_stop() {
+            return result;
+        }
+        self.before_instruction();
+        let result = self.execute();
+        self.after_instruction(&result);
+        result
+    }
+
+    fn execute(&mut self) -> StepResult {
+        let pc = self.program_counter;
+        self.latch_nmi();
+        if let Some(kind) = self.poll_interrupt() {
+            return self.interrupt(kind);
+        }
+
+        let opscode = self.mem_read(pc);
+        let op = match self.find_ops(opscode) {
+            Some(op) => op,
+            None => {
+                let fault = CpuFault {
+                    pc,
+                    opcode: opscode,
+                    mode: AddressingMode::NoneAddressing,
+                    kind: FaultKind::UnknownOpcode,
+                };
+                return self.handle_fault(fault, 1);
+            }
+        };
+        if op.name == "*JAM" {
+            self.halted = true;
+            return StepResult::Halted { pc };
+        }
+
+        self.program_counter = pc.wrapping_add(1);
+        // オペランドのない命令も 2 サイクル目で次のバイトを読んで捨てる (65C02 の 1 サイクル NOP 以外)
+        if (op.addressing_mode == AddressingMode::Implied
+            || op.addressing_mode == AddressingMode::Accumulator)
+            && op.cycles > 1
+        {
+            self.mem_read(self.program_counter);
+        }
+        let mut cycles = op.cycles;
+        if self.page_crossed(&op) {
+            cycles += 1;
+        }
+        // 65C02 の BCD 演算は 1 サイクル多い
+        if self.variant == Variant::Cmos65C02
+            && self.status & FLAG_DECIMAL != 0
+            && (op.name == "ADC" || op.name == "SBC")
+        {
+            cycles += 1;
+        }
+        let registers = self.save_registers();
+        call(self, &op);
+
+        if let Some(result) = self.take_fault(pc, &op, registers) {
+            return result;
+        }
+
+        // 分岐が成立したら +1、ページをまたいだらさらに +1
+        if op.addressing_mode == AddressingMode::Relative {
+            let next = pc.wrapping_add(2);
+            if self.program_counter != next {
+                cycles += 1;
+                if self.program_counter & 0xFF00 != next & 0xFF00 {
+                    cycles += 1;
+                }
             }
         }
-        return None;
+
+        self.cycles += cycles as u64;
+        StepResult::Executed {
+            opcode: opscode,
+            cycles,
+        }
+    }
+
+    // 命令を実行する前のレジスタ (フォルトしたときに戻す)
+    pub(crate) fn save_registers(&self) -> Registers {
+        (
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+            self.stack_pointer,
+            self.cycles,
+        )
+    }
+
+    // 命令の最後に呼ぶ。命令の途中でフォルトしていたら、変えたレジスタを戻して
+    // ポリシーどおりの結果を返す。
+    // フォルトの後の書き込みは mem_write で捨てているが、それより前のバスアクセス
+    // (RMW の空書きなど) はもう終わっているので戻せない
+    pub(crate) fn take_fault(
+        &mut self,
+        pc: u16,
+        op: &OpCode,
+        registers: Registers,
+    ) -> Option<StepResult> {
+        let (kind, mode) = self.fault.take()?;
+        (
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+            self.stack_pointer,
+            self.cycles,
+        ) = registers;
+        self.program_counter = pc;
+        let fault = CpuFault {
+            pc,
+            opcode: op.code,
+            mode,
+            kind,
+        };
+        Some(self.handle_fault(fault, op.bytes))
+    }
+
+    // PC はフォルトした命令を指している
+    fn handle_fault(&mut self, fault: CpuFault, bytes: u16) -> StepResult {
+        match self.fault_policy {
+            FaultPolicy::Halt => return StepResult::Fault(fault),
+            FaultPolicy::LogAndContinue => eprintln!("{}", fault),
+            FaultPolicy::TreatAsNop => {}
+        }
+        self.program_counter = fault.pc.wrapping_add(bytes);
+        // NOP と同じく 2 サイクル
+        self.cycles += 2;
+        StepResult::Executed {
+            opcode: fault.opcode,
+            cycles: 2,
+        }
+    }
+
+    // 読み込み命令のインデックス付きアドレッシングでページをまたぐと 1 サイクル増える。
+    // 書き込み/RMW 命令はテーブルのサイクル数に最初から含まれている
+    // 65C02 のシフト命令 (abs,X) も 6 サイクル + ページをまたいだら 1
+    fn page_crossed(&self, op: &OpCode) -> bool {
+        match op.name.as_str() {
+            "ADC" | "AND" | "CMP" | "EOR" | "LDA" | "LDX" | "LDY" | "ORA" | "SBC" | "BIT"
+            | "*LAX" | "*LAE" | "*NOP" => {}
+            "ASL" | "LSR" | "ROL" | "ROR" if self.variant == Variant::Cmos65C02 => {}
+            _ => return false,
+        }
+        let (base, index) = match op.addressing_mode {
+            AddressingMode::Absolute_X => (self.peek_u16(self.program_counter), self.register_x),
+            AddressingMode::Absolute_Y => (self.peek_u16(self.program_counter), self.register_y),
+            AddressingMode::Indirect_Y => {
+                let ptr = self.peek(self.program_counter);
+                (self.peek_zero_page_u16(ptr), self.register_y)
+            }
+            _ => return false,
+        };
+        base & 0xFF00 != base.wrapping_add(index as u16) & 0xFF00
+    }
+
+    pub fn find_ops(&self, opscode: u8) -> Option<OpCode> {
+        find_op(self.variant, opscode).cloned()
+    }
+
+    pub fn anc(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._anc(value);
+    }
+
+    pub fn arr(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._arr(value);
+    }
+
+    pub fn asr(&mut self, mode: &AddressingMode) {
+        // = ALR
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._and(value);
+        self.register_a = self._lsr(self.register_a);
+    }
+
+    pub fn lxa(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._lxa(value);
+    }
+
+    fn _anc(&mut self, value: u8) {
+        self._and(value);
+        self.status = if self.register_a & SIGN_BIT != 0 {
+            self.status | FLAG_CARRY
+        } else {
+            self.status & !FLAG_CARRY
+        };
+    }
+
+    fn _arr(&mut self, value: u8) {
+        self._and(value);
+        self.register_a = (self.register_a >> 1) | ((self.status & FLAG_CARRY) << 7);
+        self.update_zero_and_negative_flags(self.register_a);
+        // C は bit 6、V は bit 6 と bit 5 の XOR
+        let bit6 = (self.register_a >> 6) & 1;
+        let bit5 = (self.register_a >> 5) & 1;
+        self.status = (self.status & !(FLAG_CARRY | FLAG_OVERFLOW)) | bit6 | ((bit6 ^ bit5) << 6);
+    }
+
+    // 不安定な命令。マジック定数は $EE にしておく
+    fn _lxa(&mut self, value: u8) {
+        self.register_a = (self.register_a | 0xEE) & value;
+        self.register_x = self.register_a;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    pub fn sha(&mut self, mode: &AddressingMode) {
+        self._store_high(mode, self.register_a & self.register_x, self.register_y);
+    }
+
+    pub fn sbx(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._sbx(value);
+    }
+
+    fn _sbx(&mut self, value: u8) {
+        let target = self.register_a & self.register_x;
+        self._compare(target, value);
+        self.register_x = target.wrapping_sub(value);
     }
 
-    pub fn anc(&mut self, mode: &AddressingMode) {}
-    pub fn arr(&mut self, mode: &AddressingMode) {}
-    pub fn asr(&mut self, mode: &AddressingMode) {}
-    pub fn lxa(&mut self, mode: &AddressingMode) {}
-    pub fn sha(&mut self, mode: &AddressingMode) {}
-    pub fn sbx(&mut self, mode: &AddressingMode) {}
     pub fn jam(&mut self, mode: &AddressingMode) {}
-    pub fn lae(&mut self, mode: &AddressingMode) {}
-    pub fn shx(&mut self, mode: &AddressingMode) {}
-    pub fn shy(&mut self, mode: &AddressingMode) {}
-    pub fn ane(&mut self, mode: &AddressingMode) {}
 
+    pub fn lae(&mut self, mode: &AddressingMode) {
+        // = LAS
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._lae(value);
+    }
+
+    fn _lae(&mut self, value: u8) {
+        let value = value & self.stack_pointer;
+        self.register_a = value;
+        self.register_x = value;
+        self.stack_pointer = value;
+        self.update_zero_and_negative_flags(value);
+    }
+
+    pub fn shx(&mut self, mode: &AddressingMode) {
+        self._store_high(mode, self.register_x, self.register_y);
+    }
+
+    pub fn shy(&mut self, mode: &AddressingMode) {
+        self._store_high(mode, self.register_y, self.register_x);
+    }
+
+    pub fn ane(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._ane(value);
+    }
+
+    // 不安定な命令。マジック定数は $EE にしておく
+    fn _ane(&mut self, value: u8) {
+        self.register_a = (self.register_a | 0xEE) & self.register_x & value;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    // SHA/SHX/SHY: 値とベースアドレスの上位バイト+1 の AND を書く。
+    // ページをまたいだときは上位バイトがその値に化ける
+    fn _store_high(&mut self, mode: &AddressingMode, value: u8, index: u8) {
+        let addr = self.get_write_address(mode);
+        let base = addr.wrapping_sub(index as u16);
+        let value = value & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if base & 0xFF00 != addr & 0xFF00 {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(addr, value);
+    }
+
+    // 以下の RMW + 演算の組み合わせ命令は、オペランドを 1 回だけ読む
     pub fn rra(&mut self, mode: &AddressingMode) {
-        self.ror(mode);
-        self.adc(mode);
+        let value = self._modify(mode, Self::_ror);
+        self._adc(value);
     }
 
     pub fn sre(&mut self, mode: &AddressingMode) {
-        self.lsr(mode);
-        self.eor(mode);
+        let value = self._modify(mode, Self::_lsr);
+        self._eor(value);
     }
 
     pub fn rla(&mut self, mode: &AddressingMode) {
-        self.rol(mode);
-        self.and(mode);
+        let value = self._modify(mode, Self::_rol);
+        self._and(value);
     }
 
     pub fn slo(&mut self, mode: &AddressingMode) {
-        self.asl(mode);
-        self.ora(mode);
+        let value = self._modify(mode, Self::_asl);
+        self._ora(value);
     }
 
     pub fn isb(&mut self, mode: &AddressingMode) {
         // = ISC
-        self.inc(mode);
-        self.sbc(mode);
+        let value = self._modify(mode, |_, v| v.wrapping_add(1));
+        self._sbc(value);
     }
 
     pub fn dcp(&mut self, mode: &AddressingMode) {
-        self.dec(mode);
-        self.cmp(mode);
+        let value = self._modify(mode, |_, v| v.wrapping_sub(1));
+        self._compare(self.register_a, value);
     }
 
     pub fn sax(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let addr = self.get_write_address(mode);
         self.mem_write(addr, self.register_a & self.register_x);
     }
 
     pub fn lax(&mut self, mode: &AddressingMode) {
         self.lda(mode);
-        self.tax(mode);
+        self.register_x = self.register_a;
+    }
+
+    // Read-Modify-Write: 読んだ値をそのまま一度書いてから、変更した値を書く
+    // (65C02 は書く代わりにもう一度読む)
+    fn _modify(&mut self, mode: &AddressingMode, f: fn(&mut Self, u8) -> u8) -> u8 {
+        let addr = self.get_write_address(mode);
+        let value = self.mem_read(addr);
+        if self.variant == Variant::Cmos65C02 {
+            self.mem_read(addr);
+        } else {
+            self.mem_write(addr, value);
+        }
+        let value = f(self, value);
+        self.mem_write(addr, value);
+        value
     }
 
     pub fn txs(&mut self, mode: &AddressingMode) {
@@ -336,27 +1097,29 @@ impl CPU {
     }
 
     pub fn sty(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let addr = self.get_write_address(mode);
         self.mem_write(addr, self.register_y);
     }
 
     pub fn stx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let addr = self.get_write_address(mode);
         self.mem_write(addr, self.register_x);
     }
 
     pub fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let addr = self.get_write_address(mode);
         self.mem_write(addr, self.register_a);
     }
 
     pub fn rti(&mut self, mode: &AddressingMode) {
         // スタックからプロセッサ フラグをプルし、続いてプログラム カウンタをプルします。
+        self._stack_dummy_read();
         self.status = self._pop() & !FLAG_BREAK | FLAG_BREAK2;
         self.program_counter = self._pop_u16();
     }
 
     pub fn plp(&mut self, mode: &AddressingMode) {
+        self._stack_dummy_read();
         self.status = self._pop() & !FLAG_BREAK | FLAG_BREAK2;
     }
 
@@ -365,6 +1128,7 @@ impl CPU {
     }
 
     pub fn pla(&mut self, mode: &AddressingMode) {
+        self._stack_dummy_read();
         self.register_a = self._pop();
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -373,8 +1137,60 @@ impl CPU {
         self._push(self.register_a);
     }
 
+    // 以下は 65C02 で増えた命令
+    pub fn phx(&mut self, mode: &AddressingMode) {
+        self._push(self.register_x);
+    }
+
+    pub fn phy(&mut self, mode: &AddressingMode) {
+        self._push(self.register_y);
+    }
+
+    pub fn plx(&mut self, mode: &AddressingMode) {
+        self._stack_dummy_read();
+        self.register_x = self._pop();
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    pub fn ply(&mut self, mode: &AddressingMode) {
+        self._stack_dummy_read();
+        self.register_y = self._pop();
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    pub fn stz(&mut self, mode: &AddressingMode) {
+        let addr = self.get_write_address(mode);
+        self.mem_write(addr, 0);
+    }
+
+    // TSB/TRB: Z は A AND M で決まり、M のビットを A で立てる/下ろす
+    pub fn tsb(&mut self, mode: &AddressingMode) {
+        self._test_bits(mode, |a, m| m | a);
+    }
+
+    pub fn trb(&mut self, mode: &AddressingMode) {
+        self._test_bits(mode, |a, m| m & !a);
+    }
+
+    fn _test_bits(&mut self, mode: &AddressingMode, f: fn(u8, u8) -> u8) {
+        let addr = self.get_write_address(mode);
+        let value = self.mem_read(addr);
+        self.mem_read(addr);
+        self._set_zero(self.register_a & value == 0);
+        self.mem_write(addr, f(self.register_a, value));
+    }
+
+    pub fn bra(&mut self, mode: &AddressingMode) {
+        // 必ず成立する分岐
+        self._branch(mode, 0, false);
+    }
+
     pub fn nop(&mut self, mode: &AddressingMode) {
-        // なにもしない
+        // なにもしない (非公式の NOP はオペランドを読む)
+        if mode != &AddressingMode::Implied {
+            let addr = self.get_operand_address(mode);
+            self.mem_read(addr);
+        }
     }
 
     pub fn ldy(&mut self, mode: &AddressingMode) {
@@ -399,16 +1215,26 @@ impl CPU {
     }
 
     pub fn rts(&mut self, mode: &AddressingMode) {
-        let value = self._pop_u16() + 1;
-        self.program_counter = value;
+        self._stack_dummy_read();
+        let value = self._pop_u16();
+        self.mem_read(value); // 空読み
+        self.program_counter = value.wrapping_add(1);
     }
 
     pub fn jsr(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        self._push_u16(self.program_counter + 2 - 1);
-        self.program_counter = addr;
+        // 下位バイトを読んでから戻り先をプッシュし、最後に上位バイトを読む
+        let lo = self.mem_read(self.program_counter) as u16;
+        self._stack_dummy_read();
+        self._push_u16(self.program_counter.wrapping_add(1));
+        let hi = self.mem_read(self.program_counter.wrapping_add(1)) as u16;
+        self.program_counter = hi << 8 | lo;
         // 後で+2するので整合性のため-2しておく
-        self.program_counter -= 2;
+        self.program_counter = self.program_counter.wrapping_sub(2);
+    }
+
+    // スタックから pull する前の空読み
+    fn _stack_dummy_read(&mut self) {
+        self.mem_read(0x0100 + self.stack_pointer as u16);
     }
 
     pub fn _push(&mut self, value: u8) {
@@ -423,26 +1249,23 @@ impl CPU {
         self.mem_read(addr)
     }
 
+    // 上位バイトから積む
     pub fn _push_u16(&mut self, value: u16) {
-        let addr = 0x0100 + self.stack_pointer.wrapping_sub(1) as u16;
-        self.mem_write_u16(addr, value);
-        self.stack_pointer = self.stack_pointer.wrapping_sub(2);
+        self._push((value >> 8) as u8);
+        self._push((value & 0xFF) as u8);
     }
 
     pub fn _pop_u16(&mut self) -> u16 {
-        let addr = 0x0100 + self.stack_pointer.wrapping_add(1) as u16;
-        let value = self.mem_read_u16(addr);
-        self.stack_pointer = self.stack_pointer.wrapping_add(2);
-        value
+        let lo = self._pop() as u16;
+        let hi = self._pop() as u16;
+        hi << 8 | lo
     }
 
     pub fn jmp(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         self.program_counter = addr;
         // 後で+2するので整合性のため-2しておく
-        self.program_counter -= 2;
-        // TODO
-        // オリジナルの 6502 は、間接ベクトルがページ境界にある場合、ターゲット アドレスを正しくフェッチしません (たとえば、$xxFF で、xx は $00 から $FF までの任意の値です)。この場合、予想どおり $xxFF から LSB を取得しますが、$xx00 から MSB を取得します。これは、65SC02 などの最近のチップで修正されているため、互換性のために、間接ベクトルがページの最後にないことを常に確認してください。
+        self.program_counter = self.program_counter.wrapping_sub(2);
     }
 
     pub fn iny(&mut self, mode: &AddressingMode) {
@@ -456,9 +1279,12 @@ impl CPU {
     }
 
     pub fn inc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr).wrapping_add(1);
-        self.mem_write(addr, value);
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self.register_a.wrapping_add(1);
+            self.update_zero_and_negative_flags(self.register_a);
+            return;
+        }
+        let value = self._modify(mode, |_, v| v.wrapping_add(1));
         self.update_zero_and_negative_flags(value);
     }
 
@@ -473,15 +1299,22 @@ impl CPU {
     }
 
     pub fn dec(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr).wrapping_sub(1);
-        self.mem_write(addr, value);
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self.register_a.wrapping_sub(1);
+            self.update_zero_and_negative_flags(self.register_a);
+            return;
+        }
+        let value = self._modify(mode, |_, v| v.wrapping_sub(1));
         self.update_zero_and_negative_flags(value);
     }
 
     fn _cmp(&mut self, target: u8, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._compare(target, value);
+    }
+
+    fn _compare(&mut self, target: u8, value: u8) {
         if target >= value {
             self.sec(&AddressingMode::Implied);
         } else {
@@ -541,26 +1374,31 @@ impl CPU {
 
     fn _branch(&mut self, mode: &AddressingMode, flag: u8, nonzero: bool) {
         let addr = self.get_operand_address(mode);
-        if nonzero {
-            if self.status & flag != 0 {
-                self.program_counter = addr
-            }
+        let taken = if nonzero {
+            self.status & flag != 0
         } else {
-            if self.status & flag == 0 {
-                self.program_counter = addr
+            self.status & flag == 0
+        };
+        if taken {
+            // 成立したら次の命令を空読み、ページをまたいだら上位バイトを直す前のアドレスも空読み
+            let next = self.program_counter.wrapping_add(1);
+            let target = addr.wrapping_add(1);
+            self.mem_read(next);
+            if next & 0xFF00 != target & 0xFF00 {
+                self.mem_read((next & 0xFF00) | (target & 0x00FF));
             }
+            self.program_counter = addr
         }
     }
 
     pub fn brk(&mut self, mode: &AddressingMode) {
-        // プログラム カウンターとプロセッサ ステータスがスタックにプッシュされ、
-        //   ==> ??? FIXME
-        // self._push_u16(self.program_counter);
-        // self._push(self.status);
+        // BRK の次の次のアドレスとステータス (B フラグ付き) をプッシュする
+        self._push_u16(self.program_counter.wrapping_add(1));
+        self._push(self.interrupt_status(true));
 
-        // $FFFE/F の IRQ 割り込みベクトルが PC にロードされ、ステータスのブレーク フラグが 1 に設定されます。
-        self.program_counter = self.mem_read_u16(0xFFFE);
-        self.status = self.status | FLAG_BREAK;
+        // $FFFE/F の IRQ 割り込みベクトルが PC にロードされる
+        let vector = self.interrupt_vector();
+        self.program_counter = self.mem_read_u16(vector);
     }
 
     pub fn bpl(&mut self, mode: &AddressingMode) {
@@ -574,17 +1412,28 @@ impl CPU {
     pub fn bit(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
-
-        let zero = self.register_a & value;
-        if zero == 0 {
-            self.status = self.status | FLAG_ZERO;
+        if mode == &AddressingMode::Immediate {
+            // 65C02 の BIT #imm は Z だけ変える
+            self._set_zero(self.register_a & value == 0);
         } else {
-            self.status = self.status & !FLAG_ZERO;
+            self._bit(value);
         }
+    }
+
+    fn _bit(&mut self, value: u8) {
+        self._set_zero(self.register_a & value == 0);
         let flags = FLAG_NEGATIVE | FLAG_OVERFLOW;
         self.status = (self.status & !flags) | (value & flags);
     }
 
+    fn _set_zero(&mut self, zero: bool) {
+        self.status = if zero {
+            self.status | FLAG_ZERO
+        } else {
+            self.status & !FLAG_ZERO
+        };
+    }
+
     pub fn bne(&mut self, mode: &AddressingMode) {
         self._branch(mode, FLAG_ZERO, false);
     }
@@ -602,97 +1451,78 @@ impl CPU {
     }
 
     pub fn ror(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let carry = self.register_a & 0x01;
-            self.register_a = self.register_a / 2;
-            self.register_a = self.register_a | ((self.status & FLAG_CARRY) << 7);
-            (self.register_a, carry)
-        } else {
-            let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let carry = value & 0x01;
-            let value = value / 2;
-            let value = value | ((self.status & FLAG_CARRY) << 7);
-            self.mem_write(addr, value);
-            (value, carry)
-        };
-
-        self.status = if carry == 1 {
-            self.status | FLAG_CARRY
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._ror(self.register_a);
         } else {
-            self.status & !FLAG_CARRY
-        };
-        self.update_zero_and_negative_flags(value);
+            self._modify(mode, Self::_ror);
+        }
     }
 
     pub fn rol(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let (value, carry) = self.register_a.overflowing_mul(2);
-            self.register_a = value | (self.status & FLAG_CARRY);
-            (self.register_a, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._rol(self.register_a);
         } else {
-            let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let (value, carry) = value.overflowing_mul(2);
-            let value = value | (self.status & FLAG_CARRY);
-            self.mem_write(addr, value);
-            (value, carry)
-        };
-
-        self.status = if carry {
-            self.status | FLAG_CARRY
-        } else {
-            self.status & !FLAG_CARRY
-        };
-        self.update_zero_and_negative_flags(value);
+            self._modify(mode, Self::_rol);
+        }
     }
 
     pub fn lsr(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let carry = self.register_a & 0x01;
-            self.register_a = self.register_a / 2;
-            (self.register_a, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._lsr(self.register_a);
         } else {
-            let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let carry = value & 0x01;
-            let value = value / 2;
-            self.mem_write(addr, value);
-            (value, carry)
-        };
-
-        self.status = if carry == 1 {
-            self.status | FLAG_CARRY
-        } else {
-            self.status & !FLAG_CARRY
-        };
-        self.update_zero_and_negative_flags(value);
+            self._modify(mode, Self::_lsr);
+        }
     }
 
     pub fn asl(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let (value, carry) = self.register_a.overflowing_mul(2);
-            self.register_a = value;
-            (value, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._asl(self.register_a);
         } else {
-            let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let (value, carry) = value.overflowing_mul(2);
-            self.mem_write(addr, value);
-            (value, carry)
-        };
+            self._modify(mode, Self::_asl);
+        }
+    }
+
+    fn _ror(&mut self, value: u8) -> u8 {
+        let carry = value & 0x01;
+        let value = value / 2;
+        let value = value | ((self.status & FLAG_CARRY) << 7);
+        self._set_carry_and_flags(value, carry == 1)
+    }
+
+    fn _rol(&mut self, value: u8) -> u8 {
+        let (value, carry) = value.overflowing_mul(2);
+        let value = value | (self.status & FLAG_CARRY);
+        self._set_carry_and_flags(value, carry)
+    }
+
+    fn _lsr(&mut self, value: u8) -> u8 {
+        let carry = value & 0x01;
+        let value = value / 2;
+        self._set_carry_and_flags(value, carry == 1)
+    }
 
+    fn _asl(&mut self, value: u8) -> u8 {
+        let (value, carry) = value.overflowing_mul(2);
+        self._set_carry_and_flags(value, carry)
+    }
+
+    fn _set_carry_and_flags(&mut self, value: u8, carry: bool) -> u8 {
         self.status = if carry {
             self.status | FLAG_CARRY
         } else {
             self.status & !FLAG_CARRY
         };
         self.update_zero_and_negative_flags(value);
+        value
     }
 
     pub fn ora(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._ora(value);
+    }
+
+    fn _ora(&mut self, value: u8) {
         self.register_a = self.register_a | value;
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -700,6 +1530,10 @@ impl CPU {
     pub fn eor(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._eor(value);
+    }
+
+    fn _eor(&mut self, value: u8) {
         self.register_a = self.register_a ^ value;
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -707,6 +1541,10 @@ impl CPU {
     pub fn and(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._and(value);
+    }
+
+    fn _and(&mut self, value: u8) {
         self.register_a = self.register_a & value;
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -718,7 +1556,18 @@ impl CPU {
         // overflowの判定が逆 = m,p, p,m
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._sbc(value);
+    }
 
+    fn _sbc(&mut self, value: u8) {
+        if self.status & FLAG_DECIMAL != 0 && self.variant != Variant::Ricoh2A03 {
+            self._sbc_decimal(value);
+        } else {
+            self._sbc_binary(value);
+        }
+    }
+
+    fn _sbc_binary(&mut self, value: u8) {
         let carry = self.status & FLAG_CARRY;
         let (v1, carry_flag1) = self.register_a.overflowing_sub(value);
         let (n, carry_flag2) = v1.overflowing_sub(1 - carry);
@@ -745,7 +1594,13 @@ impl CPU {
     pub fn adc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._adc(value);
+    }
 
+    fn _adc(&mut self, value: u8) {
+        if self.status & FLAG_DECIMAL != 0 && self.variant != Variant::Ricoh2A03 {
+            return self._adc_decimal(value);
+        }
         let carry = self.status & FLAG_CARRY;
         let (rhs, carry_flag1) = value.overflowing_add(carry);
         let (n, carry_flag2) = self.register_a.overflowing_add(rhs);
@@ -769,6 +1624,238 @@ impl CPU {
         self.update_zero_and_negative_flags(self.register_a)
     }
 
+    // BCD の足し算。NMOS は N/V を下位桁を補正した途中の値から、Z を 2 進の結果から取る。
+    // 65C02 は N/Z を最終結果から取る (V は NMOS と同じ)
+    fn _adc_decimal(&mut self, value: u8) {
+        let a = self.register_a as u16;
+        let m = value as u16;
+        let carry = (self.status & FLAG_CARRY) as u16;
+        let binary = (a + m + carry) as u8;
+
+        let mut lo = (a & 0x0F) + (m & 0x0F) + carry;
+        if lo >= 0x0A {
+            lo = ((lo + 0x06) & 0x0F) + 0x10;
+        }
+        let mut n = (a & 0xF0) + (m & 0xF0) + lo;
+        let overflow = !(a ^ m) & (a ^ n) & 0x80 != 0;
+        let negative = n & 0x80 != 0;
+        if n >= 0xA0 {
+            n += 0x60;
+        }
+        self.register_a = n as u8;
+        self._set_arithmetic_flags(n >= 0x100, overflow);
+
+        if self.variant == Variant::Nmos6502 {
+            self._set_zero(binary == 0);
+            self.status = if negative {
+                self.status | FLAG_NEGATIVE
+            } else {
+                self.status & !FLAG_NEGATIVE
+            };
+        } else {
+            self.update_zero_and_negative_flags(self.register_a);
+        }
+    }
+
+    // BCD の引き算。フラグは NMOS だと 2 進の引き算と同じで、65C02 は N/Z だけ最終結果から取る
+    fn _sbc_decimal(&mut self, value: u8) {
+        let a = self.register_a as i16;
+        let m = value as i16;
+        let borrow = 1 - (self.status & FLAG_CARRY) as i16;
+        let lo = (a & 0x0F) - (m & 0x0F) - borrow;
+
+        let n = if self.variant == Variant::Nmos6502 {
+            let lo = if lo < 0 {
+                ((lo - 0x06) & 0x0F) - 0x10
+            } else {
+                lo
+            };
+            let n = (a & 0xF0) - (m & 0xF0) + lo;
+            if n < 0 {
+                n - 0x60
+            } else {
+                n
+            }
+        } else {
+            let mut n = a - m - borrow;
+            if n < 0 {
+                n -= 0x60;
+            }
+            if lo < 0 {
+                n -= 0x06;
+            }
+            n
+        };
+
+        // C/V (NMOS は N/Z も) は 2 進の結果と同じ
+        self._sbc_binary(value);
+        self.register_a = n as u8;
+        if self.variant == Variant::Cmos65C02 {
+            self.update_zero_and_negative_flags(self.register_a);
+        }
+    }
+
+    fn _set_arithmetic_flags(&mut self, carry: bool, overflow: bool) {
+        self.status = if carry {
+            self.status | FLAG_CARRY
+        } else {
+            self.status & !FLAG_CARRY
+        };
+        self.status = if overflow {
+            self.status | FLAG_OVERFLOW
+        } else {
+            self.status & !FLAG_OVERFLOW
+        };
+    }
+
+    // 以下はサイクル単位のコア (cycle_cpu.rs) 用。バスには触らず演算だけする
+
+    // 読み込み命令: 読んだ値で演算する
+    pub(crate) fn read_op(&mut self, name: &str, value: u8) {
+        match name {
+            "LDA" => {
+                self.register_a = value;
+                self.update_zero_and_negative_flags(value);
+            }
+            "LDX" => {
+                self.register_x = value;
+                self.update_zero_and_negative_flags(value);
+            }
+            "LDY" => {
+                self.register_y = value;
+                self.update_zero_and_negative_flags(value);
+            }
+            "LAX" => {
+                self.register_a = value;
+                self.register_x = value;
+                self.update_zero_and_negative_flags(value);
+            }
+            "ADC" => self._adc(value),
+            "SBC" => self._sbc(value),
+            "AND" => self._and(value),
+            "ORA" => self._ora(value),
+            "EOR" => self._eor(value),
+            "CMP" => self._compare(self.register_a, value),
+            "CPX" => self._compare(self.register_x, value),
+            "CPY" => self._compare(self.register_y, value),
+            "BIT" => self._bit(value),
+            "NOP" => {}
+            "LAE" => self._lae(value),
+            "ANC" => self._anc(value),
+            "ARR" => self._arr(value),
+            "ASR" => {
+                self._and(value);
+                self.register_a = self._lsr(self.register_a);
+            }
+            "SBX" => self._sbx(value),
+            "LXA" => self._lxa(value),
+            "ANE" => self._ane(value),
+            _ => self.raise_fault(
+                FaultKind::Unimplemented(name.to_string()),
+                &AddressingMode::NoneAddressing,
+            ),
+        }
+    }
+
+    // RMW 命令: 読んだ値から書き戻す値を作る
+    pub(crate) fn modify_op(&mut self, name: &str, value: u8) -> u8 {
+        match name {
+            "ASL" => self._asl(value),
+            "LSR" => self._lsr(value),
+            "ROL" => self._rol(value),
+            "ROR" => self._ror(value),
+            "INC" | "DEC" => {
+                let value = if name == "INC" {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                self.update_zero_and_negative_flags(value);
+                value
+            }
+            "SLO" => {
+                let value = self._asl(value);
+                self._ora(value);
+                value
+            }
+            "SRE" => {
+                let value = self._lsr(value);
+                self._eor(value);
+                value
+            }
+            "RLA" => {
+                let value = self._rol(value);
+                self._and(value);
+                value
+            }
+            "RRA" => {
+                let value = self._ror(value);
+                self._adc(value);
+                value
+            }
+            "DCP" => {
+                let value = value.wrapping_sub(1);
+                self._compare(self.register_a, value);
+                value
+            }
+            "ISB" => {
+                let value = value.wrapping_add(1);
+                self._sbc(value);
+                value
+            }
+            _ => {
+                self.raise_fault(
+                    FaultKind::Unimplemented(name.to_string()),
+                    &AddressingMode::NoneAddressing,
+                );
+                value
+            }
+        }
+    }
+
+    // 書き込み命令が書く値 (SHA/SHX/SHY は上位バイトとの AND の前)
+    pub(crate) fn write_value(&self, name: &str) -> u8 {
+        match name {
+            "STA" => self.register_a,
+            "STX" | "SHX" => self.register_x,
+            "STY" | "SHY" => self.register_y,
+            "SAX" | "SHA" => self.register_a & self.register_x,
+            _ => 0,
+        }
+    }
+
+    // スタック命令: プッシュする値とプルした値の反映
+    pub(crate) fn push_value(&self, name: &str) -> u8 {
+        match name {
+            "PHP" => self.status | FLAG_BREAK | FLAG_BREAK2,
+            _ => self.register_a,
+        }
+    }
+
+    pub(crate) fn pull_op(&mut self, name: &str, value: u8) {
+        match name {
+            "PLP" => self.status = value & !FLAG_BREAK | FLAG_BREAK2,
+            _ => {
+                self.register_a = value;
+                self.update_zero_and_negative_flags(value);
+            }
+        }
+    }
+
+    pub(crate) fn branch_taken(&self, name: &str) -> bool {
+        let (flag, set) = match name {
+            "BPL" => (FLAG_NEGATIVE, false),
+            "BMI" => (FLAG_NEGATIVE, true),
+            "BVC" => (FLAG_OVERFLOW, false),
+            "BVS" => (FLAG_OVERFLOW, true),
+            "BCC" => (FLAG_CARRY, false),
+            "BCS" => (FLAG_CARRY, true),
+            "BNE" => (FLAG_ZERO, false),
+            _ => (FLAG_ZERO, true),
+        };
+        (self.status & flag != 0) == set
+    }
+
     fn update_zero_and_negative_flags(&mut self, result: u8) {
         self.status = if result == 0 {
             self.status | FLAG_ZERO
@@ -784,7 +1871,37 @@ impl CPU {
     }
 }
 
-pub fn trace(cpu: &mut CPU) -> String {
+// 以下は mem_read と peek の共通部分 (下位バイトから読む)
+fn read_u16<F: FnMut(u16) -> u8>(pos: u16, mut read: F) -> u16 {
+    let lo = read(pos) as u16;
+    let hi = read(pos.wrapping_add(1)) as u16;
+    (hi << 8) | lo
+}
+
+fn read_zero_page_u16<F: FnMut(u16) -> u8>(ptr: u8, mut read: F) -> u16 {
+    let lo = read(ptr as u16) as u16;
+    let hi = read(ptr.wrapping_add(1) as u16) as u16;
+    (hi << 8) | lo
+}
+
+// NMOS (2A03 も) は $xxFF のとき上位バイトを同じページの $xx00 から読む。65C02 では直っている
+fn read_indirect_u16<F: FnMut(u16) -> u8>(variant: Variant, pos: u16, mut read: F) -> u16 {
+    let next = if variant == Variant::Cmos65C02 {
+        pos.wrapping_add(1)
+    } else {
+        (pos & 0xFF00) | (pos.wrapping_add(1) & 0x00FF)
+    };
+    let lo = read(pos) as u16;
+    let hi = read(next) as u16;
+    (hi << 8) | lo
+}
+
+pub fn trace<B: Mem>(cpu: &CPU<B>) -> String {
+    trace_with_symbols(cpu, &SymbolTable::new())
+}
+
+// オペランドのアドレスにラベルがあれば名前で出す (JSR $C0A0 => JSR copypal)
+pub fn trace_with_symbols<B: Mem>(cpu: &CPU<B>, symbols: &SymbolTable) -> String {
     // 0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD
     // OK 0064 => program_counter
     // OK A2 01 => binary code
@@ -792,17 +1909,32 @@ pub fn trace(cpu: &mut CPU) -> String {
     // "0400 @ 0400 = AA" => memory access
     // OK A:01 X:02 Y:03 P:24 SP:FD => register, status, stack_pointer
 
-    let program_counter = cpu.program_counter - 1;
+    let program_counter = cpu.program_counter;
     let pc = format!("{:<04X}", program_counter);
-    let op = cpu.mem_read(program_counter);
-    let ops = cpu.find_ops(op).unwrap();
+    let op = cpu.peek(program_counter);
+    let ops = match cpu.find_ops(op) {
+        Some(ops) => ops,
+        None => {
+            // テーブルにない命令は 1 バイトのデータとして出す
+            let asm = format!(" .byte ${:02X}", op);
+            return format!(
+                "{:<6}{:<9}{:<33}{}",
+                pc,
+                binary(op, &vec![]),
+                asm,
+                cpu2str(cpu)
+            );
+        }
+    };
     let mut args: Vec<u8> = vec![];
     for n in 1..ops.bytes {
-        let arg = cpu.mem_read(program_counter + n);
+        let arg = cpu.peek(program_counter.wrapping_add(n));
         args.push(arg);
     }
     let bin = binary(op, &args);
-    let asm = disasm(program_counter, &ops, &args);
+    let asm = disasm_with_labels(program_counter, &ops, &args, |addr| {
+        symbols.label(cpu, addr).map(|name| name.to_string())
+    });
     let memacc = memory_access(cpu, &ops, &args);
     let status = cpu2str(cpu);
 
@@ -810,107 +1942,18 @@ pub fn trace(cpu: &mut CPU) -> String {
         "{:<6}{:<9}{:<33}{}",
         pc,
         bin,
-        vec![asm, memacc].join(" "),
+        [asm, memacc].join(" "),
         status
     )
 }
 
-fn binary(op: u8, args: &Vec<u8>) -> String {
-    let mut list: Vec<String> = vec![];
-    list.push(format!("{:<02X}", op));
-    for v in args {
-        list.push(format!("{:<02X}", v));
-    }
-    list.join(" ")
-}
-
-fn disasm(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
-    let prefix = if ops.name.starts_with("*") { "" } else { " " };
-    format!(
-        "{}{} {}",
-        prefix,
-        ops.name,
-        address(program_counter, &ops, args)
-    )
-}
-
-fn address(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
-    match ops.addressing_mode {
-        AddressingMode::Implied => {
-            format!("")
-        }
-        AddressingMode::Accumulator => {
-            format!("A")
-        }
-        // LDA #$44 => a9 44
-        AddressingMode::Immediate => {
-            format!("#${:<02X}", args[0])
-        }
-
-        // LDA $44 => a5 44
-        AddressingMode::ZeroPage => {
-            format!("${:<02X}", args[0])
-        }
-
-        // LDA $4400 => ad 00 44
-        AddressingMode::Absolute => {
-            format!("${:<02X}{:<02X}", args[1], args[0])
-        }
-        // LDA $44,X => b5 44
-        AddressingMode::ZeroPage_X => {
-            format!("${:<02X},X", args[0])
-        }
-
-        // LDX $44,Y => b6 44
-        AddressingMode::ZeroPage_Y => {
-            format!("${:<02X},Y", args[0])
-        }
-
-        // LDA $4400,X => bd 00 44
-        AddressingMode::Absolute_X => {
-            format!("${:<02X}{:<02X},X", args[1], args[0])
-        }
-
-        // LDA $4400,Y => b9 00 44
-        AddressingMode::Absolute_Y => {
-            format!("${:<02X}{:<02X},Y", args[1], args[0])
-        }
-        // JMP
-        AddressingMode::Indirect => {
-            format!("(${:<02X}{:<02X})", args[1], args[0])
-        }
-
-        // LDA ($44,X) => a1 44
-        AddressingMode::Indirect_X => {
-            format!("(${:<02X},X)", args[0])
-        }
-
-        // LDA ($44),Y => b1 44
-        AddressingMode::Indirect_Y => {
-            format!("(${:<02X}),Y", args[0])
-        }
-
-        // BCC *+4 => 90 04
-        AddressingMode::Relative => {
-            format!(
-                "${:<04X}",
-                (program_counter as i32 + (args[0] as i8) as i32) as u16 + 2
-            )
-        }
-
-        AddressingMode::NoneAddressing => {
-            panic!("mode {:?} is not supported", ops.addressing_mode);
-        }
-    }
-}
-
-fn memory_access(cpu: &CPU, ops: &OpCode, args: &Vec<u8>) -> String {
+fn memory_access<B: Mem>(cpu: &CPU<B>, ops: &OpCode, args: &Vec<u8>) -> String {
     if ops.name.starts_with("J") {
         if ops.addressing_mode == AddressingMode::Indirect {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
-            let value = cpu.mem_read_u16(addr);
+            let value = cpu.peek_indirect(addr);
             return format!("= {:<04X}", value);
         }
         return format!("");
@@ -918,24 +1961,24 @@ fn memory_access(cpu: &CPU, ops: &OpCode, args: &Vec<u8>) -> String {
 
     match ops.addressing_mode {
         AddressingMode::ZeroPage => {
-            let value = cpu.mem_read(args[0] as u16);
+            let value = cpu.peek(args[0] as u16);
             format!("= {:<02X}", value)
         }
         AddressingMode::ZeroPage_X => {
             let addr = args[0].wrapping_add(cpu.register_x) as u16;
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<02X} = {:<02X}", addr, value)
         }
         AddressingMode::ZeroPage_Y => {
             let addr = args[0].wrapping_add(cpu.register_y) as u16;
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<02X} = {:<02X}", addr, value)
         }
         AddressingMode::Absolute => {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("= {:<02X}", value)
         }
         AddressingMode::Absolute_X => {
@@ -943,7 +1986,7 @@ fn memory_access(cpu: &CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let lo = args[0] as u16;
             let base = hi << 8 | lo;
             let addr = base.wrapping_add(cpu.register_x as u16);
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<04X} = {:<02X}", addr, value)
         }
         AddressingMode::Absolute_Y => {
@@ -951,146 +1994,632 @@ fn memory_access(cpu: &CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let lo = args[0] as u16;
             let base = hi << 8 | lo;
             let addr = base.wrapping_add(cpu.register_y as u16);
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<04X} = {:<02X}", addr, value)
         }
         AddressingMode::Indirect_X => {
             let base = args[0];
             let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-            let addr = cpu.mem_read_u16(ptr as u16);
-            let value = cpu.mem_read(addr);
+            let addr = cpu.peek_zero_page_u16(ptr);
+            let value = cpu.peek(addr);
             format!("@ {:<02X} = {:<04X} = {:<02X}", ptr, addr, value)
         }
-        AddressingMode::Indirect_Y => {
-            let base = args[0];
-            let deref_base = cpu.mem_read_u16(base as u16);
-            let deref = deref_base.wrapping_add(cpu.register_y as u16);
-            let value = cpu.mem_read(deref);
-            format!("= {:<04X} @ {:<04X} = {:<02X}", deref_base, deref, value)
+        AddressingMode::Indirect_Y => {
+            let base = args[0];
+            let deref_base = cpu.peek_zero_page_u16(base);
+            let deref = deref_base.wrapping_add(cpu.register_y as u16);
+            let value = cpu.peek(deref);
+            format!("= {:<04X} @ {:<04X} = {:<02X}", deref_base, deref, value)
+        }
+        _ => {
+            format!("")
+        }
+    }
+}
+
+fn cpu2str<B: Mem>(cpu: &CPU<B>) -> String {
+    format!(
+        "A:{:<02X} X:{:<02X} Y:{:<02X} P:{:<02X} SP:{:<02X}",
+        cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+
+    use super::*;
+    use crate::assembler::assemble_for;
+    use crate::bus::{Bus, FlatBus};
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_format_trace() {
+        let mut bus = Bus::new(test_rom());
+        bus.mem_write(100, 0xa2);
+        bus.mem_write(101, 0x01);
+        bus.mem_write(102, 0xca);
+        bus.mem_write(103, 0x88);
+        bus.mem_write(104, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        cpu.register_a = 1;
+        cpu.register_x = 2;
+        cpu.register_y = 3;
+
+        let mut result: Vec<String> = vec![];
+        while cpu.peek(cpu.program_counter) != 0x00 {
+            result.push(trace(&cpu));
+            cpu.step();
+        }
+
+        assert_eq!(
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            result[0]
+        );
+        assert_eq!(
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            result[1]
+        );
+        assert_eq!(
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            result[2]
+        );
+    }
+
+    #[test]
+    fn test_format_trace_with_symbols() {
+        let mut bus = Bus::new(test_rom());
+        // JSR $0610 / STA $0200
+        bus.mem_write(0x0600, 0x20);
+        bus.mem_write(0x0601, 0x10);
+        bus.mem_write(0x0602, 0x06);
+        bus.mem_write(0x0603, 0x8D);
+        bus.mem_write(0x0604, 0x00);
+        bus.mem_write(0x0605, 0x02);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        let mut symbols = SymbolTable::new();
+        symbols.add("update", 0x0610, None);
+        symbols.add("buffer", 0x0200, None);
+
+        assert_eq!(
+            "0600  20 10 06  JSR update                      A:00 X:00 Y:00 P:24 SP:FD",
+            trace_with_symbols(&cpu, &symbols)
+        );
+        cpu.program_counter = 0x0603;
+        assert_eq!(
+            "0603  8D 00 02  STA buffer = 00                 A:00 X:00 Y:00 P:24 SP:FD",
+            trace_with_symbols(&cpu, &symbols)
+        );
+    }
+
+    #[test]
+    fn test_format_mem_access() {
+        let mut bus = Bus::new(test_rom());
+        // ORA ($33), Y
+        bus.mem_write(100, 0x11);
+        bus.mem_write(101, 0x33);
+
+        // data
+        bus.mem_write(0x33, 0x00);
+        bus.mem_write(0x34, 0x04);
+
+        // target cell
+        bus.mem_write(0x0400, 0xAA);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        cpu.register_y = 0;
+
+        let mut result: Vec<String> = vec![];
+        while cpu.peek(cpu.program_counter) != 0x00 {
+            result.push(trace(&cpu));
+            cpu.step();
+        }
+        assert_eq!(
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            result[0]
+        );
+    }
+
+    #[test]
+    fn test_step_result() {
+        let mut bus = FlatBus::new();
+        // LDA #$01 / BNE +1 / (skip) / LDA $12F0,X / *JAM
+        bus.load(
+            0x8000,
+            &[0xA9, 0x01, 0xD0, 0x01, 0xEA, 0xBD, 0xF0, 0x12, 0x02],
+        );
+        bus.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.register_x = 0x10;
+        assert_eq!(cpu.cycles, 7);
+
+        assert_eq!(
+            cpu.step(),
+            StepResult::Executed {
+                opcode: 0xA9,
+                cycles: 2
+            }
+        );
+        // 分岐成立で +1
+        assert_eq!(
+            cpu.step(),
+            StepResult::Executed {
+                opcode: 0xD0,
+                cycles: 3
+            }
+        );
+        // ページをまたぐので +1
+        assert_eq!(
+            cpu.step(),
+            StepResult::Executed {
+                opcode: 0xBD,
+                cycles: 5
+            }
+        );
+        assert_eq!(cpu.cycles, 17);
+        assert_eq!(cpu.step(), StepResult::Halted { pc: 0x8008 });
+        assert_eq!(cpu.step(), StepResult::Halted { pc: 0x8008 });
+        assert_eq!(cpu.cycles, 17);
+    }
+
+    #[test]
+    fn test_step_unknown_opcode_and_breakpoint() {
+        let mut bus = FlatBus::new();
+        // INX / INX / INX / $9B (未実装)
+        bus.load(0x8000, &[0xE8, 0xE8, 0xE8, 0x9B]);
+        bus.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.breakpoints.insert(0x8001);
+
+        assert_eq!(
+            cpu.run_until(|_| false),
+            StepResult::Breakpoint { pc: 0x8001 }
+        );
+        assert_eq!(cpu.register_x, 1);
+        // 同じ所から再開すると止まらない
+        assert_eq!(
+            cpu.run_until(|_| false),
+            StepResult::Fault(CpuFault {
+                pc: 0x8003,
+                opcode: 0x9B,
+                mode: AddressingMode::NoneAddressing,
+                kind: FaultKind::UnknownOpcode,
+            })
+        );
+        assert_eq!(cpu.register_x, 3);
+        assert_eq!(cpu.program_counter, 0x8003);
+
+        // NOP として読み飛ばす
+        cpu.fault_policy = FaultPolicy::TreatAsNop;
+        assert_eq!(
+            cpu.step(),
+            StepResult::Executed {
+                opcode: 0x9B,
+                cycles: 2
+            }
+        );
+        assert_eq!(cpu.program_counter, 0x8004);
+    }
+
+    // 命令の途中でフォルトするオペコードはテーブルにないので、命令の前にオブザーバから起こす。
+    // 最初の命令だけ
+    pub(crate) struct FaultInjector {
+        pub(crate) armed: bool,
+    }
+
+    impl Observer<FlatBus> for FaultInjector {
+        fn before_instruction(&mut self, cpu: &mut CPU<FlatBus>) {
+            if std::mem::take(&mut self.armed) {
+                cpu.raise_fault(
+                    FaultKind::Unimplemented("test".to_string()),
+                    &AddressingMode::Absolute,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fault_discards_instruction() {
+        let mut bus = FlatBus::new();
+        // INC $0010
+        bus.load(0x8000, &[0xEE, 0x10, 0x00]);
+        bus.load(0x0010, &[0x7F]);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x8000;
+        cpu.status = 0;
+        cpu.add_observer(Box::new(FaultInjector { armed: true }));
+
+        assert_eq!(
+            cpu.step(),
+            StepResult::Fault(CpuFault {
+                pc: 0x8000,
+                opcode: 0xEE,
+                mode: AddressingMode::Absolute,
+                kind: FaultKind::Unimplemented("test".to_string()),
+            })
+        );
+        // フォルトの後の書き込みは捨て、レジスタは命令の前に戻す
+        assert_eq!(cpu.peek(0x0010), 0x7F);
+        assert_eq!(cpu.status, 0);
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.cycles, 0);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut bus = FlatBus::new();
+        // loop: INX / JMP loop
+        bus.load(0x8000, &[0xE8, 0x4C, 0x00, 0x80]);
+        bus.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+
+        cpu.run_cycles(50);
+        // 1 周 5 サイクル
+        assert_eq!(cpu.cycles, 57);
+        assert_eq!(cpu.register_x, 10);
+        assert_eq!(
+            cpu.run_until(|cpu| cpu.register_x == 0x20),
+            StepResult::Executed {
+                opcode: 0xE8,
+                cycles: 2
+            }
+        );
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    // バスアクセスを順番に記録するバス
+    struct RecordingBus {
+        inner: FlatBus,
+        accesses: Vec<Access>,
+    }
+
+    impl Mem for RecordingBus {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.accesses.push(Access::Read(addr));
+            self.inner.mem_read(addr)
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.accesses.push(Access::Write(addr, data));
+            self.inner.mem_write(addr, data)
         }
-        _ => {
-            format!("")
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.inner.peek(addr)
         }
     }
-}
 
-fn cpu2str(cpu: &CPU) -> String {
-    format!(
-        "A:{:<02X} X:{:<02X} Y:{:<02X} P:{:<02X} SP:{:<02X}",
-        cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
-    )
-}
+    fn recording_cpu(pc: u16, source: &str) -> CPU<RecordingBus> {
+        let mut inner = FlatBus::new();
+        assemble_for(Variant::Ricoh2A03, &format!(".org ${:04X}\n{}", pc, source))
+            .unwrap()
+            .load(&mut inner);
+        let mut cpu = CPU::new(RecordingBus {
+            inner,
+            accesses: vec![],
+        });
+        cpu.program_counter = pc;
+        cpu
+    }
 
-#[cfg(test)]
-mod test {
+    #[test]
+    fn test_trace_does_not_read() {
+        let mut cpu = recording_cpu(0x0064, "ORA ($33),Y\nJMP ($0200)");
+        cpu.bus.inner.load(0x0033, &[0x00, 0x04]);
 
-    use super::*;
-    use crate::bus::Bus;
-    use crate::cartridge::test::test_rom;
+        trace(&cpu);
+        assert!(cpu.bus.accesses.is_empty());
+        cpu.step();
+        assert!(!cpu.bus.accesses.is_empty());
+        cpu.bus.accesses.clear();
+        trace(&cpu);
+        assert!(cpu.bus.accesses.is_empty());
+    }
 
+    // 実機は 1 サイクルに必ず 1 回バスにアクセスする
     #[test]
-    fn test_format_trace() {
-        let mut bus = Bus::new(test_rom());
-        bus.mem_write(100, 0xa2);
-        bus.mem_write(101, 0x01);
-        bus.mem_write(102, 0xca);
-        bus.mem_write(103, 0x88);
-        bus.mem_write(104, 0x00);
+    fn test_bus_accesses_match_cycles() {
+        for op in CPU_OPS_CODES.iter() {
+            if op.name == "*JAM" {
+                continue;
+            }
+            // X/Y が $FF だとインデックス付きはページをまたぐ。分岐もフラグ次第で成立する
+            for (index, status) in [(0x00, 0x00), (0xFF, 0xFF), (0x10, 0x00)] {
+                // $02F0 に置くと分岐先 ($0312) がページをまたぐ
+                let mut cpu = recording_cpu(0x02F0, &format!(".byte ${:02X}, $20, $12", op.code));
+                cpu.bus.inner.load(0x0020, &[0xF0, 0x12]);
+                cpu.register_x = index;
+                cpu.register_y = index;
+                cpu.status = status;
+
+                let cycles = match cpu.step() {
+                    StepResult::Executed { cycles, .. } => cycles,
+                    other => panic!("{:02X} {}: {:?}", op.code, op.name, other),
+                };
+                assert_eq!(
+                    cpu.bus.accesses.len(),
+                    cycles as usize,
+                    "{:02X} {} {:?} X/Y={:02X}: {:?}",
+                    op.code,
+                    op.name,
+                    op.addressing_mode,
+                    index,
+                    cpu.bus.accesses
+                );
+            }
+        }
+    }
 
-        let mut cpu = CPU::new(bus);
-        cpu.program_counter = 0x64;
-        cpu.register_a = 1;
-        cpu.register_x = 2;
-        cpu.register_y = 3;
+    #[test]
+    fn test_bus_access_sequence() {
+        use Access::*;
+
+        // ページをまたぐ
+        let mut cpu = recording_cpu(0x0200, "INC $12F0,X");
+        cpu.bus.inner.load(0x1300, &[0x41]);
+        cpu.register_x = 0x10;
+        cpu.step();
+        assert_eq!(
+            cpu.bus.accesses,
+            vec![
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0202),
+                Read(0x1200),
+                Read(0x1300),
+                Write(0x1300, 0x41),
+                Write(0x1300, 0x42),
+            ]
+        );
 
-        let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
+        // ページをまたがなければ空読みしない
+        let mut cpu = recording_cpu(0x0200, "LDA ($20),Y");
+        cpu.bus.inner.load(0x0020, &[0x00, 0x04]);
+        cpu.register_y = 0x05;
+        cpu.step();
+        assert_eq!(
+            cpu.bus.accesses,
+            vec![
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0020),
+                Read(0x0021),
+                Read(0x0405),
+            ]
+        );
 
+        // JSR $1234 / RTS
+        let mut cpu = recording_cpu(0x0200, "JSR $1234");
+        cpu.bus.inner.load(0x1234, &[0x60]);
+        cpu.stack_pointer = 0xFD;
+        cpu.step();
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
-            result[0]
+            cpu.bus.accesses,
+            vec![
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x01FD),
+                Write(0x01FD, 0x02),
+                Write(0x01FC, 0x02),
+                Read(0x0202),
+            ]
         );
+        cpu.bus.accesses.clear();
+        cpu.step();
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
-            result[1]
+            cpu.bus.accesses,
+            vec![
+                Read(0x1234),
+                Read(0x1235),
+                Read(0x01FB),
+                Read(0x01FC),
+                Read(0x01FD),
+                Read(0x0202),
+            ]
         );
+        assert_eq!(cpu.program_counter, 0x0203);
+
+        // BRK
+        let mut cpu = recording_cpu(0x0200, "BRK");
+        cpu.bus.inner.load(0xFFFE, &[0x00, 0x90]);
+        cpu.stack_pointer = 0xFD;
+        cpu.status = FLAG_BREAK2;
+        cpu.step();
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
-            result[2]
+            cpu.bus.accesses,
+            vec![
+                Read(0x0200),
+                Read(0x0201),
+                Write(0x01FD, 0x02),
+                Write(0x01FC, 0x02),
+                Write(0x01FB, FLAG_BREAK | FLAG_BREAK2),
+                Read(0xFFFE),
+                Read(0xFFFF),
+            ]
         );
+        assert_eq!(cpu.program_counter, 0x9000);
+        assert_eq!(cpu.status, FLAG_BREAK2 | FLAG_INTERRRUPT);
+    }
+
+    // JSR $8010 / NOP ... $8010: JSR $8020 / RTS ... $8020: PHA / PLA / RTS ... $8030: RTI
+    pub(crate) fn call_stack_cpu() -> CPU<FlatBus> {
+        let mut bus = FlatBus::new();
+        bus.load(0x8000, &[0x20, 0x10, 0x80, 0xEA]);
+        bus.load(0x8010, &[0x20, 0x20, 0x80, 0x60]);
+        bus.load(0x8020, &[0x48, 0x68, 0x60]);
+        bus.load(0x8030, &[0x40]);
+        bus.load(0xFFFA, &[0x30, 0x80]);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x8000;
+        cpu
     }
 
     #[test]
-    fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
-        // ORA ($33), Y
-        bus.mem_write(100, 0x11);
-        bus.mem_write(101, 0x33);
+    fn test_call_stack() {
+        let mut cpu = call_stack_cpu();
+        cpu.step();
+        cpu.step();
+        assert_eq!(
+            cpu.call_stack,
+            vec![
+                Frame {
+                    kind: FrameKind::Jsr,
+                    from: 0x8000,
+                    to: 0x8010,
+                    sp: 0xFD
+                },
+                Frame {
+                    kind: FrameKind::Jsr,
+                    from: 0x8010,
+                    to: 0x8020,
+                    sp: 0xFB
+                },
+            ]
+        );
 
-        // data
-        bus.mem_write(0x33, 0x00);
-        bus.mem_write(0x34, 0x04);
+        cpu.trigger_nmi();
+        cpu.step();
+        assert_eq!(
+            cpu.call_stack[2],
+            Frame {
+                kind: FrameKind::Interrupt(Interrupt::Nmi),
+                from: 0x8020,
+                to: 0x8030,
+                sp: 0xF9
+            }
+        );
+        // RTI
+        cpu.step();
+        assert_eq!(cpu.call_stack.len(), 2);
+        // PHA / PLA はデータなので段は変わらない
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.call_stack.len(), 2);
+        cpu.step();
+        assert_eq!(cpu.call_stack.len(), 1);
+        cpu.step();
+        assert!(cpu.call_stack.is_empty());
+        assert_eq!(cpu.program_counter, 0x8003);
 
-        // target cell
-        bus.mem_write(0x0400, 0xAA);
+        // TXS などで SP を戻してスタックを捨てたら、次の命令の後に段も捨てる
+        let mut cpu = call_stack_cpu();
+        cpu.step();
+        cpu.step();
+        cpu.stack_pointer = 0xFF;
+        cpu.step();
+        assert!(cpu.call_stack.is_empty());
+    }
 
-        let mut cpu = CPU::new(bus);
-        cpu.program_counter = 0x64;
-        cpu.register_y = 0;
+    #[test]
+    fn test_power_on_and_reset() {
+        let mut cpu = recording_cpu(0x8000, "NOP");
+        cpu.bus.inner.load(0xFFFC, &[0x00, 0x80]);
+        cpu.power_on(RamInit::default());
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.register_y), (0, 0, 0));
+        assert_eq!(cpu.status, FLAG_INTERRRUPT | FLAG_BREAK2);
+        assert_eq!(cpu.stack_pointer, 0xFD);
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.cycles, 7);
+
+        cpu.register_a = 0x12;
+        cpu.register_x = 0x34;
+        cpu.status = FLAG_CARRY;
+        cpu.stack_pointer = 0xF0;
+        cpu.bus.accesses.clear();
+        cpu.reset();
 
-        let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
+        // A/X/Y はそのまま、SP は 3 減って I が立つ
+        assert_eq!((cpu.register_a, cpu.register_x), (0x12, 0x34));
+        assert_eq!(cpu.status, FLAG_CARRY | FLAG_INTERRRUPT);
+        assert_eq!(cpu.stack_pointer, 0xED);
+        assert_eq!(cpu.cycles, 14);
+        // スタックには書かない
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
-            result[0]
+            cpu.bus.accesses,
+            vec![
+                Access::Read(0x8000),
+                Access::Read(0x8000),
+                Access::Read(0x01F0),
+                Access::Read(0x01EF),
+                Access::Read(0x01EE),
+                Access::Read(0xFFFC),
+                Access::Read(0xFFFD),
+            ]
         );
     }
 
-    /* Instruction tests
-    use super::*;
-    fn run<F>(program: Vec<u8>, f: F) -> CPU
+    // Instruction tests
+    // プログラムは $8000 にアセンブルして、リセットベクタから実行する。
+    // メモリは 0 (BRK) で埋まっているので、末尾の BRK は書かなくてよい
+    fn run<F>(source: &str, f: F) -> CPU<FlatBus>
+    where
+        F: Fn(&mut CPU<FlatBus>),
+    {
+        run_as(Variant::Ricoh2A03, source, f)
+    }
+
+    fn run_as<F>(variant: Variant, source: &str, f: F) -> CPU<FlatBus>
     where
-        F: Fn(&mut CPU),
+        F: Fn(&mut CPU<FlatBus>),
     {
-        let mut cpu = CPU::new(Rom::empty());
-        cpu.load();
+        let program = assemble_for(variant, &format!(".org $8000\n{}", source)).unwrap();
+        let mut bus = FlatBus::new();
+        program.load(&mut bus);
+        bus.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = CPU::new(bus);
+        cpu.variant = variant;
         cpu.reset();
+        // ここのテストは status 0, SP $FF から始める前提で書いてある
+        cpu.status = 0;
+        cpu.stack_pointer = 0xFF;
         f(&mut cpu);
-        cpu.run();
+        run_to_brk(&mut cpu);
         cpu
     }
 
-    fn assert_status(cpu: &CPU, flags: u8) {
+    // BRK の手前まで実行する (PC は BRK を指す)
+    pub(crate) fn run_to_brk<B: Mem>(cpu: &mut CPU<B>) -> StepResult {
+        cpu.run_until(|cpu| cpu.peek(cpu.program_counter) == 0x00)
+    }
+
+    fn assert_status<B: Mem>(cpu: &CPU<B>, flags: u8) {
         assert_eq!(cpu.status, flags)
     }
 
     // LDA
     #[test]
     fn test_0xa9_lda_immidiate_load_data() {
-        let cpu = run(vec![0xa9, 0x05, 0x00], |_| {});
+        let cpu = run("LDA #$05", |_| {});
         assert_eq!(cpu.register_a, 0x05);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_0xa9_lda_zero_flag() {
-        let cpu = run(vec![0xa9, 0x00, 0x00], |_| {});
+        let cpu = run("LDA #$00", |_| {});
         assert_status(&cpu, FLAG_ZERO);
     }
 
     #[test]
     fn test_0xa9_lda_negative_flag() {
-        let cpu = run(vec![0xa9, 0x80, 0x00], |_| {});
+        let cpu = run("LDA #$80", |_| {});
         assert_status(&cpu, FLAG_NEGATIVE);
     }
 
     #[test]
     fn test_lda_from_memory_zero_page() {
-        let cpu = run(vec![0xa5, 0x10, 0x00], |cpu| {
+        let cpu = run("LDA $10", |cpu| {
             cpu.mem_write(0x10, 0x55);
         });
         assert_eq!(cpu.register_a, 0x55);
@@ -1098,7 +2627,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory_zero_page_x() {
-        let cpu = run(vec![0xb5, 0x10, 0x00], |cpu| {
+        let cpu = run("LDA $10,X", |cpu| {
             cpu.mem_write(0x11, 0x56);
             cpu.register_x = 0x01;
         });
@@ -1107,7 +2636,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory_absolute() {
-        let cpu = run(vec![0xad, 0x10, 0xaa, 0x00], |cpu| {
+        let cpu = run("LDA $AA10", |cpu| {
             cpu.mem_write(0xAA10, 0x57);
         });
         assert_eq!(cpu.register_a, 0x57);
@@ -1115,7 +2644,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory_absolute_x() {
-        let cpu = run(vec![0xbd, 0x10, 0xaa, 0x00], |cpu| {
+        let cpu = run("LDA $AA10,X", |cpu| {
             cpu.mem_write(0xAA15, 0x58);
             cpu.register_x = 0x05;
         });
@@ -1124,7 +2653,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory_absolute_y() {
-        let cpu = run(vec![0xb9, 0x10, 0xaa, 0x00], |cpu| {
+        let cpu = run("LDA $AA10,Y", |cpu| {
             cpu.mem_write(0xAA18, 0x59);
             cpu.register_y = 0x08;
         });
@@ -1133,7 +2662,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory_indirect_x() {
-        let cpu = run(vec![0xa1, 0x10, 0x00], |cpu| {
+        let cpu = run("LDA ($10,X)", |cpu| {
             cpu.mem_write_u16(0x18, 0xFF05);
             cpu.mem_write(0xFF05, 0x5A);
             cpu.register_x = 0x08;
@@ -1143,7 +2672,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory_indirect_y() {
-        let cpu = run(vec![0xb1, 0x10, 0x00], |cpu| {
+        let cpu = run("LDA ($10),Y", |cpu| {
             cpu.mem_write_u16(0x10, 0xFF06);
             cpu.mem_write(0xFF09, 0x5B);
             cpu.register_y = 0x03;
@@ -1153,23 +2682,23 @@ mod test {
 
     #[test]
     fn test_5_ops_working_together() {
-        let cpu = run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00], |_| {});
+        let cpu = run("LDA #$C0\nTAX\nINX", |_| {});
         assert_eq!(cpu.register_x, 0xc1);
     }
 
     // STA
     #[test]
     fn test_sta_from_memory() {
-        let cpu = run(vec![0x85, 0x10, 0x00], |cpu| {
+        let cpu = run("STA $10", |cpu| {
             cpu.register_a = 0xBA;
         });
-        assert_eq!(cpu.mem_read(0x10), 0xBA);
+        assert_eq!(cpu.peek(0x10), 0xBA);
     }
 
     // ADC
     #[test]
     fn test_adc_no_carry() {
-        let cpu = run(vec![0x69, 0x10, 0x00], |cpu| {
+        let cpu = run("ADC #$10", |cpu| {
             cpu.register_a = 0x20;
         });
         assert_eq!(cpu.register_a, 0x30);
@@ -1178,7 +2707,7 @@ mod test {
 
     #[test]
     fn test_adc_has_carry() {
-        let cpu = run(vec![0x69, 0x10, 0x00], |cpu| {
+        let cpu = run("ADC #$10", |cpu| {
             cpu.register_a = 0x20;
             cpu.status = FLAG_CARRY;
         });
@@ -1188,7 +2717,7 @@ mod test {
 
     #[test]
     fn test_adc_occur_carry() {
-        let cpu = run(vec![0x69, 0x01, 0x00], |cpu| {
+        let cpu = run("ADC #$01", |cpu| {
             cpu.register_a = 0xFF;
         });
         assert_eq!(cpu.register_a, 0x00);
@@ -1197,7 +2726,7 @@ mod test {
 
     #[test]
     fn test_adc_occur_overflow_plus() {
-        let cpu = run(vec![0x69, 0x10, 0x00], |cpu| {
+        let cpu = run("ADC #$10", |cpu| {
             cpu.register_a = 0x7F;
         });
         assert_eq!(cpu.register_a, 0x8F);
@@ -1206,7 +2735,7 @@ mod test {
 
     #[test]
     fn test_adc_occur_overflow_plus_with_carry() {
-        let cpu = run(vec![0x69, 0x6F, 0x00], |cpu| {
+        let cpu = run("ADC #$6F", |cpu| {
             cpu.register_a = 0x10;
             cpu.status = FLAG_CARRY;
         });
@@ -1216,7 +2745,7 @@ mod test {
 
     #[test]
     fn test_adc_occur_overflow_minus() {
-        let cpu = run(vec![0x69, 0x81, 0x00], |cpu| {
+        let cpu = run("ADC #$81", |cpu| {
             cpu.register_a = 0x81;
         });
         assert_eq!(cpu.register_a, 0x02);
@@ -1225,7 +2754,7 @@ mod test {
 
     #[test]
     fn test_adc_occur_overflow_minus_with_carry() {
-        let mut cpu = run(vec![0x69, 0x80, 0x00], |cpu| {
+        let mut cpu = run("ADC #$80", |cpu| {
             cpu.register_a = 0x80;
             cpu.status = FLAG_CARRY;
         });
@@ -1235,7 +2764,7 @@ mod test {
 
     #[test]
     fn test_adc_no_overflow() {
-        let cpu = run(vec![0x69, 0x7F, 0x00], |cpu| {
+        let cpu = run("ADC #$7F", |cpu| {
             cpu.register_a = 0x82;
         });
         assert_eq!(cpu.register_a, 0x01);
@@ -1245,7 +2774,7 @@ mod test {
     // SBC
     #[test]
     fn test_sbc_no_carry() {
-        let cpu = run(vec![0xe9, 0x10, 0x00], |cpu| {
+        let cpu = run("SBC #$10", |cpu| {
             cpu.register_a = 0x20;
         });
         assert_eq!(cpu.register_a, 0x0F);
@@ -1254,7 +2783,7 @@ mod test {
 
     #[test]
     fn test_sbc_has_carry() {
-        let mut cpu = run(vec![0xe9, 0x10, 0x00], |cpu| {
+        let mut cpu = run("SBC #$10", |cpu| {
             cpu.register_a = 0x20;
             cpu.status = FLAG_CARRY;
         });
@@ -1264,7 +2793,7 @@ mod test {
 
     #[test]
     fn test_sbc_occur_carry() {
-        let cpu = run(vec![0xe9, 0x02, 0x00], |cpu| {
+        let cpu = run("SBC #$02", |cpu| {
             cpu.register_a = 0x01;
         });
         assert_eq!(cpu.register_a, 0xFE);
@@ -1273,7 +2802,7 @@ mod test {
 
     #[test]
     fn test_sbc_occur_overflow() {
-        let cpu = run(vec![0xe9, 0x81, 0x00], |cpu| {
+        let cpu = run("SBC #$81", |cpu| {
             cpu.register_a = 0x7F;
         });
         assert_eq!(cpu.register_a, 0xFD);
@@ -1282,7 +2811,7 @@ mod test {
 
     #[test]
     fn test_sbc_occur_overflow_with_carry() {
-        let cpu = run(vec![0xe9, 0x81, 0x00], |cpu| {
+        let cpu = run("SBC #$81", |cpu| {
             cpu.register_a = 0x7F;
             cpu.status = FLAG_CARRY;
         });
@@ -1292,7 +2821,7 @@ mod test {
 
     #[test]
     fn test_sbc_no_overflow() {
-        let cpu = run(vec![0xe9, 0x7F, 0x00], |cpu| {
+        let cpu = run("SBC #$7F", |cpu| {
             cpu.register_a = 0x7E;
             cpu.status = FLAG_CARRY;
         });
@@ -1300,10 +2829,227 @@ mod test {
         assert_status(&cpu, FLAG_NEGATIVE);
     }
 
+    // BCD
+    fn run_decimal(variant: Variant, source: &str, a: u8, carry: u8) -> CPU<FlatBus> {
+        run_as(variant, source, |cpu| {
+            cpu.register_a = a;
+            cpu.status = FLAG_DECIMAL | carry;
+        })
+    }
+
+    #[test]
+    fn test_adc_decimal() {
+        let cpu = run_decimal(Variant::Nmos6502, "ADC #$46", 0x58, FLAG_CARRY);
+        assert_eq!(cpu.register_a, 0x05);
+        // N/V は補正途中の $A5 から
+        assert_status(
+            &cpu,
+            FLAG_DECIMAL | FLAG_CARRY | FLAG_OVERFLOW | FLAG_NEGATIVE,
+        );
+
+        let cpu = run_decimal(Variant::Cmos65C02, "ADC #$34", 0x12, 0);
+        assert_eq!(cpu.register_a, 0x46);
+        assert_status(&cpu, FLAG_DECIMAL);
+
+        // 2A03 は D フラグを無視する
+        let cpu = run_decimal(Variant::Ricoh2A03, "ADC #$46", 0x58, FLAG_CARRY);
+        assert_eq!(cpu.register_a, 0x9F);
+        assert_status(&cpu, FLAG_DECIMAL | FLAG_NEGATIVE | FLAG_OVERFLOW);
+    }
+
+    #[test]
+    fn test_adc_decimal_flags() {
+        // 99 + 01 = 00 (キャリー)。NMOS は Z が 2 進の $9A から、N が補正途中の $A0 から決まる
+        let cpu = run_decimal(Variant::Nmos6502, "ADC #$01", 0x99, 0);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_status(&cpu, FLAG_DECIMAL | FLAG_CARRY | FLAG_NEGATIVE);
+
+        let cpu = run_decimal(Variant::Cmos65C02, "ADC #$01", 0x99, 0);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_status(&cpu, FLAG_DECIMAL | FLAG_CARRY | FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_sbc_decimal() {
+        for variant in [Variant::Nmos6502, Variant::Cmos65C02] {
+            let cpu = run_decimal(variant, "SBC #$12", 0x46, FLAG_CARRY);
+            assert_eq!(cpu.register_a, 0x34);
+            assert_status(&cpu, FLAG_DECIMAL | FLAG_CARRY);
+
+            let cpu = run_decimal(variant, "SBC #$13", 0x40, FLAG_CARRY);
+            assert_eq!(cpu.register_a, 0x27);
+            assert_status(&cpu, FLAG_DECIMAL | FLAG_CARRY);
+
+            let cpu = run_decimal(variant, "SBC #$01", 0x00, FLAG_CARRY);
+            assert_eq!(cpu.register_a, 0x99);
+            assert_status(&cpu, FLAG_DECIMAL | FLAG_NEGATIVE);
+        }
+
+        // NMOS は Z も 2 進の結果 ($30 - $29 - 1 = $06) から
+        let cpu = run_decimal(Variant::Nmos6502, "SBC #$29", 0x30, 0);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_status(&cpu, FLAG_DECIMAL | FLAG_CARRY);
+        let cpu = run_decimal(Variant::Cmos65C02, "SBC #$29", 0x30, 0);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_status(&cpu, FLAG_DECIMAL | FLAG_CARRY | FLAG_ZERO);
+    }
+
+    // 65C02
+    fn run_cmos<F>(source: &str, f: F) -> CPU<FlatBus>
+    where
+        F: Fn(&mut CPU<FlatBus>),
+    {
+        run_as(Variant::Cmos65C02, source, f)
+    }
+
+    #[test]
+    fn test_cmos_opcode_table() {
+        for code in 0..=0xFFu8 {
+            let count = CMOS_OPS_CODES.iter().filter(|op| op.code == code).count();
+            assert_eq!(count, 1, "{:02X}", code);
+        }
+        let mut cpu = CPU::new(FlatBus::new());
+        assert_eq!(cpu.find_ops(0x80).unwrap().name, "*NOP");
+        cpu.variant = Variant::Cmos65C02;
+        assert_eq!(cpu.find_ops(0x80).unwrap().name, "BRA");
+    }
+
+    #[test]
+    fn test_cmos_bra_and_stack() {
+        let cpu = run_cmos(
+            "
+                BRA skip
+                LDA #$01
+            skip:
+                PHX
+                PLY
+                INC A
+            ",
+            |cpu| {
+                cpu.register_x = 0x42;
+            },
+        );
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(cpu.register_y, 0x42);
+        assert_eq!(cpu.stack_pointer, 0xFF);
+    }
+
+    #[test]
+    fn test_cmos_memory_ops() {
+        let cpu = run_cmos(
+            "
+                TRB $10
+                TSB $11
+                STZ $12
+                LDA ($20)
+            ",
+            |cpu| {
+                cpu.register_a = 0x0F;
+                cpu.mem_write(0x10, 0xFF);
+                cpu.mem_write(0x11, 0x30);
+                cpu.mem_write(0x12, 0x55);
+                cpu.mem_write_u16(0x20, 0x0400);
+                cpu.mem_write(0x0400, 0x77);
+            },
+        );
+        assert_eq!(cpu.peek(0x10), 0xF0);
+        assert_eq!(cpu.peek(0x11), 0x3F);
+        assert_eq!(cpu.peek(0x12), 0x00);
+        assert_eq!(cpu.register_a, 0x77);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        // すべてのページの $xxFF で試す
+        for page in 0..=0xFFu16 {
+            let vector = page << 8 | 0xFF;
+            let pc = page << 8 | 0x80;
+            for (variant, target) in [
+                (Variant::Ricoh2A03, 0x1234),
+                (Variant::Nmos6502, 0x1234),
+                (Variant::Cmos65C02, 0x5634),
+            ] {
+                let mut bus = FlatBus::new();
+                bus.load(pc, &[0x6c, 0xFF, page as u8]);
+                bus.load(vector, &[0x34]);
+                bus.load(page << 8, &[0x12]);
+                bus.load(vector.wrapping_add(1), &[0x56]);
+                let mut cpu = CPU::new(bus);
+                cpu.variant = variant;
+                cpu.program_counter = pc;
+                assert_eq!(cpu.peek_indirect(vector), target);
+                cpu.step();
+                assert_eq!(cpu.program_counter, target, "{:?} ${:04X}", variant, vector);
+            }
+        }
+    }
+
+    // ゼロページのポインタはすべての位置で $FF の次が $00 になる
+    #[test]
+    fn test_zero_page_pointer_wrap() {
+        for ptr in 0..=0xFFu8 {
+            let next = ptr.wrapping_add(1);
+            let target = 0x0300 | ptr as u16;
+            let setup = |program: &[u8]| {
+                let mut bus = FlatBus::new();
+                bus.load(0x8000, program);
+                bus.load(ptr as u16, &[ptr]);
+                bus.load(next as u16, &[0x03]);
+                bus.load(target, &[0x5A]);
+                bus.load(target.wrapping_add(1), &[0xA5]);
+                let mut cpu = CPU::new(bus);
+                cpu.program_counter = 0x8000;
+                cpu
+            };
+
+            // LDA ($nn,X): $nn + X が ptr になるように
+            let mut cpu = setup(&[0xa1, ptr.wrapping_sub(0x10)]);
+            cpu.register_x = 0x10;
+            assert_eq!(cpu.peek_zero_page_u16(ptr), target);
+            cpu.step();
+            assert_eq!(cpu.register_a, 0x5A, "(${:02X},X)", ptr);
+
+            // LDA ($nn),Y
+            let mut cpu = setup(&[0xb1, ptr]);
+            cpu.register_y = 0x01;
+            cpu.step();
+            assert_eq!(cpu.register_a, 0xA5, "(${:02X}),Y", ptr);
+
+            // LDA ($nn) (65C02)
+            let mut cpu = setup(&[0xb2, ptr]);
+            cpu.variant = Variant::Cmos65C02;
+            cpu.step();
+            assert_eq!(cpu.register_a, 0x5A, "(${:02X})", ptr);
+        }
+    }
+
+    #[test]
+    fn test_cmos_cycles() {
+        let mut bus = FlatBus::new();
+        // ADC #$01 (BCD) / BRK
+        bus.load(0x8000, &[0x69, 0x01, 0x00]);
+        bus.load(0xFFFE, &[0x00, 0x90]);
+        let mut cpu = CPU::new(bus);
+        cpu.variant = Variant::Cmos65C02;
+        cpu.program_counter = 0x8000;
+        cpu.status = FLAG_DECIMAL;
+        assert_eq!(
+            cpu.step(),
+            StepResult::Executed {
+                opcode: 0x69,
+                cycles: 3
+            }
+        );
+        // BRK で D フラグが下りる
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x9000);
+        assert_eq!(cpu.status & FLAG_DECIMAL, 0);
+    }
+
     // AND
     #[test]
     fn test_and() {
-        let cpu = run(vec![0x29, 0x0C, 0x00], |cpu| {
+        let cpu = run("AND #$0C", |cpu| {
             cpu.register_a = 0x0A;
         });
         assert_eq!(cpu.register_a, 0x08);
@@ -1313,7 +3059,7 @@ mod test {
     // EOR
     #[test]
     fn test_eor() {
-        let cpu = run(vec![0x49, 0x0C, 0x00], |cpu| {
+        let cpu = run("EOR #$0C", |cpu| {
             cpu.register_a = 0x0A;
         });
         assert_eq!(cpu.register_a, 0x06);
@@ -1323,7 +3069,7 @@ mod test {
     // ORA
     #[test]
     fn test_ora() {
-        let cpu = run(vec![0x09, 0x0C, 0x00], |cpu| {
+        let cpu = run("ORA #$0C", |cpu| {
             cpu.register_a = 0x0A;
         });
         assert_eq!(cpu.register_a, 0x0E);
@@ -1333,7 +3079,7 @@ mod test {
     // ASL
     #[test]
     fn test_asl_a() {
-        let cpu = run(vec![0x0A, 0x00], |cpu| {
+        let cpu = run("ASL A", |cpu| {
             cpu.register_a = 0x03;
         });
         assert_eq!(cpu.register_a, 0x03 * 2);
@@ -1342,16 +3088,16 @@ mod test {
 
     #[test]
     fn test_asl_zero_page() {
-        let cpu = run(vec![0x06, 0x01, 0x00], |cpu| {
+        let cpu = run("ASL $01", |cpu| {
             cpu.mem_write(0x0001, 0x03);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x03 * 2);
+        assert_eq!(cpu.peek(0x0001), 0x03 * 2);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_asl_a_occur_carry() {
-        let cpu = run(vec![0x0A, 0x00], |cpu| {
+        let cpu = run("ASL A", |cpu| {
             cpu.register_a = 0x81;
         });
         assert_eq!(cpu.register_a, 0x02);
@@ -1360,17 +3106,17 @@ mod test {
 
     #[test]
     fn test_asl_zero_page_occur_carry() {
-        let cpu = run(vec![0x06, 0x01, 0x00], |cpu| {
+        let cpu = run("ASL $01", |cpu| {
             cpu.mem_write(0x0001, 0x81);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x02);
+        assert_eq!(cpu.peek(0x0001), 0x02);
         assert_status(&cpu, FLAG_CARRY);
     }
 
     // LSR
     #[test]
     fn test_lsr_a() {
-        let cpu = run(vec![0x4A, 0x00], |cpu| {
+        let cpu = run("LSR A", |cpu| {
             cpu.register_a = 0x02;
         });
         assert_eq!(cpu.register_a, 0x01);
@@ -1379,25 +3125,25 @@ mod test {
 
     #[test]
     fn test_lsr_zero_page() {
-        let cpu = run(vec![0x46, 0x01, 0x00], |cpu| {
+        let cpu = run("LSR $01", |cpu| {
             cpu.mem_write(0x0001, 0x02);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x01);
+        assert_eq!(cpu.peek(0x0001), 0x01);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_lsr_zero_page_zero_flag() {
-        let cpu = run(vec![0x46, 0x01, 0x00], |cpu| {
+        let cpu = run("LSR $01", |cpu| {
             cpu.mem_write(0x0001, 0x01);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x00);
+        assert_eq!(cpu.peek(0x0001), 0x00);
         assert_status(&cpu, FLAG_ZERO | FLAG_CARRY);
     }
 
     #[test]
     fn test_lsr_a_occur_carry() {
-        let cpu = run(vec![0x4A, 0x00], |cpu| {
+        let cpu = run("LSR A", |cpu| {
             cpu.register_a = 0x03;
         });
         assert_eq!(cpu.register_a, 0x01);
@@ -1406,17 +3152,17 @@ mod test {
 
     #[test]
     fn test_lsr_zero_page_occur_carry() {
-        let cpu = run(vec![0x46, 0x01, 0x00], |cpu| {
+        let cpu = run("LSR $01", |cpu| {
             cpu.mem_write(0x0001, 0x03);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x01);
+        assert_eq!(cpu.peek(0x0001), 0x01);
         assert_status(&cpu, FLAG_CARRY);
     }
 
     // ROL
     #[test]
     fn test_rol_a() {
-        let cpu = run(vec![0x2A, 0x00], |cpu| {
+        let cpu = run("ROL A", |cpu| {
             cpu.register_a = 0x03;
         });
         assert_eq!(cpu.register_a, 0x03 * 2);
@@ -1425,16 +3171,16 @@ mod test {
 
     #[test]
     fn test_rol_zero_page() {
-        let cpu = run(vec![0x26, 0x01, 0x00], |cpu| {
+        let cpu = run("ROL $01", |cpu| {
             cpu.mem_write(0x0001, 0x03);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x03 * 2);
+        assert_eq!(cpu.peek(0x0001), 0x03 * 2);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_rol_a_with_carry() {
-        let cpu = run(vec![0x2A, 0x00], |cpu| {
+        let cpu = run("ROL A", |cpu| {
             cpu.register_a = 0x03;
             cpu.status = FLAG_CARRY;
         });
@@ -1444,17 +3190,17 @@ mod test {
 
     #[test]
     fn test_rol_zero_page_with_carry() {
-        let cpu = run(vec![0x26, 0x01, 0x00], |cpu| {
+        let cpu = run("ROL $01", |cpu| {
             cpu.mem_write(0x0001, 0x03);
             cpu.status = FLAG_CARRY;
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x03 * 2 + 1);
+        assert_eq!(cpu.peek(0x0001), 0x03 * 2 + 1);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_rol_a_zero_with_carry() {
-        let cpu = run(vec![0x2A, 0x00], |cpu| {
+        let cpu = run("ROL A", |cpu| {
             cpu.register_a = 0x00;
             cpu.status = FLAG_CARRY;
         });
@@ -1464,18 +3210,18 @@ mod test {
 
     #[test]
     fn test_rol_zero_page_zero_with_carry() {
-        let cpu = run(vec![0x26, 0x01, 0x00], |cpu| {
+        let cpu = run("ROL $01", |cpu| {
             cpu.mem_write(0x0001, 0x00);
             cpu.status = FLAG_CARRY;
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x01);
+        assert_eq!(cpu.peek(0x0001), 0x01);
         assert_status(&cpu, 0);
     }
 
     // ROR
     #[test]
     fn test_ror_a() {
-        let cpu = run(vec![0x6A, 0x00], |cpu| {
+        let cpu = run("ROR A", |cpu| {
             cpu.register_a = 0x02;
         });
         assert_eq!(cpu.register_a, 0x01);
@@ -1484,16 +3230,16 @@ mod test {
 
     #[test]
     fn test_ror_zero_page() {
-        let cpu = run(vec![0x66, 0x01, 0x00], |cpu| {
+        let cpu = run("ROR $01", |cpu| {
             cpu.mem_write(0x0001, 0x02);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x01);
+        assert_eq!(cpu.peek(0x0001), 0x01);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_ror_a_occur_carry() {
-        let cpu = run(vec![0x6A, 0x00], |cpu| {
+        let cpu = run("ROR A", |cpu| {
             cpu.register_a = 0x03;
         });
         assert_eq!(cpu.register_a, 0x01);
@@ -1502,16 +3248,16 @@ mod test {
 
     #[test]
     fn test_ror_zero_page_occur_carry() {
-        let cpu = run(vec![0x66, 0x01, 0x00], |cpu| {
+        let cpu = run("ROR $01", |cpu| {
             cpu.mem_write(0x0001, 0x03);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x01);
+        assert_eq!(cpu.peek(0x0001), 0x01);
         assert_status(&cpu, FLAG_CARRY);
     }
 
     #[test]
     fn test_ror_a_with_carry() {
-        let cpu = run(vec![0x6A, 0x00], |cpu| {
+        let cpu = run("ROR A", |cpu| {
             cpu.register_a = 0x03;
             cpu.status = FLAG_CARRY;
         });
@@ -1521,17 +3267,17 @@ mod test {
 
     #[test]
     fn test_ror_zero_page_with_carry() {
-        let cpu = run(vec![0x66, 0x01, 0x00], |cpu| {
+        let cpu = run("ROR $01", |cpu| {
             cpu.mem_write(0x0001, 0x03);
             cpu.status = FLAG_CARRY;
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x81);
+        assert_eq!(cpu.peek(0x0001), 0x81);
         assert_status(&cpu, FLAG_CARRY | FLAG_NEGATIVE);
     }
 
     #[test]
     fn test_ror_a_zero_with_carry() {
-        let cpu = run(vec![0x6A, 0x00], |cpu| {
+        let cpu = run("ROR A", |cpu| {
             cpu.register_a = 0x00;
             cpu.status = FLAG_CARRY;
         });
@@ -1541,117 +3287,189 @@ mod test {
 
     #[test]
     fn test_ror_zero_page_zero_with_carry() {
-        let cpu = run(vec![0x66, 0x01, 0x00], |cpu| {
+        let cpu = run("ROR $01", |cpu| {
             cpu.mem_write(0x0001, 0x00);
             cpu.status = FLAG_CARRY;
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x80);
+        assert_eq!(cpu.peek(0x0001), 0x80);
         assert_status(&cpu, FLAG_NEGATIVE);
     }
 
     // BCC
     #[test]
     fn test_bcc() {
-        let cpu = run(vec![0x90, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BCC skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     #[test]
     fn test_bcc_with_carry() {
-        let cpu = run(vec![0x90, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_CARRY;
-        });
+        let cpu = run(
+            "
+                BCC skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_CARRY;
+            },
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, FLAG_CARRY);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     #[test]
     fn test_bcc_negative() {
-        let cpu = run(vec![0x90, 0xfc, 0x00], |cpu| {
+        let cpu = run("BCC *-2", |cpu| {
             cpu.mem_write(0x7FFF, 0x00);
             cpu.mem_write(0x7FFE, 0xe8);
         });
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8000)
+        assert_eq!(cpu.program_counter, 0x7FFF)
     }
 
     // BCS
     #[test]
     fn test_bcs() {
-        let cpu = run(vec![0xb0, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BCS skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     #[test]
     fn test_bcs_with_carry() {
-        let cpu = run(vec![0xb0, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_CARRY;
-        });
+        let cpu = run(
+            "
+                BCS skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_CARRY;
+            },
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, FLAG_CARRY);
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     #[test]
     fn test_bcs_negative() {
-        let cpu = run(vec![0xb0, 0xfc, 0x00], |cpu| {
+        let cpu = run("BCS *-2", |cpu| {
             cpu.mem_write(0x7FFF, 0x00);
             cpu.mem_write(0x7FFE, 0xe8);
             cpu.status = FLAG_CARRY;
         });
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, FLAG_CARRY);
-        assert_eq!(cpu.program_counter, 0x8000)
+        assert_eq!(cpu.program_counter, 0x7FFF)
     }
 
     // BEQ
     #[test]
     fn test_beq() {
-        let cpu = run(vec![0xF0, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {});
+        let cpu = run(
+            "
+                BEQ skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {},
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     #[test]
     fn test_beq_with_zero_flag() {
-        let cpu = run(vec![0xF0, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_ZERO;
-        });
+        let cpu = run(
+            "
+                BEQ skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_ZERO;
+            },
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0); // ZEROはINXで落ちる
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     // BNE
     #[test]
     fn test_bne() {
-        let cpu = run(vec![0xD0, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BNE skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     #[test]
     fn test_bne_with_zero_flag() {
-        let cpu = run(vec![0xD0, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_ZERO;
-        });
+        let cpu = run(
+            "
+                BNE skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_ZERO;
+            },
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, FLAG_ZERO);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     // BIT
     #[test]
     fn test_bit() {
-        let cpu = run(vec![0x24, 0x00, 0x00], |cpu| {
+        let cpu = run("BIT $00", |cpu| {
             cpu.register_a = 0x00;
             cpu.mem_write(0x0000, 0x00);
         });
@@ -1660,7 +3478,7 @@ mod test {
 
     #[test]
     fn test_bit_negative_flag() {
-        let cpu = run(vec![0x24, 0x00, 0x00], |cpu| {
+        let cpu = run("BIT $00", |cpu| {
             cpu.register_a = 0x00;
             cpu.mem_write(0x0000, 0x80);
         });
@@ -1669,7 +3487,7 @@ mod test {
 
     #[test]
     fn test_bit_overflow_flag() {
-        let cpu = run(vec![0x24, 0x00, 0x00], |cpu| {
+        let cpu = run("BIT $00", |cpu| {
             cpu.register_a = 0x40;
             cpu.mem_write(0x0000, 0x40);
         });
@@ -1679,83 +3497,155 @@ mod test {
     // BMI
     #[test]
     fn test_bmi() {
-        let cpu = run(vec![0x30, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BMI skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     #[test]
     fn test_bmi_with_negative_flag() {
-        let cpu = run(vec![0x30, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_NEGATIVE;
-        });
+        let cpu = run(
+            "
+                BMI skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_NEGATIVE;
+            },
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0); //INXしてるからnegativeが落ちる
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     // BPL
     #[test]
     fn test_bpl() {
-        let cpu = run(vec![0x10, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BPL skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     #[test]
     fn test_bpl_with_negative_flag() {
-        let cpu = run(vec![0x10, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_NEGATIVE;
-        });
+        let cpu = run(
+            "
+                BPL skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_NEGATIVE;
+            },
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, FLAG_NEGATIVE);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     // BVC
     #[test]
     fn test_bvc() {
-        let cpu = run(vec![0x50, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BVC skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     #[test]
     fn test_bvc_with_overflow_flag() {
-        let cpu = run(vec![0x50, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_OVERFLOW;
-        });
+        let cpu = run(
+            "
+                BVC skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_OVERFLOW;
+            },
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, FLAG_OVERFLOW);
-        assert_eq!(cpu.program_counter, 0x8003)
+        assert_eq!(cpu.program_counter, 0x8002)
     }
 
     // BVS
     #[test]
     fn test_bvs() {
-        let cpu = run(vec![0x70, 0x02, 0x00, 0x00, 0xe8, 0x00], |_| {});
+        let cpu = run(
+            "
+                BVS skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |_| {},
+        );
         assert_eq!(cpu.register_x, 0x00);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.program_counter, 0x8002);
     }
 
     #[test]
     fn test_bvs_with_overflow_flag() {
-        let cpu = run(vec![0x70, 0x02, 0x00, 0x00, 0xe8, 0x00], |cpu| {
-            cpu.status = FLAG_OVERFLOW;
-        });
+        let cpu = run(
+            "
+                BVS skip
+                BRK
+                BRK
+            skip:
+                INX
+            ",
+            |cpu| {
+                cpu.status = FLAG_OVERFLOW;
+            },
+        );
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, FLAG_OVERFLOW);
-        assert_eq!(cpu.program_counter, 0x8006)
+        assert_eq!(cpu.program_counter, 0x8005)
     }
 
     // CLC
     #[test]
     fn test_clc() {
-        let cpu = run(vec![0x18, 0x00], |cpu| {
+        let cpu = run("CLC", |cpu| {
             cpu.status = FLAG_CARRY | FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_NEGATIVE);
@@ -1764,7 +3654,7 @@ mod test {
     // SEC
     #[test]
     fn test_sec() {
-        let cpu = run(vec![0x38, 0x00], |cpu| {
+        let cpu = run("SEC", |cpu| {
             cpu.status = FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_CARRY | FLAG_NEGATIVE);
@@ -1773,7 +3663,7 @@ mod test {
     // CLD
     #[test]
     fn test_cld() {
-        let cpu = run(vec![0xd8, 0x00], |cpu| {
+        let cpu = run("CLD", |cpu| {
             cpu.status = FLAG_DECIMAL | FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_NEGATIVE);
@@ -1782,7 +3672,7 @@ mod test {
     // SED
     #[test]
     fn test_sed() {
-        let cpu = run(vec![0xf8, 0x00], |cpu| {
+        let cpu = run("SED", |cpu| {
             cpu.status = FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_DECIMAL | FLAG_NEGATIVE);
@@ -1791,7 +3681,7 @@ mod test {
     // CLI
     #[test]
     fn test_cli() {
-        let cpu = run(vec![0x58, 0x00], |cpu| {
+        let cpu = run("CLI", |cpu| {
             cpu.status = FLAG_INTERRRUPT | FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_NEGATIVE);
@@ -1800,7 +3690,7 @@ mod test {
     // SEI
     #[test]
     fn test_sei() {
-        let cpu = run(vec![0x78, 0x00], |cpu| {
+        let cpu = run("SEI", |cpu| {
             cpu.status = FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_INTERRRUPT | FLAG_NEGATIVE);
@@ -1809,7 +3699,7 @@ mod test {
     // CLV
     #[test]
     fn test_clv() {
-        let cpu = run(vec![0xb8, 0x00], |cpu| {
+        let cpu = run("CLV", |cpu| {
             cpu.status = FLAG_OVERFLOW | FLAG_NEGATIVE;
         });
         assert_status(&cpu, FLAG_NEGATIVE);
@@ -1818,7 +3708,7 @@ mod test {
     // CMP
     #[test]
     fn test_cmp() {
-        let cpu = run(vec![0xC9, 0x01, 0x00], |cpu| {
+        let cpu = run("CMP #$01", |cpu| {
             cpu.register_a = 0x02;
         });
         assert_status(&cpu, FLAG_CARRY);
@@ -1826,7 +3716,7 @@ mod test {
 
     #[test]
     fn test_cmp_eq() {
-        let cpu = run(vec![0xC9, 0x02, 0x00], |cpu| {
+        let cpu = run("CMP #$02", |cpu| {
             cpu.register_a = 0x02;
         });
         assert_status(&cpu, FLAG_CARRY | FLAG_ZERO);
@@ -1834,7 +3724,7 @@ mod test {
 
     #[test]
     fn test_cmp_negative() {
-        let cpu = run(vec![0xC9, 0x03, 0x00], |cpu| {
+        let cpu = run("CMP #$03", |cpu| {
             cpu.register_a = 0x02;
         });
         assert_status(&cpu, FLAG_NEGATIVE);
@@ -1843,7 +3733,7 @@ mod test {
     // CPX
     #[test]
     fn test_cpx() {
-        let cpu = run(vec![0xe0, 0x01, 0x00], |cpu| {
+        let cpu = run("CPX #$01", |cpu| {
             cpu.register_x = 0x02;
         });
         assert_status(&cpu, FLAG_CARRY);
@@ -1852,7 +3742,7 @@ mod test {
     // CPY
     #[test]
     fn test_cpy() {
-        let cpu = run(vec![0xc0, 0x01, 0x00], |cpu| {
+        let cpu = run("CPY #$01", |cpu| {
             cpu.register_y = 0x02;
         });
         assert_status(&cpu, FLAG_CARRY);
@@ -1861,26 +3751,26 @@ mod test {
     // DEC
     #[test]
     fn test_dec() {
-        let cpu = run(vec![0xc6, 0x01, 0x00], |cpu| {
+        let cpu = run("DEC $01", |cpu| {
             cpu.mem_write(0x0001, 0x05);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x04);
+        assert_eq!(cpu.peek(0x0001), 0x04);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_dec_overflow() {
-        let cpu = run(vec![0xc6, 0x01, 0x00], |cpu| {
+        let cpu = run("DEC $01", |cpu| {
             cpu.mem_write(0x0001, 0x00);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0xFF);
+        assert_eq!(cpu.peek(0x0001), 0xFF);
         assert_status(&cpu, FLAG_NEGATIVE);
     }
 
     // DEX
     #[test]
     fn test_dex() {
-        let cpu = run(vec![0xca, 0x00], |cpu| {
+        let cpu = run("DEX", |cpu| {
             cpu.register_x = 0x05;
         });
         assert_eq!(cpu.register_x, 0x04);
@@ -1889,7 +3779,7 @@ mod test {
 
     #[test]
     fn test_dex_overflow() {
-        let cpu = run(vec![0xca, 0x00], |cpu| {
+        let cpu = run("DEX", |cpu| {
             cpu.register_x = 0x00;
         });
         assert_eq!(cpu.register_x, 0xFF);
@@ -1899,7 +3789,7 @@ mod test {
     // DEY
     #[test]
     fn test_dey() {
-        let cpu = run(vec![0x88, 0x00], |cpu| {
+        let cpu = run("DEY", |cpu| {
             cpu.register_y = 0x05;
         });
         assert_eq!(cpu.register_y, 0x04);
@@ -1908,7 +3798,7 @@ mod test {
 
     #[test]
     fn test_dey_overflow() {
-        let cpu = run(vec![0x88, 0x00], |cpu| {
+        let cpu = run("DEY", |cpu| {
             cpu.register_y = 0x00;
         });
         assert_eq!(cpu.register_y, 0xFF);
@@ -1918,26 +3808,26 @@ mod test {
     // INC
     #[test]
     fn test_inc() {
-        let cpu = run(vec![0xe6, 0x01, 0x00], |cpu| {
+        let cpu = run("INC $01", |cpu| {
             cpu.mem_write(0x0001, 0x05);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x06);
+        assert_eq!(cpu.peek(0x0001), 0x06);
         assert_status(&cpu, 0);
     }
 
     #[test]
     fn test_inc_overflow() {
-        let cpu = run(vec![0xe6, 0x01, 0x00], |cpu| {
+        let cpu = run("INC $01", |cpu| {
             cpu.mem_write(0x0001, 0xFF);
         });
-        assert_eq!(cpu.mem_read(0x0001), 0x00);
+        assert_eq!(cpu.peek(0x0001), 0x00);
         assert_status(&cpu, FLAG_ZERO);
     }
 
     // INX
     #[test]
     fn test_inx() {
-        let cpu = run(vec![0xe8, 0x00], |cpu| {
+        let cpu = run("INX", |cpu| {
             cpu.register_x = 0x05;
         });
         assert_eq!(cpu.register_x, 0x06);
@@ -1946,7 +3836,7 @@ mod test {
 
     #[test]
     fn test_inx_overflow() {
-        let cpu = run(vec![0xe8, 0x00], |cpu| {
+        let cpu = run("INX", |cpu| {
             cpu.register_x = 0xFF;
         });
         assert_eq!(cpu.register_x, 0x00);
@@ -1956,7 +3846,7 @@ mod test {
     // INY
     #[test]
     fn test_iny() {
-        let cpu = run(vec![0xc8, 0x00], |cpu| {
+        let cpu = run("INY", |cpu| {
             cpu.register_y = 0x05;
         });
         assert_eq!(cpu.register_y, 0x06);
@@ -1965,7 +3855,7 @@ mod test {
 
     #[test]
     fn test_iny_overflow() {
-        let cpu = run(vec![0xc8, 0x00], |cpu| {
+        let cpu = run("INY", |cpu| {
             cpu.register_y = 0xFF;
         });
         assert_eq!(cpu.register_y, 0x00);
@@ -1975,18 +3865,18 @@ mod test {
     // JMP
     #[test]
     fn test_jmp() {
-        let cpu = run(vec![0x4c, 0x30, 0x40, 0x00], |cpu| {
+        let cpu = run("JMP $4030", |cpu| {
             cpu.mem_write(0x4030, 0xe8);
             cpu.mem_write(0x4031, 0x00);
         });
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x4032);
+        assert_eq!(cpu.program_counter, 0x4031);
     }
 
     #[test]
     fn test_jmp_indirect() {
-        let cpu = run(vec![0x6c, 0x30, 0x40, 0x00], |cpu| {
+        let cpu = run("JMP ($4030)", |cpu| {
             cpu.mem_write(0x4030, 0x01);
             cpu.mem_write(0x4031, 0x02);
 
@@ -1995,32 +3885,34 @@ mod test {
         });
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x0203);
+        assert_eq!(cpu.program_counter, 0x0202);
     }
 
     // JSR
     #[test]
     fn test_jsr() {
-        let cpu = run(vec![0x20, 0x30, 0x40, 0x00], |cpu| {
+        let cpu = run("JSR $4030", |cpu| {
             cpu.mem_write(0x4030, 0xe8);
             cpu.mem_write(0x4031, 0x00);
         });
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x4032);
+        assert_eq!(cpu.program_counter, 0x4031);
         assert_eq!(cpu.stack_pointer, 0xFD);
-        assert_eq!(cpu.mem_read_u16(0x01FE), 0x8003);
+        // JSR は戻り先 - 1 (JSR の最後のバイト) を積む
+        assert_eq!(cpu.peek_u16(0x01FE), 0x8002);
     }
 
     // RTS
     #[test]
     fn test_rts() {
-        let cpu = run(vec![0x60, 0x00], |cpu| {
+        let cpu = run("RTS", |cpu| {
             cpu.mem_write(0x01FF, 0x05);
             cpu.mem_write(0x01FE, 0x06);
 
-            cpu.mem_write(0x0506, 0xe8);
-            cpu.mem_write(0x0507, 0x00);
+            // RTS は積まれたアドレス + 1 に戻る
+            cpu.mem_write(0x0507, 0xe8);
+            cpu.mem_write(0x0508, 0x00);
 
             cpu.stack_pointer = 0xFD;
         });
@@ -2029,29 +3921,29 @@ mod test {
         assert_eq!(cpu.program_counter, 0x0508);
         assert_eq!(cpu.stack_pointer, 0xFF);
         // 書き潰されない前提
-        assert_eq!(cpu.mem_read_u16(0x01FE), 0x0506);
+        assert_eq!(cpu.peek_u16(0x01FE), 0x0506);
     }
 
     // JSR & RTS
     #[test]
     fn test_jsr_and_rts() {
-        let cpu = run(vec![0x20, 0x30, 0x40, 0x00], |cpu| {
+        let cpu = run("JSR $4030", |cpu| {
             cpu.mem_write(0x4030, 0xe8);
             cpu.mem_write(0x4031, 0x60); // RTS
             cpu.mem_write(0x4032, 0x00);
         });
         assert_eq!(cpu.register_x, 0x01);
         assert_status(&cpu, 0);
-        assert_eq!(cpu.program_counter, 0x8004);
+        assert_eq!(cpu.program_counter, 0x8003);
         assert_eq!(cpu.stack_pointer, 0xFF);
         // 書き潰されない前提
-        assert_eq!(cpu.mem_read_u16(0x01FE), 0x8003);
+        assert_eq!(cpu.peek_u16(0x01FE), 0x8002);
     }
 
     // LDX
     #[test]
     fn test_ldx() {
-        let cpu = run(vec![0xa2, 0x05, 0x00], |_| {});
+        let cpu = run("LDX #$05", |_| {});
         assert_eq!(cpu.register_x, 0x05);
         assert_status(&cpu, 0);
     }
@@ -2059,7 +3951,7 @@ mod test {
     // LDY
     #[test]
     fn test_ldy() {
-        let cpu = run(vec![0xa0, 0x05, 0x00], |_| {});
+        let cpu = run("LDY #$05", |_| {});
         assert_eq!(cpu.register_y, 0x05);
         assert_status(&cpu, 0);
     }
@@ -2067,27 +3959,27 @@ mod test {
     // NOP
     #[test]
     fn test_nop() {
-        let cpu = run(vec![0xea, 0x00], |_| {});
-        assert_eq!(cpu.program_counter, 0x8002);
+        let cpu = run("NOP", |_| {});
+        assert_eq!(cpu.program_counter, 0x8001);
         assert_status(&cpu, 0);
     }
 
     // PHA
     #[test]
     fn test_pha() {
-        let cpu = run(vec![0x48, 0x00], |cpu| {
+        let cpu = run("PHA", |cpu| {
             cpu.register_a = 0x07;
         });
         assert_status(&cpu, 0);
         assert_eq!(cpu.register_a, 0x07);
         assert_eq!(cpu.stack_pointer, 0xFE);
-        assert_eq!(cpu.mem_read(0x01FF), 0x07);
+        assert_eq!(cpu.peek(0x01FF), 0x07);
     }
 
     // PLA
     #[test]
     fn test_pla() {
-        let cpu = run(vec![0x68, 0x00], |cpu| {
+        let cpu = run("PLA", |cpu| {
             cpu.mem_write(0x01FF, 0x07);
             cpu.stack_pointer = 0xFE;
         });
@@ -2098,7 +3990,7 @@ mod test {
 
     #[test]
     fn test_pla_zero() {
-        let cpu = run(vec![0x68, 0x00], |cpu| {
+        let cpu = run("PLA", |cpu| {
             cpu.mem_write(0x01FF, 0x00);
             cpu.stack_pointer = 0xFE;
         });
@@ -2110,47 +4002,52 @@ mod test {
     // PHA & PLA
     #[test]
     fn test_pla_and_pla() {
-        let cpu = run(vec![0x48, 0xa9, 0x60, 0x68, 0x00], |cpu| {
+        let cpu = run("PHA\nLDA #$60\nPLA", |cpu| {
             cpu.register_a = 0x80;
         });
         assert_eq!(cpu.register_a, 0x80);
         assert_status(&cpu, FLAG_NEGATIVE);
         assert_eq!(cpu.stack_pointer, 0xFF);
-        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(cpu.program_counter, 0x8004);
     }
 
     // PHP
     #[test]
     fn test_php() {
-        let cpu = run(vec![0x08, 0x00], |cpu| {
+        let cpu = run("PHP", |cpu| {
             cpu.status = FLAG_NEGATIVE | FLAG_OVERFLOW;
         });
         assert_status(&cpu, FLAG_NEGATIVE | FLAG_OVERFLOW);
         assert_eq!(cpu.stack_pointer, 0xFE);
-        assert_eq!(cpu.mem_read(0x01FF), FLAG_NEGATIVE | FLAG_OVERFLOW);
+        // PHP は B とビット 5 を立てて積む
+        assert_eq!(
+            cpu.peek(0x01FF),
+            FLAG_NEGATIVE | FLAG_OVERFLOW | FLAG_BREAK | FLAG_BREAK2
+        );
     }
 
     // PLP
     #[test]
     fn test_plp() {
-        let cpu = run(vec![0x28, 0x00], |cpu| {
+        let cpu = run("PLP", |cpu| {
             cpu.mem_write(0x01FF, FLAG_CARRY | FLAG_ZERO);
             cpu.stack_pointer = 0xFE;
         });
-        assert_status(&cpu, FLAG_CARRY | FLAG_ZERO);
+        // PLP は B を無視してビット 5 を立てる
+        assert_status(&cpu, FLAG_CARRY | FLAG_ZERO | FLAG_BREAK2);
         assert_eq!(cpu.stack_pointer, 0xFF);
     }
 
     // PHP & PLP
     #[test]
     fn test_plp_and_plp() {
-        let cpu = run(vec![0x08, 0xa9, 0xF0, 0x28, 0x00], |cpu| {
+        let cpu = run("PHP\nLDA #$F0\nPLP", |cpu| {
             cpu.status = FLAG_OVERFLOW | FLAG_CARRY;
         });
         assert_eq!(cpu.register_a, 0xF0);
-        assert_status(&cpu, FLAG_OVERFLOW | FLAG_CARRY);
+        assert_status(&cpu, FLAG_OVERFLOW | FLAG_CARRY | FLAG_BREAK2);
         assert_eq!(cpu.stack_pointer, 0xFF);
-        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(cpu.program_counter, 0x8004);
     }
 
     // FIXME RTIのテストは一旦保留
@@ -2159,25 +4056,25 @@ mod test {
     // STX
     #[test]
     fn test_stx() {
-        let cpu = run(vec![0x86, 0x10, 0x00], |cpu| {
+        let cpu = run("STX $10", |cpu| {
             cpu.register_x = 0xBA;
         });
-        assert_eq!(cpu.mem_read(0x10), 0xBA);
+        assert_eq!(cpu.peek(0x10), 0xBA);
     }
 
     // STY
     #[test]
     fn test_sty() {
-        let cpu = run(vec![0x84, 0x10, 0x00], |cpu| {
+        let cpu = run("STY $10", |cpu| {
             cpu.register_y = 0xBA;
         });
-        assert_eq!(cpu.mem_read(0x10), 0xBA);
+        assert_eq!(cpu.peek(0x10), 0xBA);
     }
 
     // TAX
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
-        let cpu = run(vec![0xaa, 0x00], |cpu| {
+        let cpu = run("TAX", |cpu| {
             cpu.register_a = 10;
         });
         assert_eq!(cpu.register_x, 10);
@@ -2186,7 +4083,7 @@ mod test {
     // TXA
     #[test]
     fn test_txa() {
-        let cpu = run(vec![0x8a, 0x00], |cpu| {
+        let cpu = run("TXA", |cpu| {
             cpu.register_x = 0x10;
         });
         assert_eq!(cpu.register_a, 0x10);
@@ -2195,7 +4092,7 @@ mod test {
     // TAY
     #[test]
     fn test_tay() {
-        let cpu = run(vec![0xa8, 0x00], |cpu| {
+        let cpu = run("TAY", |cpu| {
             cpu.register_a = 0x10;
         });
         assert_eq!(cpu.register_y, 0x10);
@@ -2204,7 +4101,7 @@ mod test {
     // TYA
     #[test]
     fn test_tya() {
-        let cpu = run(vec![0x98, 0x00], |cpu| {
+        let cpu = run("TYA", |cpu| {
             cpu.register_y = 0x10;
         });
         assert_eq!(cpu.register_a, 0x10);
@@ -2213,14 +4110,14 @@ mod test {
     // TSX
     #[test]
     fn test_tsx() {
-        let cpu = run(vec![0xba, 0x00], |_| {});
+        let cpu = run("TSX", |_| {});
         assert_eq!(cpu.register_x, 0xFF);
         assert_status(&cpu, FLAG_NEGATIVE);
     }
 
     #[test]
     fn test_tsx_some_value() {
-        let cpu = run(vec![0xba, 0x00], |cpu| {
+        let cpu = run("TSX", |cpu| {
             cpu.stack_pointer = 0x75;
         });
         assert_eq!(cpu.register_x, 0x75);
@@ -2230,11 +4127,113 @@ mod test {
     // TXS
     #[test]
     fn test_txs() {
-        let cpu = run(vec![0x9a, 0x00], |cpu| {
+        let cpu = run("TXS", |cpu| {
             cpu.register_x = 0x80;
         });
         assert_eq!(cpu.stack_pointer, 0x80);
         assert_status(&cpu, 0);
     }
-    */
+
+    // 非公式命令 (即値と AND してから演算するもの)
+    #[test]
+    fn test_anc() {
+        let cpu = run("ANC #$80", |cpu| {
+            cpu.register_a = 0xFF;
+        });
+        assert_eq!(cpu.register_a, 0x80);
+        // C は結果の bit 7
+        assert_status(&cpu, FLAG_NEGATIVE | FLAG_CARRY);
+    }
+
+    #[test]
+    fn test_arr() {
+        let cpu = run("ARR #$FF", |cpu| {
+            cpu.register_a = 0xC0;
+            cpu.status = FLAG_CARRY;
+        });
+        assert_eq!(cpu.register_a, 0xE0);
+        // C は bit 6、V は bit 6 と bit 5 の XOR
+        assert_status(&cpu, FLAG_NEGATIVE | FLAG_CARRY);
+    }
+
+    #[test]
+    fn test_asr() {
+        let cpu = run("ASR #$03", |cpu| {
+            cpu.register_a = 0xFF;
+        });
+        assert_eq!(cpu.register_a, 0x01);
+        assert_status(&cpu, FLAG_CARRY);
+    }
+
+    #[test]
+    fn test_lxa() {
+        let cpu = run("LXA #$0F", |_| {});
+        // マジック定数 $EE
+        assert_eq!(cpu.register_a, 0x0E);
+        assert_eq!(cpu.register_x, 0x0E);
+        assert_status(&cpu, 0);
+    }
+
+    #[test]
+    fn test_sbx() {
+        let cpu = run("SBX #$02", |cpu| {
+            cpu.register_a = 0x0F;
+            cpu.register_x = 0x03;
+        });
+        assert_eq!(cpu.register_x, 0x01);
+        assert_eq!(cpu.register_a, 0x0F);
+        assert_status(&cpu, FLAG_CARRY);
+    }
+
+    #[test]
+    fn test_las() {
+        let cpu = run("LAE $0300,Y", |cpu| {
+            cpu.mem_write(0x0300, 0xF0);
+            cpu.stack_pointer = 0xF8;
+        });
+        assert_eq!(cpu.register_a, 0xF0);
+        assert_eq!(cpu.register_x, 0xF0);
+        assert_eq!(cpu.stack_pointer, 0xF0);
+        assert_status(&cpu, FLAG_NEGATIVE);
+    }
+
+    #[test]
+    fn test_ane() {
+        let cpu = run("ANE #$FF", |cpu| {
+            cpu.register_x = 0x0F;
+        });
+        assert_eq!(cpu.register_a, 0x0E);
+        assert_status(&cpu, 0);
+    }
+
+    // SHA/SHX/SHY は上位バイト+1 と AND した値を書く
+    #[test]
+    fn test_sha() {
+        let cpu = run("SHA $0200,Y", |cpu| {
+            cpu.register_a = 0xFF;
+            cpu.register_x = 0xFF;
+            cpu.register_y = 0x10;
+        });
+        assert_eq!(cpu.peek(0x0210), 0x03);
+    }
+
+    #[test]
+    fn test_shx_page_cross() {
+        let cpu = run("SHX $02F0,Y", |cpu| {
+            cpu.register_x = 0xF1;
+            cpu.register_y = 0x20;
+        });
+        // ページをまたぐと上位バイトが書く値に化ける
+        assert_eq!(cpu.peek(0x0110), 0x01);
+        assert_eq!(cpu.peek(0x0310), 0x00);
+    }
+
+    #[test]
+    fn test_shy() {
+        let cpu = run("SHY $0200,X", |cpu| {
+            cpu.register_y = 0xFF;
+            cpu.register_x = 0x05;
+        });
+        assert_eq!(cpu.peek(0x0205), 0x03);
+    }
 }
@@ -0,0 +1,886 @@
+// サイクル単位で動く CPU コア
+//
+// CPU::step は 1 命令をまとめて実行するが、こちらは tick() 1 回でバスアクセス 1 回 (1 サイクル) だけ進める。
+// 命令はデコード時にマイクロ命令の列に分解して、1 サイクルに 1 つずつ実行する。
+// レジスタやバスは CPU をそのまま使うので、命令の境界ではどちらのコアに切り替えてもよい。
+//
+// 割り込みは各サイクルの終わりにポーリングし、命令の最後のサイクルの 1 つ前の結果で判断する。
+// 分岐が成立してページをまたがないときは 2 サイクル目でポーリングしない (IRQ が 1 命令遅れる)。
+
+use crate::bus::{Bus, Mem};
+use crate::cpu::{
+    AddressingMode, CpuFault, FaultKind, FaultPolicy, Interrupt, OpCode, Registers, StepResult,
+    Variant, CPU,
+};
+use crate::disasm::find_op;
+use crate::opscodes::call;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Micro {
+    // 次のバイトを読んで捨てる (PC は進めない)
+    DummyPc,
+    // 即値を読んで演算する
+    Immediate,
+    // アドレスの下位バイト (ゼロページならそのまま) / 上位バイト
+    AddrLo,
+    AddrHi,
+    // 上位バイトを読んでインデックスを足す (バスアクセスは上位バイトの読み込みだけ)
+    AddrHiX,
+    AddrHiY,
+    // ゼロページのインデックス: 足す前のアドレスを空読み
+    ZeroPageX,
+    ZeroPageY,
+    // ($nn,X): ポインタを空読みして X を足す
+    PointerX,
+    // ポインタから下位バイト / 上位バイト ($nn),Y は上位バイトと一緒に Y を足す
+    PointerLo,
+    PointerHi,
+    PointerHiY,
+    // 上位バイトを直す前のアドレスを読む。読み込み命令でページをまたがなければこれで終わり
+    Fixup,
+    // 書き込み命令/RMW は必ず空読みする
+    FixupDummy,
+    Read,
+    Write,
+    ModifyRead,
+    ModifyDummyWrite,
+    ModifyWrite,
+    // オペランドのない命令 (空読み + 演算)
+    Implied,
+    Push,
+    StackDummy,
+    Pull,
+    PushPch,
+    PushPcl,
+    PushStatus,
+    PullStatus,
+    PullPcl,
+    PullPch,
+    JsrHi,
+    RtsInc,
+    JmpHi,
+    IndirectLo,
+    IndirectHi,
+    BrkPad,
+    VectorLo,
+    VectorHi,
+    BranchOperand,
+    BranchTaken,
+    BranchFix,
+}
+
+use Micro::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Read,
+    Write,
+    Modify,
+    Implied,
+    Push,
+    Pull,
+    Jsr,
+    Rts,
+    Rti,
+    Brk,
+    Jmp,
+    Branch,
+    Jam,
+}
+
+fn kind(name: &str) -> Kind {
+    match name {
+        "STA" | "STX" | "STY" | "SAX" | "SHA" | "SHX" | "SHY" => Kind::Write,
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" | "SLO" | "SRE" | "RLA" | "RRA" | "DCP"
+        | "ISB" => Kind::Modify,
+        "PHA" | "PHP" => Kind::Push,
+        "PLA" | "PLP" => Kind::Pull,
+        "JSR" => Kind::Jsr,
+        "RTS" => Kind::Rts,
+        "RTI" => Kind::Rti,
+        "BRK" => Kind::Brk,
+        "JMP" => Kind::Jmp,
+        "BPL" | "BMI" | "BVC" | "BVS" | "BCC" | "BCS" | "BNE" | "BEQ" => Kind::Branch,
+        "JAM" => Kind::Jam,
+        "TAX" | "TAY" | "TXA" | "TYA" | "TSX" | "TXS" | "INX" | "INY" | "DEX" | "DEY" | "CLC"
+        | "SEC" | "CLI" | "SEI" | "CLV" | "CLD" | "SED" => Kind::Implied,
+        _ => Kind::Read,
+    }
+}
+
+// オペコードの後のサイクル
+fn micro_ops(op: &OpCode) -> &'static [Micro] {
+    let name = op.name.trim_start_matches('*');
+    let mode = &op.addressing_mode;
+    let kind = kind(name);
+    match kind {
+        Kind::Push => return &[DummyPc, Push],
+        Kind::Pull => return &[DummyPc, StackDummy, Pull],
+        Kind::Jsr => return &[AddrLo, StackDummy, PushPch, PushPcl, JsrHi],
+        Kind::Rts => return &[DummyPc, StackDummy, PullPcl, PullPch, RtsInc],
+        Kind::Rti => return &[DummyPc, StackDummy, PullStatus, PullPcl, PullPch],
+        Kind::Brk => return &[BrkPad, PushPch, PushPcl, PushStatus, VectorLo, VectorHi],
+        Kind::Branch => return &[BranchOperand, BranchTaken, BranchFix],
+        Kind::Jam => return &[],
+        Kind::Jmp if mode == &AddressingMode::Indirect => {
+            return &[AddrLo, AddrHi, IndirectLo, IndirectHi]
+        }
+        Kind::Jmp => return &[AddrLo, JmpHi],
+        _ => {}
+    }
+    match (mode, kind) {
+        (AddressingMode::Implied, _) | (AddressingMode::Accumulator, _) => &[Implied],
+        (AddressingMode::Immediate, _) => &[Immediate],
+
+        (AddressingMode::ZeroPage, Kind::Write) => &[AddrLo, Write],
+        (AddressingMode::ZeroPage, Kind::Modify) => {
+            &[AddrLo, ModifyRead, ModifyDummyWrite, ModifyWrite]
+        }
+        (AddressingMode::ZeroPage, _) => &[AddrLo, Read],
+
+        (AddressingMode::ZeroPage_X, Kind::Write) => &[AddrLo, ZeroPageX, Write],
+        (AddressingMode::ZeroPage_X, Kind::Modify) => {
+            &[AddrLo, ZeroPageX, ModifyRead, ModifyDummyWrite, ModifyWrite]
+        }
+        (AddressingMode::ZeroPage_X, _) => &[AddrLo, ZeroPageX, Read],
+        (AddressingMode::ZeroPage_Y, Kind::Write) => &[AddrLo, ZeroPageY, Write],
+        (AddressingMode::ZeroPage_Y, _) => &[AddrLo, ZeroPageY, Read],
+
+        (AddressingMode::Absolute, Kind::Write) => &[AddrLo, AddrHi, Write],
+        (AddressingMode::Absolute, Kind::Modify) => {
+            &[AddrLo, AddrHi, ModifyRead, ModifyDummyWrite, ModifyWrite]
+        }
+        (AddressingMode::Absolute, _) => &[AddrLo, AddrHi, Read],
+
+        (AddressingMode::Absolute_X, Kind::Write) => &[AddrLo, AddrHiX, FixupDummy, Write],
+        (AddressingMode::Absolute_X, Kind::Modify) => &[
+            AddrLo,
+            AddrHiX,
+            FixupDummy,
+            ModifyRead,
+            ModifyDummyWrite,
+            ModifyWrite,
+        ],
+        (AddressingMode::Absolute_X, _) => &[AddrLo, AddrHiX, Fixup, Read],
+        (AddressingMode::Absolute_Y, Kind::Write) => &[AddrLo, AddrHiY, FixupDummy, Write],
+        (AddressingMode::Absolute_Y, Kind::Modify) => &[
+            AddrLo,
+            AddrHiY,
+            FixupDummy,
+            ModifyRead,
+            ModifyDummyWrite,
+            ModifyWrite,
+        ],
+        (AddressingMode::Absolute_Y, _) => &[AddrLo, AddrHiY, Fixup, Read],
+
+        (AddressingMode::Indirect_X, Kind::Write) => {
+            &[AddrLo, PointerX, PointerLo, PointerHi, Write]
+        }
+        (AddressingMode::Indirect_X, Kind::Modify) => &[
+            AddrLo,
+            PointerX,
+            PointerLo,
+            PointerHi,
+            ModifyRead,
+            ModifyDummyWrite,
+            ModifyWrite,
+        ],
+        (AddressingMode::Indirect_X, _) => &[AddrLo, PointerX, PointerLo, PointerHi, Read],
+
+        (AddressingMode::Indirect_Y, Kind::Write) => {
+            &[AddrLo, PointerLo, PointerHiY, FixupDummy, Write]
+        }
+        (AddressingMode::Indirect_Y, Kind::Modify) => &[
+            AddrLo,
+            PointerLo,
+            PointerHiY,
+            FixupDummy,
+            ModifyRead,
+            ModifyDummyWrite,
+            ModifyWrite,
+        ],
+        (AddressingMode::Indirect_Y, _) => &[AddrLo, PointerLo, PointerHiY, Fixup, Read],
+
+        // テーブルにない組み合わせ
+        _ => &[Implied],
+    }
+}
+
+// 割り込みシーケンスの 2 サイクル目以降 (1 サイクル目はオペコードの代わりの空読み)
+const INTERRUPT_OPS: [Micro; 6] = [DummyPc, PushPch, PushPcl, PushStatus, VectorLo, VectorHi];
+
+pub struct CycleCpu<B: Mem = Bus> {
+    pub cpu: CPU<B>,
+    // 実行中の命令 (割り込みシーケンス中は None)
+    op: Option<&'static OpCode>,
+    interrupt: Option<Interrupt>,
+    // 報告用のオペコード (未知の命令を NOP として実行したときは元のコード)
+    opcode: u8,
+    micro: &'static [Micro],
+    // 次に実行するマイクロ命令
+    index: usize,
+    // 命令の境界のときに 0
+    instruction_cycles: u16,
+    // 命令の先頭の PC とレジスタ (命令の途中でフォルトしたら戻す)
+    pc: u16,
+    registers: Registers,
+    // 直前のサイクルの終わりにポーリングした結果
+    pending: Option<Interrupt>,
+    skip_poll: bool,
+    addr: u16,
+    base: u16,
+    pointer: u8,
+    value: u8,
+    vector: u16,
+}
+
+impl<B: Mem> CycleCpu<B> {
+    pub fn new(cpu: CPU<B>) -> Self {
+        CycleCpu {
+            cpu,
+            op: None,
+            interrupt: None,
+            opcode: 0,
+            micro: &[],
+            index: 0,
+            instruction_cycles: 0,
+            pc: 0,
+            registers: (0, 0, 0, 0, 0, 0),
+            pending: None,
+            skip_poll: false,
+            addr: 0,
+            base: 0,
+            pointer: 0,
+            value: 0,
+            vector: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> CPU<B> {
+        self.cpu
+    }
+
+    // 命令の途中でなければ true
+    pub fn at_boundary(&self) -> bool {
+        self.instruction_cycles == 0
+    }
+
+    // 1 サイクル進める。命令 (か割り込みシーケンス) がこのサイクルで終わったら結果を返す
+    pub fn tick(&mut self) -> Option<StepResult> {
+        if self.at_boundary() {
+            // マイクロ命令の表は NMOS (2A03) の分しかないので、65C02 は命令単位で動かす
+            if self.cpu.variant == Variant::Cmos65C02 {
+                return Some(self.cpu.step());
+            }
+            return self.start();
+        }
+        let micro = self.micro[self.index];
+        self.index += 1;
+        let done = self.execute(micro) || self.index == self.micro.len();
+        self.cpu.cycles += 1;
+        self.instruction_cycles += 1;
+        if !done {
+            self.poll();
+            return None;
+        }
+
+        let cycles = self.instruction_cycles;
+        self.instruction_cycles = 0;
+        // 命令の途中のフォルトは CPU::step と同じくレジスタを戻してポリシーに従う
+        let fault = match self.op {
+            Some(op) => self.cpu.take_fault(self.pc, op, self.registers),
+            None => None,
+        };
+        let result = match (fault, self.interrupt.take()) {
+            (Some(result), _) => result,
+            (None, Some(kind)) => StepResult::Interrupt { kind, cycles },
+            (None, None) => StepResult::Executed {
+                opcode: self.opcode,
+                cycles,
+            },
+        };
+        self.cpu.after_instruction(&result);
+        Some(result)
+    }
+
+    // 1 命令分 tick する
+    pub fn step(&mut self) -> StepResult {
+        loop {
+            if let Some(result) = self.tick() {
+                return result;
+            }
+        }
+    }
+
+    pub fn run_until<F>(&mut self, mut predicate: F) -> StepResult
+    where
+        F: FnMut(&CPU<B>) -> bool,
+    {
+        loop {
+            let result = self.step();
+            match result {
+                StepResult::Executed { .. } | StepResult::Interrupt { .. }
+                    if !predicate(&self.cpu) => {}
+                _ => return result,
+            }
+        }
+    }
+
+    pub fn run_cycles(&mut self, n: u64) -> StepResult {
+        let target = self.cpu.cycles + n;
+        self.run_until(|cpu| cpu.cycles >= target)
+    }
+
+    pub fn run(&mut self) {
+        self.run_with_callback(|_| {});
+    }
+
+    // CPU::run_with_callback と同じく命令の境界で呼ぶ
+    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut CPU<B>),
+    {
+        loop {
+            callback(&mut self.cpu);
+            match self.step() {
+                StepResult::Executed { .. } | StepResult::Interrupt { .. } => {}
+                _ => return,
+            }
+        }
+    }
+
+    // 命令の境界: 割り込みに入るか、オペコードを読んでデコードする
+    fn start(&mut self) -> Option<StepResult> {
+        if let Some(result) = self.cpu.boundary_stop() {
+            return Some(result);
+        }
+        self.cpu.before_instruction();
+        let result = self.decode();
+        if let Some(result) = &result {
+            self.cpu.after_instruction(result);
+        }
+        result
+    }
+
+    fn decode(&mut self) -> Option<StepResult> {
+        let pc = self.cpu.program_counter;
+
+        if let Some(kind) = self.pending.take() {
+            self.cpu.mem_read(pc);
+            self.op = None;
+            self.interrupt = Some(kind);
+            self.micro = &INTERRUPT_OPS;
+        } else {
+            let opcode = self.cpu.mem_read(pc);
+            let op = match find_op(self.cpu.variant, opcode) {
+                Some(op) => op,
+                None => {
+                    let fault = CpuFault {
+                        pc,
+                        opcode,
+                        mode: AddressingMode::NoneAddressing,
+                        kind: FaultKind::UnknownOpcode,
+                    };
+                    match self.cpu.fault_policy {
+                        FaultPolicy::Halt => return Some(StepResult::Fault(fault)),
+                        FaultPolicy::LogAndContinue => eprintln!("{}", fault),
+                        FaultPolicy::TreatAsNop => {}
+                    }
+                    // 1 バイトの NOP として実行する
+                    find_op(self.cpu.variant, 0xEA).unwrap()
+                }
+            };
+            if kind(op.name.trim_start_matches('*')) == Kind::Jam {
+                self.cpu.halted = true;
+                return Some(StepResult::Halted { pc });
+            }
+            self.pc = pc;
+            self.registers = self.cpu.save_registers();
+            self.cpu.program_counter = pc.wrapping_add(1);
+            self.op = Some(op);
+            self.opcode = opcode;
+            self.micro = micro_ops(op);
+        }
+
+        self.index = 0;
+        self.cpu.cycles += 1;
+        self.instruction_cycles = 1;
+        self.poll();
+        None
+    }
+
+    fn poll(&mut self) {
+        if self.skip_poll {
+            self.skip_poll = false;
+            return;
+        }
+        self.cpu.latch_nmi();
+        self.pending = self.cpu.poll_interrupt();
+    }
+
+    fn name(&self) -> &'static str {
+        match self.op {
+            Some(op) => op.name.trim_start_matches('*'),
+            None => "",
+        }
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let value = self.cpu.mem_read(self.cpu.program_counter);
+        self.cpu.program_counter = self.cpu.program_counter.wrapping_add(1);
+        value
+    }
+
+    fn push(&mut self, value: u8) {
+        self.cpu
+            .mem_write(0x0100 + self.cpu.stack_pointer as u16, value);
+        self.cpu.stack_pointer = self.cpu.stack_pointer.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.cpu.stack_pointer = self.cpu.stack_pointer.wrapping_add(1);
+        self.cpu.mem_read(0x0100 + self.cpu.stack_pointer as u16)
+    }
+
+    fn add_index(&mut self, index: u8) {
+        self.base = self.addr;
+        self.addr = self.base.wrapping_add(index as u16);
+    }
+
+    fn crossed(&self) -> bool {
+        self.base & 0xFF00 != self.addr & 0xFF00
+    }
+
+    // マイクロ命令を 1 つ実行する (バスアクセスはちょうど 1 回)。
+    // 途中で命令が終わったら true
+    fn execute(&mut self, micro: Micro) -> bool {
+        match micro {
+            DummyPc => {
+                self.cpu.mem_read(self.cpu.program_counter);
+            }
+            Immediate => {
+                let value = self.fetch();
+                self.cpu.read_op(self.name(), value);
+            }
+            AddrLo => {
+                self.addr = self.fetch() as u16;
+                self.pointer = self.addr as u8;
+            }
+            AddrHi => {
+                self.addr |= (self.fetch() as u16) << 8;
+            }
+            AddrHiX => {
+                self.addr |= (self.fetch() as u16) << 8;
+                self.add_index(self.cpu.register_x);
+            }
+            AddrHiY => {
+                self.addr |= (self.fetch() as u16) << 8;
+                self.add_index(self.cpu.register_y);
+            }
+            ZeroPageX => {
+                self.cpu.mem_read(self.addr);
+                self.addr = (self.addr as u8).wrapping_add(self.cpu.register_x) as u16;
+            }
+            ZeroPageY => {
+                self.cpu.mem_read(self.addr);
+                self.addr = (self.addr as u8).wrapping_add(self.cpu.register_y) as u16;
+            }
+            PointerX => {
+                self.cpu.mem_read(self.pointer as u16);
+                self.pointer = self.pointer.wrapping_add(self.cpu.register_x);
+            }
+            PointerLo => {
+                self.addr = self.cpu.mem_read(self.pointer as u16) as u16;
+            }
+            PointerHi => {
+                let hi = self.cpu.mem_read(self.pointer.wrapping_add(1) as u16) as u16;
+                self.addr |= hi << 8;
+            }
+            PointerHiY => {
+                let hi = self.cpu.mem_read(self.pointer.wrapping_add(1) as u16) as u16;
+                self.addr |= hi << 8;
+                self.add_index(self.cpu.register_y);
+            }
+            Fixup | FixupDummy => {
+                let value = self
+                    .cpu
+                    .mem_read((self.base & 0xFF00) | (self.addr & 0x00FF));
+                if micro == Fixup && !self.crossed() {
+                    self.cpu.read_op(self.name(), value);
+                    return true;
+                }
+            }
+            Read => {
+                let value = self.cpu.mem_read(self.addr);
+                self.cpu.read_op(self.name(), value);
+            }
+            Write => {
+                let name = self.name();
+                let mut value = self.cpu.write_value(name);
+                let mut addr = self.addr;
+                if name == "SHA" || name == "SHX" || name == "SHY" {
+                    // 上位バイト+1 との AND。ページをまたいだら上位バイトが化ける
+                    value &= ((self.base >> 8) as u8).wrapping_add(1);
+                    if self.crossed() {
+                        addr = ((value as u16) << 8) | (addr & 0x00FF);
+                    }
+                }
+                self.cpu.mem_write(addr, value);
+            }
+            ModifyRead => {
+                self.value = self.cpu.mem_read(self.addr);
+            }
+            ModifyDummyWrite => {
+                self.cpu.mem_write(self.addr, self.value);
+            }
+            ModifyWrite => {
+                let value = self.cpu.modify_op(self.name(), self.value);
+                self.cpu.mem_write(self.addr, value);
+            }
+            Implied => {
+                self.cpu.mem_read(self.cpu.program_counter);
+                call(&mut self.cpu, self.op.unwrap());
+            }
+            Push => {
+                let value = self.cpu.push_value(self.name());
+                self.push(value);
+            }
+            StackDummy => {
+                self.cpu.mem_read(0x0100 + self.cpu.stack_pointer as u16);
+            }
+            Pull => {
+                let value = self.pull();
+                self.cpu.pull_op(self.name(), value);
+            }
+            PushPch => {
+                self.push((self.cpu.program_counter >> 8) as u8);
+            }
+            PushPcl => {
+                self.push(self.cpu.program_counter as u8);
+            }
+            PushStatus => {
+                let status = self.cpu.interrupt_status(self.op.is_some());
+                self.push(status);
+                // ここまでに NMI が来ていれば BRK/IRQ でも NMI のベクタを読む
+                self.vector = self.cpu.interrupt_vector();
+                if self.interrupt == Some(Interrupt::Irq) && self.vector == 0xFFFA {
+                    self.interrupt = Some(Interrupt::Nmi);
+                }
+            }
+            PullStatus => {
+                let value = self.pull();
+                self.cpu.pull_op("PLP", value);
+            }
+            PullPcl => {
+                self.addr = self.pull() as u16;
+            }
+            PullPch => {
+                self.addr |= (self.pull() as u16) << 8;
+                self.cpu.program_counter = self.addr;
+            }
+            JsrHi => {
+                let hi = self.cpu.mem_read(self.cpu.program_counter) as u16;
+                self.cpu.program_counter = hi << 8 | self.addr;
+            }
+            RtsInc => {
+                self.fetch();
+            }
+            JmpHi => {
+                let hi = self.cpu.mem_read(self.cpu.program_counter) as u16;
+                self.cpu.program_counter = hi << 8 | self.addr;
+            }
+            IndirectLo => {
+                self.value = self.cpu.mem_read(self.addr);
+            }
+            IndirectHi => {
+                // ページをまたがずに同じページの先頭から上位バイトを読む
+                let addr = (self.addr & 0xFF00) | (self.addr.wrapping_add(1) & 0x00FF);
+                let hi = self.cpu.mem_read(addr) as u16;
+                self.cpu.program_counter = hi << 8 | self.value as u16;
+            }
+            BrkPad => {
+                self.fetch();
+            }
+            VectorLo => {
+                self.addr = self.cpu.mem_read(self.vector) as u16;
+            }
+            VectorHi => {
+                let hi = self.cpu.mem_read(self.vector.wrapping_add(1)) as u16;
+                self.cpu.program_counter = hi << 8 | self.addr;
+            }
+            BranchOperand => {
+                let offset = self.fetch() as i8;
+                if !self.cpu.branch_taken(self.name()) {
+                    return true;
+                }
+                self.base = self.cpu.program_counter;
+                self.addr = self.base.wrapping_add(offset as u16);
+                // 成立した分岐はこのサイクルでは割り込みを見ない
+                self.skip_poll = true;
+            }
+            BranchTaken => {
+                self.cpu.mem_read(self.base);
+                if !self.crossed() {
+                    self.cpu.program_counter = self.addr;
+                    return true;
+                }
+                self.cpu.program_counter = (self.base & 0xFF00) | (self.addr & 0x00FF);
+            }
+            BranchFix => {
+                self.cpu.mem_read(self.cpu.program_counter);
+                self.cpu.program_counter = self.addr;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::{FlatBus, RamInit};
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::test::FaultInjector;
+    use crate::opscodes::CPU_OPS_CODES;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // 2 つのコアのバスアクセスを比べるための記録付きバス
+    struct RecordingBus {
+        inner: FlatBus,
+        accesses: Accesses,
+    }
+
+    impl Mem for RecordingBus {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.accesses.borrow_mut().push((addr, None));
+            self.inner.mem_read(addr)
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.accesses.borrow_mut().push((addr, Some(data)));
+            self.inner.mem_write(addr, data)
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.inner.peek(addr)
+        }
+    }
+
+    type Accesses = Rc<RefCell<Vec<(u16, Option<u8>)>>>;
+
+    fn recording_cpu(program: &[u8], index: u8, status: u8) -> (CPU<RecordingBus>, Accesses) {
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let mut inner = FlatBus::new();
+        // $02F0 に置くと分岐先がページをまたぐ
+        inner.load(0x02F0, program);
+        inner.load(0x0020, &[0xF0, 0x12]);
+        inner.load(0x1220, &[0x55, 0xAA]);
+        inner.load(0xFFFA, &[0x00, 0x90, 0x00, 0x80, 0x00, 0xA0]);
+        let mut cpu = CPU::new(RecordingBus {
+            inner,
+            accesses: accesses.clone(),
+        });
+        cpu.program_counter = 0x02F0;
+        cpu.register_a = 0x3C;
+        cpu.register_x = index;
+        cpu.register_y = index;
+        cpu.status = status;
+        (cpu, accesses)
+    }
+
+    fn registers<B: Mem>(cpu: &CPU<B>) -> (u8, u8, u8, u8, u16, u8, u64) {
+        (
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.status,
+            cpu.program_counter,
+            cpu.stack_pointer,
+            cpu.cycles,
+        )
+    }
+
+    // すべての命令で、命令単位のコアとバスアクセス・結果が同じになる
+    #[test]
+    fn test_same_as_instruction_core() {
+        for op in CPU_OPS_CODES.iter() {
+            if op.name == "*JAM" {
+                continue;
+            }
+            for (index, status) in [(0x00, 0x00), (0xFF, 0xFF), (0x10, 0x00), (0x01, 0xC3)] {
+                let program = [op.code, 0x20, 0x12];
+                let (mut expected, expected_accesses) = recording_cpu(&program, index, status);
+                let expected_result = expected.step();
+
+                let (cpu, accesses) = recording_cpu(&program, index, status);
+                let mut cycle = CycleCpu::new(cpu);
+                let result = cycle.step();
+
+                let message = format!(
+                    "{:02X} {} X/Y={:02X} P={:02X}",
+                    op.code, op.name, index, status
+                );
+                assert_eq!(expected_result, result, "{}", message);
+                assert_eq!(
+                    *expected_accesses.borrow(),
+                    *accesses.borrow(),
+                    "{}",
+                    message
+                );
+                assert_eq!(registers(&expected), registers(&cycle.cpu), "{}", message);
+                assert_eq!(
+                    expected.bus.inner.peek_u16(0x1220),
+                    cycle.cpu.bus.inner.peek_u16(0x1220),
+                    "{}",
+                    message
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fault_same_as_instruction_core() {
+        for policy in [FaultPolicy::Halt, FaultPolicy::TreatAsNop] {
+            let faulting_cpu = || {
+                let mut bus = FlatBus::new();
+                assemble(".org $8000\nINC a:$0010\nINC a:$0010")
+                    .unwrap()
+                    .load(&mut bus);
+                bus.load(0x0010, &[0x7F]);
+                let mut cpu = CPU::new(bus);
+                cpu.program_counter = 0x8000;
+                cpu.status = 0;
+                cpu.fault_policy = policy;
+                cpu.add_observer(Box::new(FaultInjector { armed: true }));
+                cpu
+            };
+            let mut expected = faulting_cpu();
+            let mut cycle = CycleCpu::new(faulting_cpu());
+
+            let result = cycle.step();
+            assert_eq!(result, expected.step(), "{:?}", policy);
+            assert_eq!(registers(&expected), registers(&cycle.cpu), "{:?}", policy);
+            assert_eq!(cycle.cpu.peek(0x0010), 0x7F);
+            if policy == FaultPolicy::Halt {
+                assert!(matches!(result, StepResult::Fault(_)));
+            }
+
+            // フォルトは残らないので次の命令は書き込める
+            cycle.step();
+            assert_eq!(cycle.cpu.peek(0x0010), 0x80);
+        }
+    }
+
+    #[test]
+    fn test_nestest_same_as_instruction_core() {
+        let mut expected = CPU::new(Bus::new(test_rom()));
+        expected.power_on(RamInit::default());
+        expected.program_counter = 0xC000;
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.power_on(RamInit::default());
+        cpu.program_counter = 0xC000;
+        let mut cycle = CycleCpu::new(cpu);
+
+        for _ in 0..8990 {
+            assert_eq!(expected.step(), cycle.step());
+            assert_eq!(registers(&expected), registers(&cycle.cpu));
+        }
+    }
+
+    fn irq_cpu(source: &str) -> CycleCpu<FlatBus> {
+        let mut bus = FlatBus::new();
+        assemble(&format!(".org $0200\n{}", source))
+            .unwrap()
+            .load(&mut bus);
+        bus.load(0xFFFE, &[0x00, 0x90]);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0200;
+        cpu.status = 0;
+        CycleCpu::new(cpu)
+    }
+
+    // 2 サイクル目の間に IRQ を上げて、命令が終わったあと次に何が起きるか
+    fn after_irq_in_second_cycle(source: &str) -> Vec<StepResult> {
+        let mut cpu = irq_cpu(source);
+        assert_eq!(cpu.tick(), None);
+        cpu.cpu.set_irq(true);
+        cpu.step();
+        vec![cpu.step(), cpu.step()]
+    }
+
+    #[test]
+    fn test_irq_polling() {
+        let irq = StepResult::Interrupt {
+            kind: Interrupt::Irq,
+            cycles: 7,
+        };
+        let nop = StepResult::Executed {
+            opcode: 0xEA,
+            cycles: 2,
+        };
+
+        // LDA $10 (3 サイクル): 2 サイクル目の終わりに見えているのですぐ割り込む
+        let results = after_irq_in_second_cycle("LDA $10\nNOP");
+        assert_eq!(results[0], irq);
+
+        // 成立した分岐 (ページをまたがない) は 1 命令遅れる
+        let results = after_irq_in_second_cycle("BNE next\nnext: NOP\nNOP");
+        assert_eq!(results, vec![nop.clone(), irq.clone()]);
+
+        // 成立しない分岐は 2 サイクルで終わるので同じく 1 命令遅れる
+        let results = after_irq_in_second_cycle("BEQ next\nnext: NOP\nNOP");
+        assert_eq!(results, vec![nop.clone(), irq.clone()]);
+
+        // CLI の直後の命令は実行してから割り込む
+        let mut cpu = irq_cpu("CLI\nNOP\nNOP");
+        cpu.cpu.status = FLAG_I;
+        cpu.cpu.set_irq(true);
+        cpu.step();
+        assert_eq!(cpu.step(), nop);
+        assert_eq!(cpu.step(), irq);
+        assert_eq!(cpu.cpu.program_counter, 0x9000);
+        assert_eq!(cpu.cpu.peek(0x01FB) & FLAG_I, 0);
+    }
+
+    #[test]
+    fn test_run_executes_brk() {
+        // BRK のあとの 1 バイトは読み飛ばされる。ハンドラは JAM
+        let mut cpu = irq_cpu("BRK\n.byte $00");
+        cpu.cpu.bus.load(0x9000, &[0x02]);
+        let mut pcs = vec![];
+        cpu.run_with_callback(|cpu| pcs.push(cpu.program_counter));
+        assert_eq!(pcs, vec![0x0200, 0x9000]);
+        assert!(cpu.cpu.halted);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        let mut bus = FlatBus::new();
+        bus.load(0x0200, &[0x00, 0x00]);
+        bus.load(0xFFFA, &[0x00, 0x80, 0x00, 0x00, 0x00, 0x90]);
+        let mut cpu = CycleCpu::new(CPU::new(bus));
+        cpu.cpu.program_counter = 0x0200;
+        cpu.cpu.stack_pointer = 0xFD;
+
+        // BRK の 4 サイクル目で NMI
+        for _ in 0..3 {
+            assert_eq!(cpu.tick(), None);
+        }
+        cpu.cpu.trigger_nmi();
+        assert_eq!(
+            cpu.step(),
+            StepResult::Executed {
+                opcode: 0x00,
+                cycles: 7
+            }
+        );
+        assert_eq!(cpu.cpu.program_counter, 0x8000);
+        // B フラグ付きでプッシュされている
+        assert_eq!(cpu.cpu.peek(0x01FB) & 0x10, 0x10);
+        assert_eq!(cpu.cpu.peek_u16(0x01FC), 0x0202);
+    }
+
+    const FLAG_I: u8 = 0x04;
+}
@@ -0,0 +1,1019 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use crate::bus::Mem;
+use crate::cpu::{
+    trace_with_symbols, CpuFault, Frame, FrameKind, Interrupt, OpCode, StepResult, CPU,
+};
+use crate::disasm::decode;
+use crate::expr::Expr;
+use crate::observer::Observer;
+use crate::source_map::SourceMap;
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(&self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    // アクセスした命令の実行後に評価して、成り立つときだけ止まる
+    pub condition: Option<Expr>,
+    pub hits: u64,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, kind: WatchKind) -> Self {
+        Watchpoint {
+            start,
+            end,
+            kind,
+            condition: None,
+            hits: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    Watchpoint { addr: u16, write: bool },
+    Brk(u16),
+    Halted(u16),
+    Fault(CpuFault),
+}
+
+// ブレークポイントは CPU 側 (cpu.breakpoints) で持つ
+#[derive(Default)]
+pub struct Debugger {
+    pub watchpoints: Vec<Watchpoint>,
+    // ブレークポイントの条件。その PC の命令を実行する前に評価する
+    pub conditions: BTreeMap<u16, Expr>,
+    // ブレークポイントに来た回数 (条件が成り立たなかったときも数える)
+    pub hits: BTreeMap<u16, u64>,
+    // アドレスの代わりにラベルで表示し、コマンドでもラベルを使えるようにする
+    pub symbols: SymbolTable,
+    // cc65 の .dbg の行情報。ソースの行単位で実行し、ローカル変数を見る
+    pub source: SourceMap,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            watchpoints: vec![],
+            conditions: BTreeMap::new(),
+            hits: BTreeMap::new(),
+            symbols: SymbolTable::new(),
+            source: SourceMap::new(),
+        }
+    }
+
+    // ソースの行が分かればトレースの次の行に出す
+    fn trace<B: Mem>(&self, cpu: &CPU<B>) -> String {
+        let trace = trace_with_symbols(cpu, &self.symbols);
+        match self.source.describe(cpu, cpu.program_counter) {
+            Some(line) => format!("{}\n{}", trace, line),
+            None => trace,
+        }
+    }
+
+    // $C000 (Reset)
+    fn describe<B: Mem>(&self, cpu: &CPU<B>, addr: u16) -> String {
+        match self.symbols.label(cpu, addr) {
+            Some(name) => format!("${:04X} ({})", addr, name),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    // シャドウコールスタックから。内側 (今の PC) から順に
+    //   #0  $0612 in sub
+    //   #1  $0600 in $8000 [NMI]
+    pub fn backtrace<B: Mem>(&self, cpu: &CPU<B>) -> Vec<String> {
+        let frames = &cpu.call_stack;
+        let mut lines = vec![];
+        let mut pc = cpu.program_counter;
+        for depth in 0..=frames.len() {
+            // 今いる関数に入ったときの段
+            let mut line = format!("#{:<2} {}", depth, self.describe(cpu, pc));
+            if let Some(frame) = frames.len().checked_sub(depth + 1).map(|i| &frames[i]) {
+                line += &format!(" in {}", self.function_name(cpu, frame.to));
+                if frame.kind != FrameKind::Jsr {
+                    line += &format!(" [{}]", frame_kind_name(frame.kind));
+                }
+                pc = frame.from;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    fn function_name<B: Mem>(&self, cpu: &CPU<B>, addr: u16) -> String {
+        match self.symbols.label(cpu, addr) {
+            Some(name) => name.to_string(),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    // $0100+SP から上の各バイトが何か (戻り先、ステータス、データ)
+    pub fn stack_view<B: Mem>(&self, cpu: &CPU<B>) -> String {
+        let mut result = String::new();
+        // SP が $FF なら何も積まれていない (+1 すると 0 に戻ってページ全体になる)
+        if cpu.stack_pointer == 0xFF {
+            return result;
+        }
+        for sp in cpu.stack_pointer + 1..=0xFF {
+            let addr = 0x0100 + sp as u16;
+            let value = cpu.peek(addr);
+            // sp は frame.sp から下に frame.size() バイト
+            let frame = cpu
+                .call_stack
+                .iter()
+                .rev()
+                .find(|frame| sp <= frame.sp && frame.sp - sp < frame.size());
+            let note = match frame {
+                Some(frame) => self.stack_note(cpu, frame, frame.sp - sp),
+                None => "data".to_string(),
+            };
+            result += &format!("{:04X}: {:02X}  {}\n", addr, value, note);
+        }
+        result
+    }
+
+    // offset は frame.sp からいくつ下か (0 が戻り先の上位バイト)
+    fn stack_note<B: Mem>(&self, cpu: &CPU<B>, frame: &Frame, offset: u8) -> String {
+        let pushed = cpu.peek_u16(0x0100 + frame.sp as u16 - 1);
+        // RTS は積まれた値 + 1 に戻る
+        let ret = match frame.kind {
+            FrameKind::Jsr => pushed.wrapping_add(1),
+            _ => pushed,
+        };
+        let from = format!(
+            "({} at {})",
+            frame_kind_name(frame.kind),
+            self.describe(cpu, frame.from)
+        );
+        match offset {
+            0 => format!("return ${:04X} high", ret),
+            1 => format!("return ${:04X} low {}", ret, from),
+            _ => format!("status {}", from),
+        }
+    }
+
+    fn need_source(&self) -> Result<(), String> {
+        if self.source.is_empty() {
+            return Err("no source line information (load a .dbg file with sym)".to_string());
+        }
+        Ok(())
+    }
+
+    // 数値かラベル
+    fn parse_address(&self, word: &str) -> Result<u16, String> {
+        parse_number(word).or_else(|e| self.symbols.address_of(word).ok_or(e))
+    }
+
+    pub fn step_into<B: Mem>(&mut self, cpu: &mut CPU<B>) -> StopReason {
+        self.resume(cpu, |_, _| true)
+    }
+
+    // JSR ならサブルーチンから戻るまで実行する
+    pub fn step_over<B: Mem>(&mut self, cpu: &mut CPU<B>) -> StopReason {
+        let op = cpu.find_ops(cpu.peek(cpu.program_counter));
+        match op {
+            Some(op) if op.name == "JSR" => {
+                let ret = cpu.program_counter.wrapping_add(op.bytes);
+                let sp = cpu.stack_pointer;
+                self.resume(cpu, |cpu, _| {
+                    cpu.program_counter == ret && cpu.stack_pointer >= sp
+                })
+            }
+            _ => self.step_into(cpu),
+        }
+    }
+
+    // 今のサブルーチンから RTS/RTI で抜けるまで実行する
+    pub fn step_out<B: Mem>(&mut self, cpu: &mut CPU<B>) -> StopReason {
+        let sp = cpu.stack_pointer;
+        self.resume(cpu, |cpu, executed| {
+            (executed.name == "RTS" || executed.name == "RTI") && cpu.stack_pointer > sp
+        })
+    }
+
+    // 別のソースの行の先頭に来るまで実行する。行情報のないコード (ライブラリなど) は飛ばす
+    pub fn step_line<B: Mem>(&mut self, cpu: &mut CPU<B>) -> StopReason {
+        self.resume_lines(cpu, false)
+    }
+
+    // step_line と同じだが、呼んだ関数の中では止まらない
+    pub fn next_line<B: Mem>(&mut self, cpu: &mut CPU<B>) -> StopReason {
+        self.resume_lines(cpu, true)
+    }
+
+    fn resume_lines<B: Mem>(&mut self, cpu: &mut CPU<B>, over: bool) -> StopReason {
+        let source = std::mem::take(&mut self.source);
+        let start = source.line(cpu, cpu.program_counter).cloned();
+        let sp = cpu.stack_pointer;
+        let reason = self.resume(cpu, |cpu, _| {
+            let pc = cpu.program_counter;
+            if over && cpu.stack_pointer < sp {
+                return false;
+            }
+            match source.line(cpu, pc) {
+                Some(line) => {
+                    Some(line) != start.as_ref() && source.is_line_start(pc, cpu.bus.prg_bank(pc))
+                }
+                None => false,
+            }
+        });
+        self.source = source;
+        reason
+    }
+
+    pub fn run_to<B: Mem>(&mut self, cpu: &mut CPU<B>, addr: u16) -> StopReason {
+        self.resume(cpu, |cpu, _| cpu.program_counter == addr)
+    }
+
+    pub fn continue_<B: Mem>(&mut self, cpu: &mut CPU<B>) -> StopReason {
+        self.resume(cpu, |_, _| false)
+    }
+
+    // 1 命令ずつ実行して、stop が true を返すかブレーク/ウォッチポイントで止まる。
+    // 今いる PC のブレークポイントでは止まらない (止まった所から再開できるように)
+    pub(crate) fn resume<B: Mem, F>(&mut self, cpu: &mut CPU<B>, stop: F) -> StopReason
+    where
+        F: FnMut(&CPU<B>, &OpCode) -> bool,
+    {
+        // ウォッチポイントは実際のバスアクセス (空読みや RMW の 2 回目の書き込みも) で見る
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let watching = !self.watchpoints.is_empty();
+        if watching {
+            cpu.add_observer(Box::new(AccessLog(accesses.clone())));
+        }
+        let reason = self.run(cpu, &accesses, stop);
+        if watching {
+            cpu.remove_observer();
+        }
+        reason
+    }
+
+    fn run<B: Mem, F>(&mut self, cpu: &mut CPU<B>, accesses: &Accesses, mut stop: F) -> StopReason
+    where
+        F: FnMut(&CPU<B>, &OpCode) -> bool,
+    {
+        let mut first = true;
+        loop {
+            let pc = cpu.program_counter;
+            let op = cpu.find_ops(cpu.peek(pc));
+            accesses.borrow_mut().clear();
+
+            let result = cpu.step();
+            let fetched = match result {
+                StepResult::Executed { .. } => op.as_ref().map_or(0, |op| op.bytes),
+                // 割り込みシーケンスのスタックへの書き込みも見る
+                StepResult::Interrupt { .. } => 0,
+                // 今いる PC のブレークポイントは 1 回目だけ無視される
+                StepResult::Breakpoint { .. } if first => continue,
+                StepResult::Breakpoint { pc } => {
+                    if self.breakpoint_hit(cpu, pc) {
+                        return StopReason::Breakpoint(pc);
+                    }
+                    // 条件が成り立たなければ、次の step でこのブレークポイントを無視して続ける
+                    continue;
+                }
+                StepResult::Halted { pc } => return StopReason::Halted(pc),
+                StepResult::Fault(fault) => return StopReason::Fault(fault),
+            };
+            first = false;
+            // 命令自体 (オペコードとオペランド) の読み込みは数えない
+            let accesses = accesses.take();
+            for &(addr, write) in &accesses {
+                if !write && addr.wrapping_sub(pc) < fetched {
+                    continue;
+                }
+                for i in self.watching(addr, write) {
+                    if self.watchpoint_hit(cpu, i) {
+                        return StopReason::Watchpoint { addr, write };
+                    }
+                }
+            }
+            // 割り込みに入ったら次の命令から続ける
+            if let StepResult::Interrupt { .. } = result {
+                continue;
+            }
+            let op = op.unwrap();
+            // BRK は実行してから (ハンドラの先頭で) 止まる。続けるとハンドラから再開する
+            if op.name == "BRK" {
+                return StopReason::Brk(pc);
+            }
+            if stop(cpu, &op) {
+                return StopReason::Step;
+            }
+        }
+    }
+
+    // addr へのアクセスに引っかかるウォッチポイントの番号
+    fn watching(&self, addr: u16, write: bool) -> Vec<usize> {
+        self.watchpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| w.start <= addr && addr <= w.end && w.kind.matches(write))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // 回数を数えて、条件があれば評価する
+    fn breakpoint_hit<B: Mem>(&mut self, cpu: &CPU<B>, pc: u16) -> bool {
+        let hits = self.hits.entry(pc).or_insert(0);
+        *hits += 1;
+        match self.conditions.get(&pc) {
+            Some(condition) => condition.is_true(cpu, *hits),
+            None => true,
+        }
+    }
+
+    fn watchpoint_hit<B: Mem>(&mut self, cpu: &CPU<B>, i: usize) -> bool {
+        let watchpoint = &mut self.watchpoints[i];
+        watchpoint.hits += 1;
+        match &watchpoint.condition {
+            Some(condition) => condition.is_true(cpu, watchpoint.hits),
+            None => true,
+        }
+    }
+
+    pub fn repl<B: Mem, R: BufRead, W: Write>(&mut self, cpu: &mut CPU<B>, input: R, out: &mut W) {
+        let _ = writeln!(out, "{}", self.trace(cpu));
+        let _ = write!(out, "> ");
+        let _ = out.flush();
+        for line in input.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.first() == Some(&"q") || words.first() == Some(&"quit") {
+                break;
+            }
+            if let Err(e) = self.command(cpu, &words, out) {
+                let _ = writeln!(out, "error: {}", e);
+            }
+            let _ = write!(out, "> ");
+            let _ = out.flush();
+        }
+    }
+
+    pub fn command<B: Mem, W: Write>(
+        &mut self,
+        cpu: &mut CPU<B>,
+        words: &[&str],
+        out: &mut W,
+    ) -> Result<(), String> {
+        // b $C000 if SP < $10 のように if から後ろは条件
+        let (words, mut condition) = match words.iter().position(|word| *word == "if") {
+            Some(i) => (&words[..i], Some(words[i + 1..].join(" ").parse::<Expr>()?)),
+            None => (words, None),
+        };
+        if condition.is_some() && !matches!(words.first(), Some(&"b" | &"break" | &"w" | &"watch"))
+        {
+            return Err("only break and watch take a condition".to_string());
+        }
+        let arg = |n: usize| -> Result<u16, String> {
+            match words.get(n) {
+                Some(word) => self.parse_address(word),
+                None => Err(format!("{} needs an address", words[0])),
+            }
+        };
+        let reason = match words.first() {
+            None => return Ok(()),
+            Some(&"s") | Some(&"step") => self.step_into(cpu),
+            Some(&"n") | Some(&"next") => self.step_over(cpu),
+            Some(&"finish") => self.step_out(cpu),
+            Some(&"sl") | Some(&"step-line") => {
+                self.need_source()?;
+                self.step_line(cpu)
+            }
+            Some(&"nl") | Some(&"next-line") => {
+                self.need_source()?;
+                self.next_line(cpu)
+            }
+            Some(&"c") | Some(&"continue") => self.continue_(cpu),
+            Some(&"until") => self.run_to(cpu, arg(1)?),
+            Some(&"b") | Some(&"break") => {
+                let addr = arg(1)?;
+                cpu.breakpoints.insert(addr);
+                self.hits.remove(&addr);
+                match condition.take() {
+                    Some(condition) => self.conditions.insert(addr, condition),
+                    None => self.conditions.remove(&addr),
+                };
+                return Ok(());
+            }
+            Some(&"d") | Some(&"delete") => {
+                let addr = arg(1)?;
+                if !cpu.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at ${:04X}", addr));
+                }
+                self.conditions.remove(&addr);
+                self.hits.remove(&addr);
+                return Ok(());
+            }
+            Some(&"w") | Some(&"watch") => {
+                let range = words.get(1).ok_or("watch needs an address")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (self.parse_address(start)?, self.parse_address(end)?),
+                    None => (self.parse_address(range)?, self.parse_address(range)?),
+                };
+                let kind = match words.get(2) {
+                    None | Some(&"rw") => WatchKind::ReadWrite,
+                    Some(&"r") => WatchKind::Read,
+                    Some(&"w") => WatchKind::Write,
+                    Some(other) => return Err(format!("unknown watch kind {}", other)),
+                };
+                let mut watchpoint = Watchpoint::new(start, end, kind);
+                watchpoint.condition = condition.take();
+                self.watchpoints.push(watchpoint);
+                return Ok(());
+            }
+            Some(&"wd") => {
+                let n = arg(1)? as usize;
+                if n >= self.watchpoints.len() {
+                    return Err(format!("no watchpoint #{}", n));
+                }
+                self.watchpoints.remove(n);
+                return Ok(());
+            }
+            Some(&"sym") | Some(&"symbols") => {
+                let path = words.get(1).ok_or("sym needs a file (.dbg, .nl or .mlb)")?;
+                let table = SymbolTable::load(path)?;
+                let _ = writeln!(out, "loaded {} symbols", table.symbols().len());
+                self.symbols.extend(table);
+                if path.ends_with(".dbg") {
+                    self.source = SourceMap::load(path)?;
+                }
+                return Ok(());
+            }
+            Some(&"locals") => {
+                self.need_source()?;
+                let (function, locals) = self
+                    .source
+                    .locals(cpu, cpu.program_counter)
+                    .ok_or("no local variables here")?;
+                let _ = writeln!(out, "{}:", function);
+                for local in locals {
+                    let bytes: Vec<String> = (0..local.size)
+                        .map(|i| format!("{:02X}", cpu.peek(local.addr.wrapping_add(i as u16))))
+                        .collect();
+                    let _ = writeln!(
+                        out,
+                        "  {} = {} (${:04X})",
+                        local.name,
+                        bytes.join(" "),
+                        local.addr
+                    );
+                }
+                return Ok(());
+            }
+            Some(&"info") => {
+                // break $C000 if SP < $10 (hits 2)
+                let details = |condition: Option<&Expr>, hits: u64| {
+                    let mut text = String::new();
+                    if let Some(condition) = condition {
+                        text += &format!(" if {}", condition);
+                    }
+                    if hits > 0 {
+                        text += &format!(" (hits {})", hits);
+                    }
+                    text
+                };
+                for addr in cpu.breakpoints.iter() {
+                    let hits = self.hits.get(addr).copied().unwrap_or(0);
+                    let _ = writeln!(
+                        out,
+                        "break {}{}",
+                        self.describe(cpu, *addr),
+                        details(self.conditions.get(addr), hits)
+                    );
+                }
+                for (i, w) in self.watchpoints.iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "#{} watch ${:04X}-${:04X} {:?}{}",
+                        i,
+                        w.start,
+                        w.end,
+                        w.kind,
+                        details(w.condition.as_ref(), w.hits)
+                    );
+                }
+                return Ok(());
+            }
+            Some(&"r") | Some(&"regs") => {
+                let _ = writeln!(out, "{}", registers(cpu));
+                return Ok(());
+            }
+            Some(&"x") => {
+                let addr = arg(1)?;
+                let len = if words.len() > 2 { arg(2)? } else { 16 };
+                let _ = write!(out, "{}", hexdump(cpu, addr, len));
+                return Ok(());
+            }
+            Some(&"dis") => {
+                let mut addr = if words.len() > 1 {
+                    arg(1)?
+                } else {
+                    cpu.program_counter
+                };
+                let count = if words.len() > 2 { arg(2)? } else { 10 };
+                let label = |a: u16| self.symbols.label(cpu, a).map(|name| name.to_string());
+                for _ in 0..count {
+                    let instruction = decode(cpu.variant, |a| Some(cpu.peek(a)), addr).unwrap();
+                    if let Some(name) = label(addr) {
+                        let _ = writeln!(out, "{}:", name);
+                    }
+                    let asm = instruction.asm_with_labels(label);
+                    let _ = writeln!(out, "{}", instruction.line(&asm));
+                    addr = addr.wrapping_add(instruction.size());
+                }
+                return Ok(());
+            }
+            Some(&"stack") => {
+                let view = self.stack_view(cpu);
+                if view.is_empty() {
+                    let _ = writeln!(out, "stack is empty");
+                } else {
+                    let _ = write!(out, "{}", view);
+                }
+                return Ok(());
+            }
+            Some(&"bt") | Some(&"backtrace") => {
+                for line in self.backtrace(cpu) {
+                    let _ = writeln!(out, "{}", line);
+                }
+                return Ok(());
+            }
+            Some(other) => return Err(format!("unknown command {}", other)),
+        };
+        match reason {
+            StopReason::Step => {}
+            StopReason::Breakpoint(pc) => {
+                let _ = writeln!(out, "breakpoint at {}", self.describe(cpu, pc));
+            }
+            StopReason::Watchpoint { addr, write } => {
+                let kind = if write { "write" } else { "read" };
+                let _ = writeln!(out, "watchpoint: {} ${:04X}", kind, addr);
+            }
+            StopReason::Brk(pc) => {
+                let _ = writeln!(out, "BRK at ${:04X}", pc);
+            }
+            StopReason::Halted(pc) => {
+                let _ = writeln!(out, "CPU halted (JAM) at ${:04X}", pc);
+            }
+            StopReason::Fault(fault) => {
+                let _ = writeln!(out, "{}", fault);
+            }
+        }
+        let _ = writeln!(out, "{}", self.trace(cpu));
+        Ok(())
+    }
+}
+
+fn parse_number(word: &str) -> Result<u16, String> {
+    let result = if let Some(hex) = word.strip_prefix('$') {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(hex) = word.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else {
+        word.parse::<u16>()
+    };
+    result.map_err(|_| format!("invalid number {}", word))
+}
+
+fn registers<B: Mem>(cpu: &CPU<B>) -> String {
+    let mut flags = String::new();
+    for (i, name) in "NV-BDIZC".chars().enumerate() {
+        if cpu.status & (0x80 >> i) != 0 {
+            flags.push(name);
+        } else {
+            flags.push('.');
+        }
+    }
+    format!(
+        "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} [{}]",
+        cpu.program_counter,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.stack_pointer,
+        cpu.status,
+        flags
+    )
+}
+
+fn frame_kind_name(kind: FrameKind) -> &'static str {
+    match kind {
+        FrameKind::Jsr => "JSR",
+        FrameKind::Brk => "BRK",
+        FrameKind::Interrupt(Interrupt::Nmi) => "NMI",
+        FrameKind::Interrupt(Interrupt::Irq) => "IRQ",
+    }
+}
+
+fn hexdump<B: Mem>(cpu: &CPU<B>, addr: u16, len: u16) -> String {
+    let mut result = String::new();
+    for row in (0..len).step_by(16) {
+        let start = addr.wrapping_add(row);
+        let bytes: Vec<String> = (0..16.min(len - row))
+            .map(|i| format!("{:02X}", cpu.peek(start.wrapping_add(i))))
+            .collect();
+        result += &format!("{:04X}: {}\n", start, bytes.join(" "));
+    }
+    result
+}
+
+type Accesses = Rc<RefCell<Vec<(u16, bool)>>>;
+
+// CPU のバスアクセスを記録する (書き込みなら true)
+struct AccessLog(Accesses);
+
+impl<B: Mem> Observer<B> for AccessLog {
+    fn memory_read(&mut self, addr: u16, _value: u8) {
+        self.0.borrow_mut().push((addr, false));
+    }
+
+    fn memory_write(&mut self, addr: u16, _value: u8) {
+        self.0.borrow_mut().push((addr, true));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    fn debug_cpu(source: &str) -> CPU {
+        let mut bus = Bus::new(test_rom());
+        assemble(&format!(".org $0600\n{}", source))
+            .unwrap()
+            .load(&mut bus);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    const PROGRAM: &str = "
+            JSR sub
+            LDA #$02
+            STA $10
+            BRK
+            .byte $00
+        sub:
+            LDX #$05
+            INX
+            RTS
+    ";
+
+    #[test]
+    fn test_step_into_and_over() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(PROGRAM);
+        assert_eq!(dbg.step_into(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0609);
+        assert_eq!(cpu.stack_pointer, 0xFB);
+
+        let mut cpu = debug_cpu(PROGRAM);
+        assert_eq!(dbg.step_over(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 0x06);
+        assert_eq!(cpu.stack_pointer, 0xFD);
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(PROGRAM);
+        dbg.step_into(&mut cpu);
+        dbg.step_into(&mut cpu);
+        assert_eq!(dbg.step_out(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 0x06);
+    }
+
+    #[test]
+    fn test_breakpoint_and_run_to() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(PROGRAM);
+        cpu.breakpoints.insert(0x060B);
+        assert_eq!(dbg.continue_(&mut cpu), StopReason::Breakpoint(0x060B));
+        assert_eq!(cpu.register_x, 0x05);
+        // 止まった所から再開できる
+        assert_eq!(dbg.run_to(&mut cpu, 0x0605), StopReason::Step);
+        assert_eq!(cpu.register_a, 0x02);
+        assert_eq!(dbg.continue_(&mut cpu), StopReason::Brk(0x0607));
+        // BRK を実行して IRQ/BRK ハンドラの先頭で止まっている
+        assert_eq!(cpu.program_counter, cpu.peek_u16(0xFFFE));
+        assert_eq!(cpu.stack_pointer, 0xFA);
+        assert_eq!(cpu.peek_u16(0x01FC), 0x0609);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(PROGRAM);
+        dbg.watchpoints
+            .push(Watchpoint::new(0x10, 0x1F, WatchKind::Write));
+        assert_eq!(
+            dbg.continue_(&mut cpu),
+            StopReason::Watchpoint {
+                addr: 0x10,
+                write: true
+            }
+        );
+        assert_eq!(cpu.program_counter, 0x0607);
+        assert_eq!(cpu.peek(0x10), 0x02);
+    }
+
+    #[test]
+    fn test_watchpoint_sees_bus_accesses() {
+        // LDA $00F0,X はページをまたぐので先に $0010 を空読みする
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu("LDA a:$00F0,X");
+        cpu.register_x = 0x20;
+        dbg.watchpoints
+            .push(Watchpoint::new(0x10, 0x10, WatchKind::Read));
+        assert_eq!(
+            dbg.continue_(&mut cpu),
+            StopReason::Watchpoint {
+                addr: 0x10,
+                write: false
+            }
+        );
+        assert_eq!(cpu.program_counter, 0x0603);
+
+        // INC $10 は元の値と結果の 2 回書く
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu("INC $10");
+        dbg.watchpoints
+            .push(Watchpoint::new(0x10, 0x10, WatchKind::Write));
+        dbg.continue_(&mut cpu);
+        assert_eq!(dbg.watchpoints[0].hits, 1);
+        dbg.watchpoints[0].condition = Some("hits == 3".parse().unwrap());
+        cpu.program_counter = 0x0600;
+        dbg.continue_(&mut cpu);
+        assert_eq!(dbg.watchpoints[0].hits, 3);
+        assert_eq!(cpu.program_counter, 0x0602);
+
+        // 割り込みシーケンスのスタックへの書き込み
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu("NOP\nNOP");
+        let top = 0x0100 + cpu.stack_pointer as u16;
+        dbg.watchpoints
+            .push(Watchpoint::new(top, top, WatchKind::Write));
+        cpu.trigger_nmi();
+        assert_eq!(
+            dbg.continue_(&mut cpu),
+            StopReason::Watchpoint {
+                addr: top,
+                write: true
+            }
+        );
+        assert_eq!(cpu.peek(top), 0x06);
+        assert_eq!(cpu.call_stack.len(), 1);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(PROGRAM);
+        let input = "b $060B\nc\nr\nstack\nx $0600 4\ndis $0609 2\nfoo\nq\ns\n".as_bytes();
+        let mut out: Vec<u8> = vec![];
+        dbg.repl(&mut cpu, input, &mut out);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("breakpoint at $060B"));
+        assert!(out.contains("PC:060B A:00 X:05 Y:00 SP:FB P:24 [..-..I..]"));
+        assert!(out
+            .contains("01FC: 02  return $0603 low (JSR at $0600)\n01FD: 06  return $0603 high\n"));
+        assert!(out.contains("0600: 20 09 06 A9"));
+        assert!(out.contains("0609  A2 05     LDX #$05\n060B  E8        INX\n"));
+        assert!(out.contains("error: unknown command foo"));
+        // q の後は実行しない
+        assert_eq!(cpu.program_counter, 0x060B);
+    }
+
+    #[test]
+    fn test_repl_with_symbols() {
+        let mut dbg = Debugger::new();
+        dbg.symbols.add("sub", 0x0609, None);
+        dbg.symbols.add("result", 0x0010, None);
+        let mut cpu = debug_cpu(PROGRAM);
+        let input = "b sub\nc\ninfo\ndis $0600 3\ndis sub 1\nq\n".as_bytes();
+        let mut out: Vec<u8> = vec![];
+        dbg.repl(&mut cpu, input, &mut out);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("0600  20 09 06  JSR sub"));
+        assert!(out.contains("breakpoint at $0609 (sub)"));
+        assert!(out.contains("break $0609 (sub)"));
+        assert!(out.contains("0605  85 10     STA result"));
+        assert!(out.contains("sub:\n0609  A2 05     LDX #$05\n"));
+    }
+
+    const LOOP: &str = "
+            LDX #$00
+        loop:
+            INX
+            STX $10
+            CPX #$05
+            BNE loop
+            BRK
+    ";
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(LOOP);
+        cpu.breakpoints.insert(0x0602);
+        dbg.conditions.insert(0x0602, "X == 3".parse().unwrap());
+        assert_eq!(dbg.continue_(&mut cpu), StopReason::Breakpoint(0x0602));
+        assert_eq!(cpu.register_x, 3);
+        assert_eq!(dbg.hits[&0x0602], 4);
+        assert_eq!(dbg.continue_(&mut cpu), StopReason::Brk(0x0609));
+        assert_eq!(dbg.hits[&0x0602], 5);
+
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(LOOP);
+        cpu.breakpoints.insert(0x0602);
+        dbg.conditions.insert(0x0602, "hits == 2".parse().unwrap());
+        assert_eq!(dbg.continue_(&mut cpu), StopReason::Breakpoint(0x0602));
+        assert_eq!(cpu.register_x, 1);
+
+        // ウォッチポイントの条件は書き込んだ後に見る
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(LOOP);
+        let mut watchpoint = Watchpoint::new(0x10, 0x10, WatchKind::Write);
+        watchpoint.condition = Some("[$10] == 4".parse().unwrap());
+        dbg.watchpoints.push(watchpoint);
+        assert_eq!(
+            dbg.continue_(&mut cpu),
+            StopReason::Watchpoint {
+                addr: 0x10,
+                write: true
+            }
+        );
+        assert_eq!(cpu.program_counter, 0x0605);
+        assert_eq!(dbg.watchpoints[0].hits, 4);
+    }
+
+    #[test]
+    fn test_repl_conditions() {
+        let mut dbg = Debugger::new();
+        let mut cpu = debug_cpu(LOOP);
+        let input = "b $0602 if X == 2\nw $10 w if A != 0\nc\ninfo\nb $0603 if foo\ns if A\nq\n";
+        let mut out: Vec<u8> = vec![];
+        dbg.repl(&mut cpu, input.as_bytes(), &mut out);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("breakpoint at $0602"));
+        assert!(out.contains("break $0602 if X == 2 (hits 3)"));
+        assert!(out.contains("#0 watch $0010-$0010 Write if A != 0 (hits 2)"));
+        assert!(out.contains("error: unknown name: foo"));
+        assert!(out.contains("error: only break and watch take a condition"));
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_backtrace_and_stack_view() {
+        let mut dbg = Debugger::new();
+        for (name, addr) in [
+            ("Reset", 0x8000),
+            ("update", 0x8010),
+            ("helper", 0x8020),
+            ("nmi", 0x8030),
+        ] {
+            dbg.symbols.add(name, addr, None);
+        }
+        let mut cpu = crate::cpu::test::call_stack_cpu();
+        cpu.step();
+        cpu.step();
+        cpu.trigger_nmi();
+        cpu.step();
+
+        assert_eq!(
+            dbg.backtrace(&cpu),
+            [
+                "#0  $8030 (nmi) in nmi [NMI]",
+                "#1  $8020 (helper) in helper",
+                "#2  $8010 (update) in update",
+                "#3  $8000 (Reset)",
+            ]
+        );
+        assert_eq!(
+            dbg.stack_view(&cpu),
+            "01F7: 24  status (NMI at $8020 (helper))\n\
+             01F8: 20  return $8020 low (NMI at $8020 (helper))\n\
+             01F9: 80  return $8020 high\n\
+             01FA: 12  return $8013 low (JSR at $8010 (update))\n\
+             01FB: 80  return $8013 high\n\
+             01FC: 02  return $8003 low (JSR at $8000 (Reset))\n\
+             01FD: 80  return $8003 high\n\
+             01FE: 00  data\n\
+             01FF: 00  data\n"
+        );
+
+        // 何も積まれていなければ空
+        cpu.stack_pointer = 0xFE;
+        assert_eq!(dbg.stack_view(&cpu), "01FF: 00  data\n");
+        cpu.stack_pointer = 0xFF;
+        assert_eq!(dbg.stack_view(&cpu), "");
+        let mut out = vec![];
+        dbg.repl(&mut cpu, "stack\n".as_bytes(), &mut out);
+        assert!(String::from_utf8(out).unwrap().contains("stack is empty"));
+    }
+
+    // source_map::test::DBG の行に合わせたプログラム
+    fn source_cpu() -> CPU {
+        let mut cpu = debug_cpu(
+            "
+                JSR sub     ; 51: SetScroll(0, 0);
+                LDA #$02    ; 52: ShowScreen(1);
+                STA $10
+                NOP         ; 55: while (1);
+                NOP
+                BRK         ; 52
+                .org $0610
+            sub:
+                LDX #$05    ; 21: *(char*)0x2005 = x;
+                INX         ; 22: *(char*)0x2005 = y;
+                RTS
+            ",
+        );
+        // sp = $03E1
+        cpu.mem_write(0x0000, 0xE1);
+        cpu.mem_write(0x0001, 0x03);
+        cpu
+    }
+
+    #[test]
+    fn test_step_line() {
+        let mut dbg = Debugger::new();
+        dbg.source = SourceMap::parse(crate::source_map::test::DBG).unwrap();
+        let mut cpu = source_cpu();
+        assert_eq!(dbg.step_line(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0610);
+        assert_eq!(dbg.step_line(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0612);
+        // 戻った先の行の先頭で止まる
+        assert_eq!(dbg.step_line(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0603);
+
+        let mut cpu = source_cpu();
+        assert_eq!(dbg.next_line(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 0x06);
+        assert_eq!(dbg.next_line(&mut cpu), StopReason::Step);
+        assert_eq!(cpu.program_counter, 0x0607);
+    }
+
+    #[test]
+    fn test_repl_source_lines() {
+        let mut dbg = Debugger::new();
+        let mut cpu = source_cpu();
+        let input = "sl\nlocals\nq\n".as_bytes();
+        let mut out: Vec<u8> = vec![];
+        dbg.repl(&mut cpu, input, &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("error: no source line information"));
+
+        dbg.source = SourceMap::parse(crate::source_map::test::DBG).unwrap();
+        let mut cpu = source_cpu();
+        cpu.mem_write(0x03E1, 0x07);
+        let input = "nl\nlocals\nq\n".as_bytes();
+        let mut out: Vec<u8> = vec![];
+        dbg.repl(&mut cpu, input, &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(
+            "0600  20 10 06  JSR $0610                       A:00 X:00 Y:00 P:24 SP:FD\nsample2.c:51\n"
+        ));
+        assert!(out.contains("SP:FD\nsample2.c:52\n"));
+        assert!(out.contains("NesMain:\n  i = 07 ($03E1)\n  string = 00"));
+    }
+}
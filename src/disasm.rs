@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::cpu::{AddressingMode, OpCode, Variant};
+use crate::rom::Rom;
+
+pub fn binary(op: u8, args: &Vec<u8>) -> String {
+    let mut list: Vec<String> = vec![];
+    list.push(format!("{:<02X}", op));
+    for v in args {
+        list.push(format!("{:<02X}", v));
+    }
+    list.join(" ")
+}
+
+pub fn disasm(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
+    let prefix = if ops.name.starts_with("*") { "" } else { " " };
+    format!(
+        "{}{} {}",
+        prefix,
+        ops.name,
+        address(program_counter, &ops, args)
+    )
+}
+
+pub fn address(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
+    match ops.addressing_mode {
+        AddressingMode::Implied => {
+            format!("")
+        }
+        AddressingMode::Accumulator => {
+            format!("A")
+        }
+        // LDA #$44 => a9 44
+        AddressingMode::Immediate => {
+            format!("#${:<02X}", args[0])
+        }
+
+        // LDA $44 => a5 44
+        AddressingMode::ZeroPage => {
+            format!("${:<02X}", args[0])
+        }
+
+        // LDA $4400 => ad 00 44
+        AddressingMode::Absolute => {
+            format!("${:<02X}{:<02X}", args[1], args[0])
+        }
+        // LDA $44,X => b5 44
+        AddressingMode::ZeroPage_X => {
+            format!("${:<02X},X", args[0])
+        }
+
+        // LDX $44,Y => b6 44
+        AddressingMode::ZeroPage_Y => {
+            format!("${:<02X},Y", args[0])
+        }
+
+        // LDA $4400,X => bd 00 44
+        AddressingMode::Absolute_X => {
+            format!("${:<02X}{:<02X},X", args[1], args[0])
+        }
+
+        // LDA $4400,Y => b9 00 44
+        AddressingMode::Absolute_Y => {
+            format!("${:<02X}{:<02X},Y", args[1], args[0])
+        }
+        // JMP
+        AddressingMode::Indirect => {
+            format!("(${:<02X}{:<02X})", args[1], args[0])
+        }
+
+        // LDA ($44,X) => a1 44
+        AddressingMode::Indirect_X => {
+            format!("(${:<02X},X)", args[0])
+        }
+
+        // LDA ($44),Y => b1 44
+        AddressingMode::Indirect_Y => {
+            format!("(${:<02X}),Y", args[0])
+        }
+
+        // LDA ($44) => b2 44
+        AddressingMode::ZeroPage_Indirect => {
+            format!("(${:<02X})", args[0])
+        }
+
+        // JMP ($4400,X) => 7c 00 44
+        AddressingMode::Absolute_Indirect_X => {
+            format!("(${:<02X}{:<02X},X)", args[1], args[0])
+        }
+
+        // BCC *+4 => 90 04
+        AddressingMode::Relative => {
+            format!(
+                "${:<04X}",
+                (program_counter as i32 + (args[0] as i8) as i32) as u16 + 2
+            )
+        }
+
+        AddressingMode::NoneAddressing => {
+            panic!("mode {:?} is not supported", ops.addressing_mode);
+        }
+    }
+}
+
+// オペランドのアドレス (即値などは None) と、address() の中でのその書き方
+fn operand(program_counter: u16, ops: &OpCode, args: &[u8]) -> Option<(u16, String)> {
+    match ops.addressing_mode {
+        AddressingMode::ZeroPage
+        | AddressingMode::ZeroPage_X
+        | AddressingMode::ZeroPage_Y
+        | AddressingMode::Indirect_X
+        | AddressingMode::Indirect_Y
+        | AddressingMode::ZeroPage_Indirect => Some((args[0] as u16, format!("${:02X}", args[0]))),
+        AddressingMode::Absolute
+        | AddressingMode::Absolute_X
+        | AddressingMode::Absolute_Y
+        | AddressingMode::Indirect
+        | AddressingMode::Absolute_Indirect_X => {
+            let addr = u16::from_le_bytes([args[0], args[1]]);
+            Some((addr, format!("${:04X}", addr)))
+        }
+        AddressingMode::Relative => {
+            let addr = program_counter
+                .wrapping_add(2)
+                .wrapping_add(args[0] as i8 as u16);
+            Some((addr, format!("${:04X}", addr)))
+        }
+        _ => None,
+    }
+}
+
+// label が名前を返したら、オペランドのアドレスをその名前にする (JSR $C0A0 => JSR copypal)
+pub fn disasm_with_labels<F>(program_counter: u16, ops: &OpCode, args: &Vec<u8>, label: F) -> String
+where
+    F: Fn(u16) -> Option<String>,
+{
+    let asm = disasm(program_counter, ops, args);
+    match operand(program_counter, ops, args) {
+        Some((addr, text)) => match label(addr) {
+            Some(name) => asm.replacen(&text, &name, 1),
+            None => asm,
+        },
+        None => asm,
+    }
+}
+
+pub fn find_op(variant: Variant, code: u8) -> Option<&'static OpCode> {
+    variant.opcodes().iter().find(|op| op.code == code)
+}
+
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub op: Option<OpCode>,
+}
+
+impl Instruction {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    // 非公式命令は "*NOP" のように * が付く
+    pub fn asm(&self) -> String {
+        match &self.op {
+            Some(op) => disasm(self.addr, op, &self.bytes[1..].to_vec())
+                .trim_end()
+                .to_string(),
+            None => format!(" .byte ${:<02X}", self.bytes[0]),
+        }
+    }
+
+    pub fn asm_with_labels<F>(&self, label: F) -> String
+    where
+        F: Fn(u16) -> Option<String>,
+    {
+        match &self.op {
+            Some(op) => disasm_with_labels(self.addr, op, &self.bytes[1..].to_vec(), label)
+                .trim_end()
+                .to_string(),
+            None => self.asm(),
+        }
+    }
+
+    // アドレスとバイト列の後に asm を付ける (Display と同じ並び)
+    pub fn line(&self, asm: &str) -> String {
+        let args = self.bytes[1..].to_vec();
+        format!(
+            "{:<6}{:<9}{}",
+            format!("{:<04X}", self.addr),
+            binary(self.bytes[0], &args),
+            asm
+        )
+    }
+
+    // JMP/JSR/分岐の飛び先
+    pub fn target(&self) -> Option<u16> {
+        let op = self.op.as_ref()?;
+        match op.addressing_mode {
+            AddressingMode::Relative => {
+                let offset = self.bytes[1] as i8;
+                Some(self.addr.wrapping_add(2).wrapping_add(offset as u16))
+            }
+            AddressingMode::Absolute if op.name == "JMP" || op.name == "JSR" => {
+                Some(u16::from_le_bytes([self.bytes[1], self.bytes[2]]))
+            }
+            _ => None,
+        }
+    }
+
+    // この命令の次に実行が続かない
+    fn ends_flow(&self) -> bool {
+        match &self.op {
+            Some(op) => matches!(
+                op.name.as_str(),
+                "JMP" | "BRA" | "RTS" | "RTI" | "BRK" | "*JAM"
+            ),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.line(&self.asm()))
+    }
+}
+
+// read が None を返すアドレスはデータが無いものとして扱う。
+// 命令表は variant のもの (CPU::find_ops と同じ)
+pub fn decode<F>(variant: Variant, read: F, addr: u16) -> Option<Instruction>
+where
+    F: Fn(u16) -> Option<u8>,
+{
+    let code = read(addr)?;
+    if let Some(op) = find_op(variant, code) {
+        let bytes: Option<Vec<u8>> = (0..op.bytes).map(|n| read(addr.wrapping_add(n))).collect();
+        if let Some(bytes) = bytes {
+            return Some(Instruction {
+                addr,
+                bytes,
+                op: Some(op.clone()),
+            });
+        }
+    }
+    Some(Instruction {
+        addr,
+        bytes: vec![code],
+        op: None,
+    })
+}
+
+pub fn disassemble(variant: Variant, data: &[u8], base: u16) -> Vec<Instruction> {
+    let read = |addr: u16| data.get(addr.wrapping_sub(base) as usize).copied();
+    let mut result = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let instruction = decode(variant, read, base.wrapping_add(offset as u16)).unwrap();
+        offset += instruction.bytes.len();
+        result.push(instruction);
+    }
+    result
+}
+
+// entries から JMP/JSR/分岐を辿って、実行されうる命令だけを集める
+pub fn disassemble_recursive<F>(
+    variant: Variant,
+    read: F,
+    entries: &[u16],
+) -> BTreeMap<u16, Instruction>
+where
+    F: Fn(u16) -> Option<u8>,
+{
+    let mut result = BTreeMap::new();
+    let mut pending: Vec<u16> = entries.to_vec();
+    while let Some(mut addr) = pending.pop() {
+        while !result.contains_key(&addr) {
+            let instruction = match decode(variant, &read, addr) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            if let Some(target) = instruction.target() {
+                pending.push(target);
+            }
+            let ends = instruction.ends_flow();
+            let next = addr.wrapping_add(instruction.size());
+            result.insert(addr, instruction);
+            if ends {
+                break;
+            }
+            addr = next;
+        }
+    }
+    result
+}
+
+// 電源投入直後の CPU から見た PRG ROM ($8000-$FFFF)。
+// 32KiB を超える場合は先頭のバンクを $8000 に、最後のバンクを $C000 に置く
+pub fn prg_byte(rom: &Rom, addr: u16) -> Option<u8> {
+    if addr < 0x8000 || rom.prg_rom.is_empty() {
+        return None;
+    }
+    let len = rom.prg_rom.len();
+    let offset = (addr - 0x8000) as usize;
+    let index = if len <= 0x8000 {
+        offset % len
+    } else if offset < 0x4000 {
+        offset
+    } else {
+        len - 0x8000 + offset
+    };
+    rom.prg_rom.get(index).copied()
+}
+
+pub fn vectors(rom: &Rom) -> Vec<(&'static str, u16)> {
+    let read = |addr: u16| {
+        let lo = prg_byte(rom, addr).unwrap_or(0);
+        let hi = prg_byte(rom, addr + 1).unwrap_or(0);
+        u16::from_le_bytes([lo, hi])
+    };
+    vec![
+        ("NMI", read(0xFFFA)),
+        ("RESET", read(0xFFFC)),
+        ("IRQ", read(0xFFFE)),
+    ]
+}
+
+pub fn disassemble_rom(rom: &Rom) -> BTreeMap<u16, Instruction> {
+    let entries: Vec<u16> = vectors(rom).iter().map(|(_, addr)| *addr).collect();
+    disassemble_recursive(Variant::Ricoh2A03, |addr| prg_byte(rom, addr), &entries)
+}
+
+// 飛び先になっている命令には L1234: のラベルを付ける
+pub fn listing<'a, I>(instructions: I, labels: &[(&str, u16)]) -> String
+where
+    I: IntoIterator<Item = &'a Instruction>,
+{
+    listing_with_symbols(instructions, labels, |_| None)
+}
+
+// symbol が名前を返すアドレスは L1234 の代わりにその名前を使い、オペランドも名前にする
+pub fn listing_with_symbols<'a, I, F>(instructions: I, labels: &[(&str, u16)], symbol: F) -> String
+where
+    I: IntoIterator<Item = &'a Instruction>,
+    F: Fn(u16) -> Option<String>,
+{
+    let instructions: Vec<&Instruction> = instructions.into_iter().collect();
+    let targets: BTreeSet<u16> = instructions.iter().filter_map(|i| i.target()).collect();
+    let mut result = String::new();
+    let mut next: Option<u16> = None;
+    for instruction in instructions {
+        if next.is_some() && next != Some(instruction.addr) {
+            result.push('\n');
+        }
+        for (name, addr) in labels {
+            if *addr == instruction.addr {
+                result += &format!("{}:\n", name);
+            }
+        }
+        if let Some(name) = symbol(instruction.addr) {
+            result += &format!("{}:\n", name);
+        } else if targets.contains(&instruction.addr) {
+            result += &format!("L{:04X}:\n", instruction.addr);
+        }
+        result += &format!(
+            "{}\n",
+            instruction.line(&instruction.asm_with_labels(&symbol))
+        );
+        next = Some(instruction.addr.wrapping_add(instruction.size()));
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nrom(prg: &[(u16, &[u8])]) -> Rom {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg_rom = vec![0xFF; 0x4000];
+        for (addr, data) in prg {
+            let offset = (*addr as usize - 0x8000) % 0x4000;
+            prg_rom[offset..offset + data.len()].copy_from_slice(data);
+        }
+        raw.extend(prg_rom);
+        raw.extend(vec![0; 0x2000]);
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_disassemble_slice() {
+        let data = [
+            0xA9, 0x01, 0x8D, 0x00, 0x02, 0xA7, 0x10, 0xCA, 0x9B, 0xD0, 0xF5, 0x4C,
+        ];
+        let lines: Vec<String> = disassemble(Variant::Ricoh2A03, &data, 0x0600)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0600  A9 01     LDA #$01",
+                "0602  8D 00 02  STA $0200",
+                "0605  A7 10    *LAX $10",
+                "0607  CA        DEX",
+                "0608  9B        .byte $9B",
+                "0609  D0 F5     BNE $0600",
+                // 途中で切れている命令
+                "060B  4C        .byte $4C",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_65c02() {
+        // BRA / STZ $10 / PHX / LDA ($10)
+        let data = [0x80, 0x02, 0x64, 0x10, 0xDA, 0xB2, 0x10];
+        let lines = |variant| -> Vec<String> {
+            disassemble(variant, &data, 0x0600)
+                .iter()
+                .map(|i| i.to_string())
+                .collect()
+        };
+        assert_eq!(
+            lines(Variant::Cmos65C02),
+            vec![
+                "0600  80 02     BRA $0604",
+                "0602  64 10     STZ $10",
+                "0604  DA        PHX",
+                "0605  B2 10     LDA ($10)",
+            ]
+        );
+        // NMOS では非公式の NOP と JAM
+        assert_eq!(
+            lines(Variant::Ricoh2A03),
+            vec![
+                "0600  80 02    *NOP #$02",
+                "0602  64 10    *NOP $10",
+                "0604  DA       *NOP",
+                "0605  B2       *JAM",
+                "0606  10        .byte $10",
+            ]
+        );
+
+        // BRA の次 ($0602) には続かない
+        let code = disassemble_recursive(
+            Variant::Cmos65C02,
+            |addr| data.get(addr.wrapping_sub(0x0600) as usize).copied(),
+            &[0x0600],
+        );
+        assert_eq!(
+            code.keys().copied().collect::<Vec<u16>>(),
+            [0x0600, 0x0604, 0x0605]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_rom_follows_flow() {
+        let rom = nrom(&[
+            (
+                0x8000,
+                &[
+                    0x20, 0x10, 0x80, // JSR $8010
+                    0xF0, 0x03, // BEQ $8008
+                    0x4C, 0x00, 0x80, // JMP $8000 (fall through of BEQ)
+                ],
+            ),
+            (0x8008, &[0x40]),                   // RTI
+            (0x8010, &[0xE8, 0x60, 0x12, 0x34]), // INX, RTS, (data)
+            (0xFFFA, &[0x08, 0x80, 0x00, 0x80, 0x08, 0x80]),
+        ]);
+        let code = disassemble_rom(&rom);
+        let addrs: Vec<u16> = code.keys().copied().collect();
+        assert_eq!(addrs, vec![0x8000, 0x8003, 0x8005, 0x8008, 0x8010, 0x8011]);
+
+        let text = listing(code.values(), &vectors(&rom));
+        assert!(text.starts_with("RESET:\nL8000:\n8000  20 10 80  JSR $8010\n"));
+        assert!(text.contains(
+            "8005  4C 00 80  JMP $8000\nNMI:\nIRQ:\nL8008:\n8008  40        RTI\n\nL8010:\n"
+        ));
+    }
+
+    #[test]
+    fn test_listing_with_symbols() {
+        // JSR sub / STA $10 / JMP $8000 / sub: RTS
+        let data = [0x20, 0x08, 0x80, 0x85, 0x10, 0x4C, 0x00, 0x80, 0x60];
+        let symbol = |addr: u16| match addr {
+            0x8008 => Some("sub".to_string()),
+            0x0010 => Some("counter".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            listing_with_symbols(&disassemble(Variant::Ricoh2A03, &data, 0x8000), &[], symbol),
+            "L8000:\n\
+             8000  20 08 80  JSR sub\n\
+             8003  85 10     STA counter\n\
+             8005  4C 00 80  JMP $8000\n\
+             sub:\n\
+             8008  60        RTS\n"
+        );
+    }
+}
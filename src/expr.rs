@@ -0,0 +1,377 @@
+// デバッガのブレークポイント/ウォッチポイントの条件式
+//
+//   SP > $F0 && [$0300] == 1
+//   word[$00] >= $8000 || (P & $80) != 0
+//   scanline == 241 && hits > 3
+//
+// 値はすべて整数で、比較や論理演算は 0/1 になる。演算子の優先順位は C と同じ
+//   レジスタ: A X Y P SP PC
+//   フラグ:   N V D I Z C (0 か 1)
+//   メモリ:   [addr] (1 バイト)、word[addr] (リトルエンディアンの 2 バイト)。副作用なしで読む
+//   PPU:      scanline dot (PPU がなければ 0)、frame (frame_complete の回数)
+//   その他:   hits (そのブレークポイント/ウォッチポイントに来た回数、今回を含む)、cycles
+// 数は $C000 / 0xC000 / 49152 / %1010
+
+use std::fmt;
+
+use crate::bus::Mem;
+use crate::cpu::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    Pc,
+    // P のビット
+    Flag(u8),
+    Scanline,
+    Dot,
+    Frame,
+    Hits,
+    Cycles,
+}
+
+impl Var {
+    fn from_name(name: &str) -> Option<Var> {
+        let var = match name.to_ascii_lowercase().as_str() {
+            "a" => Var::A,
+            "x" => Var::X,
+            "y" => Var::Y,
+            "p" => Var::P,
+            "sp" => Var::Sp,
+            "pc" => Var::Pc,
+            "c" => Var::Flag(0),
+            "z" => Var::Flag(1),
+            "i" => Var::Flag(2),
+            "d" => Var::Flag(3),
+            "v" => Var::Flag(6),
+            "n" => Var::Flag(7),
+            "scanline" => Var::Scanline,
+            "dot" => Var::Dot,
+            "frame" => Var::Frame,
+            "hits" => Var::Hits,
+            "cycles" => Var::Cycles,
+            _ => return None,
+        };
+        Some(var)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(i64),
+    Var(Var),
+    Byte(Box<Node>),
+    Word(Box<Node>),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// 長いものから順に探す
+const OPERATORS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+// 二項演算子の優先順位 (大きいほど強い)
+const BINARY: [(&str, u8); 18] = [
+    ("||", 1),
+    ("&&", 2),
+    ("|", 3),
+    ("^", 4),
+    ("&", 5),
+    ("==", 6),
+    ("!=", 6),
+    ("<", 7),
+    ("<=", 7),
+    (">", 7),
+    (">=", 7),
+    ("<<", 8),
+    (">>", 8),
+    ("+", 9),
+    ("-", 9),
+    ("*", 10),
+    ("/", 10),
+    ("%", 10),
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        // % は値の後なら剰余、そうでなければ 2 進数
+        let after_value = matches!(
+            tokens.last(),
+            Some(Token::Number(_))
+                | Some(Token::Ident(_))
+                | Some(Token::Op(")"))
+                | Some(Token::Op("]"))
+        );
+        let binary = c == '%' && !after_value;
+        let (token, len) = if c == '$' || binary {
+            let radix = if c == '$' { 16 } else { 2 };
+            let digits = rest[1..]
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(rest.len() - 1);
+            let value = i64::from_str_radix(&rest[1..1 + digits], radix)
+                .map_err(|_| format!("invalid number: {}", rest))?;
+            (Token::Number(value), 1 + digits)
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let value = match word.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => word.parse(),
+            };
+            let value = value.map_err(|_| format!("invalid number: {}", word))?;
+            (Token::Number(value), len)
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            (Token::Ident(rest[..len].to_string()), len)
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or(format!("unexpected character: {}", c))?;
+            (Token::Op(op), op.len())
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(found)) if found == op => Ok(()),
+            Some(token) => Err(format!("expected {} but found {:?}", op, token)),
+            None => Err(format!("expected {}", op)),
+        }
+    }
+
+    // 優先順位が min 以上の二項演算子だけをつなげる
+    fn binary(&mut self, min: u8) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let (op, precedence) = match BINARY.iter().find(|(name, _)| name == op) {
+                Some(&(op, precedence)) if precedence >= min => (op, precedence),
+                _ => break,
+            };
+            self.pos += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Node::Number(value)),
+            Some(Token::Op("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Op("[")) => {
+                let node = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Byte(Box::new(node)))
+            }
+            Some(Token::Op(op)) if matches!(op, "!" | "-" | "~") => {
+                Ok(Node::Unary(op, Box::new(self.unary()?)))
+            }
+            Some(Token::Ident(name)) if name.eq_ignore_ascii_case("word") => {
+                self.expect("[")?;
+                let node = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Word(Box::new(node)))
+            }
+            Some(Token::Ident(name)) => Var::from_name(&name)
+                .map(Node::Var)
+                .ok_or(format!("unknown name: {}", name)),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    text: String,
+    node: Node,
+}
+
+impl std::str::FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let node = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?}", token));
+        }
+        Ok(Expr {
+            text: s.trim().to_string(),
+            node,
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Expr {
+    pub fn eval<B: Mem>(&self, cpu: &CPU<B>, hits: u64) -> i64 {
+        eval(&self.node, cpu, hits)
+    }
+
+    // 0 以外なら成り立つ
+    pub fn is_true<B: Mem>(&self, cpu: &CPU<B>, hits: u64) -> bool {
+        self.eval(cpu, hits) != 0
+    }
+}
+
+fn eval<B: Mem>(node: &Node, cpu: &CPU<B>, hits: u64) -> i64 {
+    let address = |node: &Node| eval(node, cpu, hits) as u16;
+    match node {
+        Node::Number(value) => *value,
+        Node::Var(var) => match var {
+            Var::A => cpu.register_a as i64,
+            Var::X => cpu.register_x as i64,
+            Var::Y => cpu.register_y as i64,
+            Var::P => cpu.status as i64,
+            Var::Sp => cpu.stack_pointer as i64,
+            Var::Pc => cpu.program_counter as i64,
+            Var::Flag(bit) => (cpu.status >> bit & 1) as i64,
+            Var::Scanline => cpu.bus.ppu_position().map_or(0, |(line, _)| line as i64),
+            Var::Dot => cpu.bus.ppu_position().map_or(0, |(_, dot)| dot as i64),
+            Var::Frame => cpu.frames as i64,
+            Var::Hits => hits as i64,
+            Var::Cycles => cpu.cycles as i64,
+        },
+        Node::Byte(addr) => cpu.peek(address(addr)) as i64,
+        Node::Word(addr) => cpu.peek_u16(address(addr)) as i64,
+        Node::Unary(op, value) => {
+            let value = eval(value, cpu, hits);
+            match *op {
+                "!" => (value == 0) as i64,
+                "-" => value.wrapping_neg(),
+                _ => !value,
+            }
+        }
+        Node::Binary(op, left, right) => {
+            let left = eval(left, cpu, hits);
+            // && と || は右辺を必要なときだけ計算する
+            match *op {
+                "&&" => return (left != 0 && eval(right, cpu, hits) != 0) as i64,
+                "||" => return (left != 0 || eval(right, cpu, hits) != 0) as i64,
+                _ => {}
+            }
+            let right = eval(right, cpu, hits);
+            match *op {
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                "<=" => (left <= right) as i64,
+                ">" => (left > right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                // 0 で割ったら 0
+                "/" => left.checked_div(right).unwrap_or(0),
+                _ => left.checked_rem(right).unwrap_or(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::FlatBus;
+
+    fn eval_str(text: &str, cpu: &CPU<FlatBus>) -> i64 {
+        text.parse::<Expr>().unwrap().eval(cpu, 3)
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut bus = FlatBus::new();
+        bus.load(0x0000, &[0x34, 0x12]);
+        bus.load(0x0300, &[0x01]);
+        let mut cpu = CPU::new(bus);
+        cpu.register_a = 5;
+        cpu.stack_pointer = 0xF8;
+        cpu.status = 0x81;
+
+        assert_eq!(eval_str("1 + 2 * 3", &cpu), 7);
+        assert_eq!(eval_str("(1 + 2) * 3", &cpu), 9);
+        assert_eq!(eval_str("$10 | %0101 << 1", &cpu), 0x1A);
+        assert_eq!(eval_str("0x10 - 20 / 0", &cpu), 0x10);
+        assert_eq!(eval_str("A%3 + %11", &cpu), 5);
+        assert_eq!(eval_str("-a + ~0", &cpu), -6);
+        assert_eq!(eval_str("SP > $F0 && [$0300] == 1", &cpu), 1);
+        assert_eq!(eval_str("word[$00]", &cpu), 0x1234);
+        assert_eq!(eval_str("[0x300 + x]", &cpu), 1);
+        assert_eq!(eval_str("N && C && !Z", &cpu), 1);
+        assert_eq!(eval_str("(P & $80) != 0 || [$FFFF]", &cpu), 1);
+        assert_eq!(
+            eval_str("hits >= 3 && frame == 0 && scanline == 0", &cpu),
+            1
+        );
+        assert_eq!(eval_str("PC", &cpu), 0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("A ==".parse::<Expr>().is_err());
+        assert!("(A".parse::<Expr>().is_err());
+        assert!("foo > 1".parse::<Expr>().is_err());
+        assert!("A B".parse::<Expr>().is_err());
+        assert!("A # 1".parse::<Expr>().is_err());
+        assert_eq!(" SP<$10 ".parse::<Expr>().unwrap().to_string(), "SP<$10");
+    }
+}
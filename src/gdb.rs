@@ -0,0 +1,598 @@
+// GDB のリモートプロトコル (の 6502 で使う分) で CPU を外のデバッガから操作する
+//
+//   $パケット#チェックサム  (チェックサムは中身のバイトの和の下位 8 ビット、16 進 2 桁)
+//
+// レジスタは A, X, Y, P, SP (各 1 バイト), PC (2 バイト、リトルエンディアン) の順。
+// ブレークポイントは cpu.breakpoints、ウォッチポイントは Debugger のものを使う
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::bus::Mem;
+use crate::cpu::CPU;
+use crate::debugger::{Debugger, StopReason, WatchKind, Watchpoint};
+
+// continue 中にこの命令数ごとに中断 (Ctrl-C) を見る
+const CONTINUE_CHUNK: usize = 10_000;
+const INTERRUPT: u8 = 0x03;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.6502.core">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8" regnum="1"/>
+    <reg name="y" bitsize="8" regnum="2"/>
+    <reg name="p" bitsize="8" regnum="3"/>
+    <reg name="sp" bitsize="8" regnum="4"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="5"/>
+  </feature>
+</target>
+"#;
+
+// チェックサムを付け、$ # } * をエスケープする
+pub fn encode(data: &str) -> Vec<u8> {
+    let mut body = vec![];
+    for &b in data.as_bytes() {
+        if matches!(b, b'$' | b'#' | b'}' | b'*') {
+            body.push(b'}');
+            body.push(b ^ 0x20);
+        } else {
+            body.push(b);
+        }
+    }
+    let sum = body.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    let mut packet = vec![b'$'];
+    packet.extend(&body);
+    packet.extend(format!("#{:02x}", sum).as_bytes());
+    packet
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s, 16).map_err(|_| format!("bad number {}", s))
+}
+
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(digits) if digits.len() == 2 => u8::from_str_radix(digits, 16).ok(),
+            _ => None,
+        })
+        .map(|byte| byte.ok_or(format!("bad hex {}", s)))
+        .collect()
+}
+
+// "addr,len" や "addr,kind"
+fn parse_pair(s: &str) -> Result<(u16, u16), String> {
+    let (a, b) = s.split_once(',').ok_or(format!("bad arguments {}", s))?;
+    Ok((parse_hex(a)?, parse_hex(b)?))
+}
+
+fn registers<B: Mem>(cpu: &CPU<B>) -> Vec<u8> {
+    let pc = cpu.program_counter.to_le_bytes();
+    vec![
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer,
+        pc[0],
+        pc[1],
+    ]
+}
+
+fn set_register<B: Mem>(cpu: &mut CPU<B>, n: usize, bytes: &[u8]) -> Result<(), String> {
+    let byte = *bytes.first().ok_or("no value")?;
+    match n {
+        0 => cpu.register_a = byte,
+        1 => cpu.register_x = byte,
+        2 => cpu.register_y = byte,
+        3 => cpu.status = byte,
+        4 => cpu.stack_pointer = byte,
+        5 if bytes.len() >= 2 => cpu.program_counter = u16::from_le_bytes([bytes[0], bytes[1]]),
+        _ => return Err(format!("bad register {}", n)),
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct GdbStub {
+    pub debugger: Debugger,
+    // QStartNoAckMode の後は + を返さない
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        GdbStub {
+            debugger: Debugger::new(),
+            no_ack: false,
+        }
+    }
+
+    // パケット 1 つを処理して返事を返す。None なら接続を終える。
+    // interrupted は continue 中に呼ばれ、true なら止める (Ctrl-C)
+    pub fn handle<B: Mem, F>(
+        &mut self,
+        cpu: &mut CPU<B>,
+        packet: &str,
+        interrupted: F,
+    ) -> Option<String>
+    where
+        F: FnMut() -> bool,
+    {
+        self.command(cpu, packet, interrupted)
+            .unwrap_or_else(|_| Some("E01".to_string()))
+    }
+
+    fn command<B: Mem, F>(
+        &mut self,
+        cpu: &mut CPU<B>,
+        packet: &str,
+        mut interrupted: F,
+    ) -> Result<Option<String>, String>
+    where
+        F: FnMut() -> bool,
+    {
+        let (kind, args) = match packet.char_indices().nth(1) {
+            Some((i, _)) => packet.split_at(i),
+            None => (packet, ""),
+        };
+        let reply = match kind {
+            "?" => "S05".to_string(),
+            "g" => hex(&registers(cpu)),
+            "G" => {
+                let bytes = parse_bytes(args)?;
+                for (n, range) in [0..1, 1..2, 2..3, 3..4, 4..5, 5..7].into_iter().enumerate() {
+                    set_register(cpu, n, bytes.get(range).ok_or("too short")?)?;
+                }
+                "OK".to_string()
+            }
+            "p" => {
+                let bytes = registers(cpu);
+                match parse_hex(args)? {
+                    n @ 0..=4 => hex(&bytes[n as usize..n as usize + 1]),
+                    5 => hex(&bytes[5..7]),
+                    n => return Err(format!("bad register {}", n)),
+                }
+            }
+            "P" => {
+                let (n, value) = args.split_once('=').ok_or("bad P")?;
+                set_register(cpu, parse_hex(n)? as usize, &parse_bytes(value)?)?;
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, len) = parse_pair(args)?;
+                let bytes: Vec<u8> = (0..len).map(|i| cpu.peek(addr.wrapping_add(i))).collect();
+                hex(&bytes)
+            }
+            "M" => {
+                let (range, data) = args.split_once(':').ok_or("bad M")?;
+                let (addr, len) = parse_pair(range)?;
+                let bytes = parse_bytes(data)?;
+                if bytes.len() != len as usize {
+                    return Err("length mismatch".to_string());
+                }
+                // カートリッジの ROM には書けない
+                if (0..len).any(|i| addr.wrapping_add(i) >= 0x8000) {
+                    return Err("ROM is read only".to_string());
+                }
+                for (i, b) in bytes.iter().enumerate() {
+                    cpu.bus.mem_write(addr.wrapping_add(i as u16), *b);
+                }
+                "OK".to_string()
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    cpu.program_counter = parse_hex(args)?;
+                }
+                let reason = if kind == "s" {
+                    self.debugger.step_into(cpu)
+                } else {
+                    match self.continue_(cpu, &mut interrupted) {
+                        Some(reason) => reason,
+                        None => return Ok(Some("S02".to_string())),
+                    }
+                };
+                stop_reply(&reason)
+            }
+            "Z" | "z" => {
+                let (point, rest) = args.split_once(',').ok_or("bad Z")?;
+                let (addr, len) = parse_pair(rest)?;
+                let insert = kind == "Z";
+                let watch = match point {
+                    "0" | "1" => {
+                        if insert {
+                            cpu.breakpoints.insert(addr);
+                        } else {
+                            cpu.breakpoints.remove(&addr);
+                            self.debugger.conditions.remove(&addr);
+                            self.debugger.hits.remove(&addr);
+                        }
+                        None
+                    }
+                    "2" => Some(WatchKind::Write),
+                    "3" => Some(WatchKind::Read),
+                    "4" => Some(WatchKind::ReadWrite),
+                    _ => return Ok(Some(String::new())),
+                };
+                if let Some(kind) = watch {
+                    let end = addr.wrapping_add(len.max(1) - 1);
+                    if insert {
+                        self.debugger
+                            .watchpoints
+                            .push(Watchpoint::new(addr, end, kind));
+                    } else {
+                        self.debugger
+                            .watchpoints
+                            .retain(|w| (w.start, w.end, w.kind) != (addr, end, kind));
+                    }
+                }
+                "OK".to_string()
+            }
+            "k" => return Ok(None),
+            "D" => {
+                cpu.breakpoints.clear();
+                self.debugger.conditions.clear();
+                self.debugger.hits.clear();
+                self.debugger.watchpoints.clear();
+                return Ok(None);
+            }
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            // 知らないパケットには空を返す
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            // offset,length で切り出す。m なら続きがあり、l なら最後
+            let (offset, length) = match parse_pair(args) {
+                Ok((offset, length)) => (offset as usize, length as usize),
+                Err(_) => return "E01".to_string(),
+            };
+            let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+            return if rest.len() > length {
+                format!("m{}", &rest[..length])
+            } else {
+                format!("l{}", rest)
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // 止まれば理由を、中断されたら None を返す
+    fn continue_<B: Mem, F>(&mut self, cpu: &mut CPU<B>, interrupted: &mut F) -> Option<StopReason>
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            let mut count = 0;
+            let reason = self.debugger.resume(cpu, |_, _| {
+                count += 1;
+                count >= CONTINUE_CHUNK
+            });
+            if reason != StopReason::Step {
+                return Some(reason);
+            }
+            // 区切りがブレークポイントの上なら、そこで止まったことにする
+            // (resume は最初の命令のブレークポイントを無視するので)
+            if cpu.breakpoints.contains(&cpu.program_counter) {
+                return Some(StopReason::Breakpoint(cpu.program_counter));
+            }
+            if interrupted() {
+                return None;
+            }
+        }
+    }
+
+    // 接続が切れるか k/D が来るまでパケットを処理する
+    pub fn serve<B: Mem>(&mut self, cpu: &mut CPU<B>, mut stream: TcpStream) -> Result<(), String> {
+        let error = |e: std::io::Error| e.to_string();
+        while let Some(packet) = read_packet(&mut stream).map_err(error)? {
+            if !self.no_ack {
+                stream.write_all(b"+").map_err(error)?;
+            }
+            let poll = stream.try_clone().map_err(error)?;
+            let reply = self.handle(cpu, &packet, || interrupted(&poll));
+            match reply {
+                Some(reply) => stream.write_all(&encode(&reply)).map_err(error)?,
+                None => {
+                    let _ = stream.write_all(&encode("OK"));
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// 届いていれば 1 バイト覗いて Ctrl-C か見る (待たない)
+fn interrupted(mut stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    // Ctrl-C 以外 (次のパケットなど) は読まずに残しておく
+    let mut byte = [0];
+    let result = matches!(stream.peek(&mut byte), Ok(1) if byte[0] == INTERRUPT);
+    if result {
+        let _ = stream.read(&mut byte);
+    }
+    let _ = stream.set_nonblocking(false);
+    result
+}
+
+// $...#xx を 1 つ読む。ack (+/-) や止まっているときの Ctrl-C は読み飛ばす。
+// 接続が閉じたら None
+pub fn read_packet<R: Read>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] != b'$' {
+            continue;
+        }
+        let mut body = vec![];
+        let mut escaped = false;
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'#' if !escaped => break,
+                b'}' if !escaped => escaped = true,
+                b if escaped => {
+                    body.push(b ^ 0x20);
+                    escaped = false;
+                }
+                b => body.push(b),
+            }
+        }
+        // チェックサムは TCP なので確かめずに読み捨てる
+        let mut checksum = [0; 2];
+        reader.read_exact(&mut checksum)?;
+        return Ok(Some(String::from_utf8_lossy(&body).to_string()));
+    }
+}
+
+// 127.0.0.1:port で 1 つだけ接続を待って、切れるまで相手をする
+pub fn listen<B: Mem>(stub: &mut GdbStub, cpu: &mut CPU<B>, port: u16) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+    stub.serve(cpu, stream)
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint { addr, write } => {
+            let kind = if *write { "watch" } else { "rwatch" };
+            format!("T05{}:{:04x};", kind, addr)
+        }
+        // JAM やフォルトは SIGILL
+        StopReason::Halted(_) | StopReason::Fault(_) => "S04".to_string(),
+        StopReason::Step | StopReason::Breakpoint(_) | StopReason::Brk(_) => "S05".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::FlatBus;
+
+    fn gdb_cpu() -> CPU<FlatBus> {
+        let mut bus = FlatBus::new();
+        assemble(
+            "
+                .org $8000
+            start:
+                LDX #$05
+                INX
+                STX $10
+                JMP start
+            ",
+        )
+        .unwrap()
+        .load(&mut bus);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x8000;
+        cpu
+    }
+
+    fn send(stub: &mut GdbStub, cpu: &mut CPU<FlatBus>, packet: &str) -> String {
+        stub.handle(cpu, packet, || false).unwrap()
+    }
+
+    #[test]
+    fn test_packets() {
+        assert_eq!(encode("OK"), b"$OK#9a");
+        assert_eq!(encode("a#b"), b"$a}\x03b#43");
+        let mut input: &[u8] = b"+$m8000,2#b8\x03$a}\x03b#43";
+        assert_eq!(
+            read_packet(&mut input).unwrap(),
+            Some("m8000,2".to_string())
+        );
+        assert_eq!(read_packet(&mut input).unwrap(), Some("a#b".to_string()));
+        assert_eq!(read_packet(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = GdbStub::new();
+        let mut cpu = gdb_cpu();
+        assert_eq!(send(&mut stub, &mut cpu, "g"), "00000024fd0080");
+        assert_eq!(send(&mut stub, &mut cpu, "P0=42"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "P5=0280"), "OK");
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(send(&mut stub, &mut cpu, "p5"), "0280");
+        assert_eq!(send(&mut stub, &mut cpu, "G0102032404f000"), "OK");
+        assert_eq!(
+            (cpu.register_y, cpu.stack_pointer, cpu.program_counter),
+            (0x03, 0x04, 0xF0)
+        );
+
+        assert_eq!(send(&mut stub, &mut cpu, "m8000,3"), "a205e8");
+        assert_eq!(send(&mut stub, &mut cpu, "M0200,2:beef"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "m0200,2"), "beef");
+        assert_eq!(send(&mut stub, &mut cpu, "M8000,1:00"), "E01");
+        assert_eq!(send(&mut stub, &mut cpu, "vMustReplyEmpty"), "");
+        assert!(
+            send(&mut stub, &mut cpu, "qXfer:features:read:target.xml:0,400").starts_with("l<?xml")
+        );
+    }
+
+    #[test]
+    fn test_write_rom_over_bus() {
+        use crate::bus::Bus;
+        use crate::cartridge::test::test_rom;
+
+        let mut stub = GdbStub::new();
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        let rom = cpu.peek(0x8000);
+        assert_eq!(
+            stub.handle(&mut cpu, "M8000,1:00", || false),
+            Some("E01".to_string())
+        );
+        // 一部でも ROM にかかっていたら何も書かない
+        assert_eq!(
+            stub.handle(&mut cpu, "M7fff,2:1234", || false),
+            Some("E01".to_string())
+        );
+        assert_eq!(cpu.peek(0x8000), rom);
+        assert_eq!(cpu.peek(0x7FFF), 0x00);
+        assert_eq!(
+            stub.handle(&mut cpu, "M7fff,1:12", || false),
+            Some("OK".to_string())
+        );
+        assert_eq!(cpu.peek(0x7FFF), 0x12);
+    }
+
+    #[test]
+    fn test_step_breakpoints_and_watchpoints() {
+        let mut stub = GdbStub::new();
+        let mut cpu = gdb_cpu();
+        assert_eq!(send(&mut stub, &mut cpu, "s"), "S05");
+        assert_eq!(cpu.program_counter, 0x8002);
+
+        assert_eq!(send(&mut stub, &mut cpu, "Z2,10,1"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "T05watch:0010;");
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(send(&mut stub, &mut cpu, "z2,10,1"), "OK");
+
+        assert_eq!(send(&mut stub, &mut cpu, "Z0,8002,1"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "S05");
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(send(&mut stub, &mut cpu, "z0,8002,1"), "OK");
+
+        // 消したブレークポイントの条件とヒット数も残さない
+        stub.debugger
+            .conditions
+            .insert(0x8002, "X == 5".parse().unwrap());
+        assert_eq!(send(&mut stub, &mut cpu, "Z0,8002,1"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "S05");
+        assert_eq!(stub.debugger.hits[&0x8002], 1);
+        assert_eq!(send(&mut stub, &mut cpu, "z0,8002,1"), "OK");
+        assert!(stub.debugger.conditions.is_empty());
+        assert!(stub.debugger.hits.is_empty());
+
+        // ブレークポイントがなければ Ctrl-C まで回り続ける
+        let mut polls = 0;
+        let reply = stub.handle(&mut cpu, "c", || {
+            polls += 1;
+            polls == 3
+        });
+        assert_eq!(reply, Some("S02".to_string()));
+        assert_eq!(stub.handle(&mut cpu, "k", || false), None);
+    }
+
+    #[test]
+    fn test_continue_past_brk() {
+        let mut stub = GdbStub::new();
+        let mut cpu = gdb_cpu();
+        assemble(
+            "
+                .org $8000
+                BRK
+                .byte $00
+                .org $9000
+            handler:
+                NOP
+                NOP
+                .org $FFFE
+                .word handler
+            ",
+        )
+        .unwrap()
+        .load(&mut cpu.bus);
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "S05");
+        assert_eq!(cpu.program_counter, 0x9000);
+        assert_eq!(send(&mut stub, &mut cpu, "s"), "S05");
+        assert_eq!(cpu.program_counter, 0x9001);
+    }
+
+    #[test]
+    fn test_interrupted_keeps_packets() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.write_all(&encode("p0")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!interrupted(&stream));
+        assert_eq!(read_packet(&mut stream).unwrap(), Some("p0".to_string()));
+
+        client.write_all(&[INTERRUPT]).unwrap();
+        client.write_all(&encode("p1")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(interrupted(&stream));
+        assert!(!interrupted(&stream));
+        assert_eq!(read_packet(&mut stream).unwrap(), Some("p1".to_string()));
+    }
+
+    #[test]
+    fn test_serve_over_tcp() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut replies = vec![];
+            for packet in ["p0", "s", "p1"] {
+                stream.write_all(&encode(packet)).unwrap();
+                let mut ack = [0];
+                stream.read_exact(&mut ack).unwrap();
+                replies.push(read_packet(&mut stream).unwrap().unwrap());
+                stream.write_all(b"+").unwrap();
+            }
+            stream.write_all(&encode("D")).unwrap();
+            replies.push(read_packet(&mut stream).unwrap().unwrap());
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut cpu = gdb_cpu();
+        GdbStub::new().serve(&mut cpu, stream).unwrap();
+        assert_eq!(client.join().unwrap(), ["00", "S05", "05", "OK"]);
+    }
+}
@@ -0,0 +1,21 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod assembler;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod cycle_cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod expr;
+pub mod gdb;
+pub mod observer;
+pub mod opscodes;
+pub mod ppu;
+pub mod rom;
+pub mod savestate;
+pub mod source_map;
+pub mod symbols;
+pub mod trace_log;
+pub mod tracediff;
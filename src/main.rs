@@ -1,31 +1,79 @@
-#[macro_use]
-extern crate lazy_static;
+use famicom_project::bus::{Bus, Mem, RamInit};
+use famicom_project::cartridge::test::test_rom;
+use famicom_project::cpu::{StepResult, CPU};
+use famicom_project::cycle_cpu::CycleCpu;
+use famicom_project::debugger::Debugger;
+use famicom_project::gdb::{self, GdbStub};
+use famicom_project::source_map::SourceMap;
+use famicom_project::symbols::SymbolTable;
+use famicom_project::trace_log::{parse_range, TraceLogger, TraceRing, TraceSink};
 
-mod bus;
-mod cartridge;
-mod cpu;
-mod opscodes;
-mod rom;
-
-use crate::cpu::trace;
-
-use self::bus::{Bus, Mem};
-use self::cpu::CPU;
-
-use cartridge::test::test_rom;
 use rand::Rng;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
 use sdl2::EventPump;
 
 fn main() {
     let rom = test_rom();
     let bus = Bus::new(rom);
     let mut cpu = CPU::new(bus);
+    // --variant=nmos / --variant=65c02 で CPU を切り替える (デフォルトは 2A03)
+    // --ram=ff / pattern / random:1234 で電源投入時の RAM を変える (デフォルトは 0)
+    // --symbols=game.dbg / game.nes.0.nl / game.mlb でラベルを読む (何個でも)
+    let mut ram = RamInit::default();
+    let mut symbols = SymbolTable::new();
+    let mut source = SourceMap::new();
+    for arg in std::env::args() {
+        if let Some(name) = arg.strip_prefix("--variant=") {
+            cpu.variant = name.parse().unwrap_or_else(|e: String| panic!("{}", e));
+        }
+        if let Some(name) = arg.strip_prefix("--ram=") {
+            ram = name.parse().unwrap_or_else(|e: String| panic!("{}", e));
+        }
+        if let Some(path) = arg.strip_prefix("--symbols=") {
+            symbols.extend(SymbolTable::load(path).unwrap_or_else(|e| panic!("{}", e)));
+            // cc65 の .dbg なら行情報も読む (デバッガでソースの行単位に実行する)
+            if path.ends_with(".dbg") {
+                source = SourceMap::load(path).unwrap_or_else(|e| panic!("{}", e));
+            }
+        }
+    }
+    let (mut logger, ring) = trace_logger();
+    if let Some(logger) = logger.as_mut() {
+        logger.symbols = symbols.clone();
+    }
+
+    cpu.power_on(ram);
+    // nestest の自動テストモードは $C000 から始める
+    cpu.program_counter = 0xC000;
 
-    cpu.reset();
+    if std::env::args().any(|arg| arg == "--debug") {
+        let mut debugger = Debugger::new();
+        debugger.symbols = symbols;
+        debugger.source = source;
+        let stdin = std::io::stdin();
+        debugger.repl(&mut cpu, stdin.lock(), &mut std::io::stdout());
+        return;
+    }
+
+    // --gdb / --gdb=2345 で 127.0.0.1 に GDB のリモートプロトコルで接続を待つ (デフォルトは 1234)
+    if let Some(arg) = std::env::args().find(|arg| arg.starts_with("--gdb")) {
+        let port = match arg.strip_prefix("--gdb=") {
+            Some(port) => port
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid port: {}", port)),
+            None => 1234,
+        };
+        let mut stub = GdbStub::new();
+        if let Err(e) = gdb::listen(&mut stub, &mut cpu, port) {
+            panic!("gdb: {}", e);
+        }
+        return;
+    }
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -35,41 +83,186 @@ fn main() {
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
+    let event_pump = sdl_context.event_pump().unwrap();
     canvas.set_scale(10.0, 10.0).unwrap();
-
     let creator = canvas.texture_creator();
-    let mut texture = creator
+    let texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
         .unwrap();
 
-    let mut screen_state = [0 as u8; 32 * 3 * 32];
-    let mut rng = rand::thread_rng();
+    if let Some(logger) = logger {
+        cpu.add_observer(Box::new(logger));
+    }
+    let mut frontend = Frontend {
+        canvas,
+        texture,
+        event_pump,
+        screen_state: [0 as u8; 32 * 3 * 32],
+        rng: rand::thread_rng(),
+        slot: 1,
+    };
+
+    // ウィンドウを閉じるか CPU が止まるまで動かす。--cycle ならサイクル単位のコアで動かす。
+    // フロントエンドは命令の境界で呼ぶ (テクスチャが creator を借りているのでオブザーバにはしない)。
+    // 終わったら CPU ごとトレースのロガーを捨てて、ファイルに残りを書き出す
+    let stopped = if std::env::args().any(|arg| arg == "--cycle") {
+        let mut cpu = CycleCpu::new(cpu);
+        loop {
+            if frontend.update(&mut cpu.cpu) {
+                break None;
+            }
+            match cpu.step() {
+                StepResult::Executed { .. } | StepResult::Interrupt { .. } => {}
+                result => break Some(result),
+            }
+        }
+    } else {
+        let mut cpu = cpu;
+        loop {
+            if frontend.update(&mut cpu) {
+                break None;
+            }
+            match cpu.step() {
+                StepResult::Executed { .. } | StepResult::Interrupt { .. } => {}
+                result => break Some(result),
+            }
+        }
+    };
+    if let Some(result) = stopped {
+        eprintln!("CPU stopped: {:?}", result);
+    }
+    // リングバッファは止まったときに最後の部分だけ出す
+    if let Some(ring) = ring {
+        for line in ring.lines() {
+            println!("{}", line);
+        }
+    }
+}
+
+// --trace (標準出力) / --trace=file.log / --trace-ring=1000 でトレースを出す (デフォルトは出さない)
+// --trace-format=nestest / full / ppu,cyc,bank
+// --trace-start=pc:C000 / frame:10, --trace-stop=...
+// --trace-range=8000-BFFF,C123 で PC を絞る
+fn trace_logger() -> (Option<TraceLogger>, Option<TraceRing>) {
+    let fail = |e: String| -> ! { panic!("{}", e) };
+    let mut sink = None;
+    let mut ring = None;
+    for arg in std::env::args() {
+        if arg == "--trace" {
+            sink = Some(TraceSink::Stdout);
+        } else if let Some(path) = arg.strip_prefix("--trace=") {
+            sink = Some(TraceSink::file(path).unwrap_or_else(|e| fail(e)));
+        } else if let Some(size) = arg.strip_prefix("--trace-ring=") {
+            let size = size
+                .parse()
+                .unwrap_or_else(|_| fail(format!("invalid ring size: {}", size)));
+            let buffer = TraceRing::new(size).unwrap_or_else(|e| fail(e));
+            sink = Some(TraceSink::Ring(buffer.clone()));
+            ring = Some(buffer);
+        }
+    }
+    let mut logger = match sink {
+        Some(sink) => TraceLogger::new(sink),
+        None => return (None, None),
+    };
+
+    for arg in std::env::args() {
+        if let Some(format) = arg.strip_prefix("--trace-format=") {
+            logger.format = format.parse().unwrap_or_else(|e| fail(e));
+        } else if let Some(trigger) = arg.strip_prefix("--trace-start=") {
+            logger.start = Some(trigger.parse().unwrap_or_else(|e| fail(e)));
+        } else if let Some(trigger) = arg.strip_prefix("--trace-stop=") {
+            logger.stop = Some(trigger.parse().unwrap_or_else(|e| fail(e)));
+        } else if let Some(ranges) = arg.strip_prefix("--trace-range=") {
+            for range in ranges.split(',') {
+                logger
+                    .ranges
+                    .push(parse_range(range).unwrap_or_else(|e| fail(e)));
+            }
+        }
+    }
+    (Some(logger), ring)
+}
+
+// Snake の入力・乱数・画面
+struct Frontend<'a> {
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    event_pump: EventPump,
+    screen_state: [u8; 32 * 3 * 32],
+    rng: rand::rngs::ThreadRng,
+    slot: u8,
+}
 
-    cpu.run_with_callback(move |cpu| {
-        println!("{}", trace(cpu));
-        handle_user_input(cpu, &mut event_pump);
-        let r: u8 = rng.gen_range(1..16);
+impl<'a> Frontend<'a> {
+    // Esc かウィンドウを閉じたら true
+    fn update(&mut self, cpu: &mut CPU) -> bool {
+        if handle_user_input(cpu, &mut self.event_pump, &mut self.slot) {
+            return true;
+        }
+        let r: u8 = self.rng.gen_range(1..16);
         cpu.mem_write(0xFE, r);
 
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+        if read_screen_state(cpu, &mut self.screen_state) {
+            self.texture
+                .update(None, &self.screen_state, 32 * 3)
+                .unwrap();
+            self.canvas.copy(&self.texture, None, None).unwrap();
+            self.canvas.present();
         }
 
         ::std::thread::sleep(std::time::Duration::new(0, 70_000));
-    });
+        false
+    }
+}
+
+fn state_path(slot: u8) -> String {
+    format!("saves/slot{}.state", slot)
+}
+
+fn quick_save(cpu: &CPU, slot: u8) {
+    let result = std::fs::create_dir_all("saves")
+        .and_then(|_| std::fs::write(state_path(slot), cpu.save_state()));
+    match result {
+        Ok(_) => println!("Saved state to slot {}", slot),
+        Err(e) => println!("Failed to save slot {}: {}", slot, e),
+    }
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+fn quick_load(cpu: &mut CPU, slot: u8) {
+    let result = std::fs::read(state_path(slot))
+        .map_err(|e| e.to_string())
+        .and_then(|data| cpu.load_state(&data));
+    match result {
+        Ok(_) => println!("Loaded state from slot {}", slot),
+        Err(e) => println!("Failed to load slot {}: {}", slot, e),
+    }
+}
+
+fn slot_key(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
+// 終了するなら true
+fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump, slot: &mut u8) -> bool {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
+            } => return true,
             Event::KeyDown {
                 keycode: Some(Keycode::W),
                 ..
@@ -94,9 +287,26 @@ fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
             } => {
                 cpu.mem_write(0xFF, 0x64);
             }
+            // 1-9 でスロット選択、F5 でセーブ、F9 でロード
+            Event::KeyDown {
+                keycode: Some(Keycode::F5),
+                ..
+            } => quick_save(cpu, *slot),
+            Event::KeyDown {
+                keycode: Some(Keycode::F9),
+                ..
+            } => quick_load(cpu, *slot),
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } if slot_key(keycode).is_some() => {
+                *slot = slot_key(keycode).unwrap();
+                println!("Selected slot {}", slot);
+            }
             _ => { /* do nothing */ }
         }
     }
+    false
 }
 
 fn color(byte: u8) -> Color {
@@ -118,7 +328,7 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     let mut update = false;
 
     for i in 0x0200..0x0600 {
-        let color_idx = cpu.mem_read(i as u16);
+        let color_idx = cpu.peek(i as u16);
         let (b1, b2, b3) = color(color_idx).rgb();
         if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
             frame[frame_idx] = b1;
@@ -129,4 +339,4 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
         frame_idx += 3;
     }
     update
-}
\ No newline at end of file
+}
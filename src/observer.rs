@@ -0,0 +1,159 @@
+// CPU の実行を外から見るためのフック
+//
+// トレース、プロファイラ、フロントエンドなどを別々のオブザーバとして CPU に登録する。
+// 使わないフックは実装しなくてよい (デフォルトは何もしない)。
+
+use crate::bus::{Bus, Mem};
+use crate::cpu::{Interrupt, StepResult, CPU};
+
+pub trait Observer<B: Mem = Bus> {
+    // 命令 (か割り込みシーケンス) を実行する直前。ここで CPU を書き換えてもよい
+    fn before_instruction(&mut self, _cpu: &mut CPU<B>) {}
+
+    // step() が結果を返す直前
+    fn after_instruction(&mut self, _cpu: &mut CPU<B>, _result: &StepResult) {}
+
+    // バスアクセス (空読みも含む)。peek は通知しない
+    fn memory_read(&mut self, _addr: u16, _value: u8) {}
+
+    fn memory_write(&mut self, _addr: u16, _value: u8) {}
+
+    // 割り込みシーケンスを実行した (BRK は含まない)
+    fn interrupt(&mut self, _cpu: &mut CPU<B>, _kind: Interrupt) {}
+
+    // CPU::frame_complete で呼ばれる
+    fn frame_complete(&mut self, _cpu: &mut CPU<B>) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::FlatBus;
+    use crate::cycle_cpu::CycleCpu;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // イベントを文字列にして記録する
+    struct Recorder {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.log
+                .borrow_mut()
+                .push(format!("{} {}", self.name, event));
+        }
+    }
+
+    impl Observer<FlatBus> for Recorder {
+        fn before_instruction(&mut self, cpu: &mut CPU<FlatBus>) {
+            self.push(format!("before {:04X}", cpu.program_counter));
+        }
+
+        fn after_instruction(&mut self, cpu: &mut CPU<FlatBus>, result: &StepResult) {
+            if let StepResult::Executed { opcode, .. } = result {
+                self.push(format!("after {:02X} {:04X}", opcode, cpu.program_counter));
+            }
+        }
+
+        fn memory_read(&mut self, addr: u16, value: u8) {
+            self.push(format!("read {:04X} {:02X}", addr, value));
+        }
+
+        fn memory_write(&mut self, addr: u16, value: u8) {
+            self.push(format!("write {:04X} {:02X}", addr, value));
+        }
+
+        fn interrupt(&mut self, cpu: &mut CPU<FlatBus>, kind: Interrupt) {
+            self.push(format!("{:?} {:04X}", kind, cpu.program_counter));
+        }
+
+        fn frame_complete(&mut self, _cpu: &mut CPU<FlatBus>) {
+            self.push("frame".to_string());
+        }
+    }
+
+    fn observed_cpu(program: &[u8], log: &Log) -> CPU<FlatBus> {
+        let mut bus = FlatBus::new();
+        bus.load(0x8000, program);
+        bus.load(0xFFFA, &[0x00, 0x90]);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x8000;
+        cpu.add_observer(Box::new(Recorder {
+            name: "a",
+            log: log.clone(),
+        }));
+        cpu
+    }
+
+    #[test]
+    fn test_observer_hooks() {
+        let log = Rc::new(RefCell::new(vec![]));
+        // STA $10
+        let mut cpu = observed_cpu(&[0x85, 0x10], &log);
+        cpu.register_a = 0x42;
+        cpu.step();
+        cpu.trigger_nmi();
+        cpu.step();
+        cpu.frame_complete();
+
+        let log = log.borrow();
+        assert_eq!(
+            log[..6],
+            [
+                "a before 8000",
+                "a read 8000 85",
+                "a read 8001 10",
+                "a write 0010 42",
+                "a after 85 8002",
+                "a before 8002",
+            ]
+        );
+        assert_eq!(log[log.len() - 2..], ["a Nmi 9000", "a frame"]);
+    }
+
+    #[test]
+    fn test_multiple_observers() {
+        let log = Rc::new(RefCell::new(vec![]));
+        // INX
+        let mut cpu = observed_cpu(&[0xE8], &log);
+        cpu.add_observer(Box::new(Recorder {
+            name: "b",
+            log: log.clone(),
+        }));
+        cpu.step();
+
+        // 登録した順に呼ぶ
+        assert_eq!(
+            log.borrow()[..4],
+            [
+                "a before 8000",
+                "b before 8000",
+                "a read 8000 E8",
+                "b read 8000 E8"
+            ]
+        );
+        assert_eq!(log.borrow()[log.borrow().len() - 1], "b after E8 8001");
+    }
+
+    #[test]
+    fn test_cycle_core_notifies_observers() {
+        let program = [0xA5, 0x10, 0xE6, 0x10, 0xEA];
+        let expected = Rc::new(RefCell::new(vec![]));
+        let mut cpu = observed_cpu(&program, &expected);
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut cpu = CycleCpu::new(observed_cpu(&program, &log));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(*log.borrow(), *expected.borrow());
+    }
+}
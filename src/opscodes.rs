@@ -1,4 +1,6 @@
+use crate::bus::Mem;
 use crate::cpu::AddressingMode;
+use crate::cpu::FaultKind;
 use crate::cpu::OpCode;
 use crate::cpu::CPU;
 
@@ -261,387 +263,490 @@ lazy_static! {
     OpCode::new(0xFC, "*NOP", 3, 4 /* (+ some cycles) */, AddressingMode::Absolute_X),
     OpCode::new(0x8B, "*ANE", 2, 2, AddressingMode::Immediate),
   ];
+
+  // 65C02: NMOS の公式命令 + 追加の命令。残りのオペコードはすべて NOP
+  pub static ref CMOS_OPS_CODES: Vec<OpCode> = {
+    let mut ops: Vec<OpCode> = CPU_OPS_CODES
+      .iter()
+      .filter(|op| !op.name.starts_with('*'))
+      .map(|op| match op.code {
+        // JMP ($xxFF) の修正で 1 サイクル増えた
+        0x6C => OpCode::new(0x6C, "JMP", 3, 6, AddressingMode::Indirect),
+        // シフト命令 (abs,X) はページをまたがなければ 6 サイクル
+        0x1E | 0x3E | 0x5E | 0x7E => OpCode::new(op.code, &op.name, 3, 6 /* (+1 if page crossed) */, AddressingMode::Absolute_X),
+        _ => op.clone(),
+      })
+      .collect();
+    ops.extend(vec![
+      OpCode::new(0x72, "ADC", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0x32, "AND", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0xD2, "CMP", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0x52, "EOR", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0xB2, "LDA", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0x12, "ORA", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0xF2, "SBC", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0x92, "STA", 2, 5, AddressingMode::ZeroPage_Indirect),
+      OpCode::new(0x89, "BIT", 2, 2, AddressingMode::Immediate),
+      OpCode::new(0x34, "BIT", 2, 4, AddressingMode::ZeroPage_X),
+      OpCode::new(0x3C, "BIT", 3, 4 /* (+1 if page crossed) */, AddressingMode::Absolute_X),
+      OpCode::new(0x80, "BRA", 2, 2 /* (+1 always, +2 if to a new page) */, AddressingMode::Relative),
+      OpCode::new(0x3A, "DEC", 1, 2, AddressingMode::Accumulator),
+      OpCode::new(0x1A, "INC", 1, 2, AddressingMode::Accumulator),
+      OpCode::new(0x7C, "JMP", 3, 6, AddressingMode::Absolute_Indirect_X),
+      OpCode::new(0xDA, "PHX", 1, 3, AddressingMode::Implied),
+      OpCode::new(0x5A, "PHY", 1, 3, AddressingMode::Implied),
+      OpCode::new(0xFA, "PLX", 1, 4, AddressingMode::Implied),
+      OpCode::new(0x7A, "PLY", 1, 4, AddressingMode::Implied),
+      OpCode::new(0x64, "STZ", 2, 3, AddressingMode::ZeroPage),
+      OpCode::new(0x74, "STZ", 2, 4, AddressingMode::ZeroPage_X),
+      OpCode::new(0x9C, "STZ", 3, 4, AddressingMode::Absolute),
+      OpCode::new(0x9E, "STZ", 3, 5, AddressingMode::Absolute_X),
+      OpCode::new(0x14, "TRB", 2, 5, AddressingMode::ZeroPage),
+      OpCode::new(0x1C, "TRB", 3, 6, AddressingMode::Absolute),
+      OpCode::new(0x04, "TSB", 2, 5, AddressingMode::ZeroPage),
+      OpCode::new(0x0C, "TSB", 3, 6, AddressingMode::Absolute),
+    ]);
+    for code in 0..=0xFFu8 {
+      if ops.iter().any(|op| op.code == code) {
+        continue;
+      }
+      // Rockwell のビット命令 (x7/xF) と WAI/STP はない石として扱う
+      let nop = match code {
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => OpCode::new(code, "*NOP", 2, 2, AddressingMode::Immediate),
+        0x44 => OpCode::new(code, "*NOP", 2, 3, AddressingMode::ZeroPage),
+        0x54 | 0xD4 | 0xF4 => OpCode::new(code, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        0x5C => OpCode::new(code, "*NOP", 3, 8, AddressingMode::Absolute),
+        0xDC | 0xFC => OpCode::new(code, "*NOP", 3, 4, AddressingMode::Absolute),
+        _ => OpCode::new(code, "*NOP", 1, 1, AddressingMode::Implied),
+      };
+      ops.push(nop);
+    }
+    ops
+  };
 }
 
-pub fn call(cpu: &mut CPU, op: &OpCode) {
+pub fn call<B: Mem>(cpu: &mut CPU<B>, op: &OpCode) {
     match op.name.replace("*", "").as_str() {
         "ADC" => {
             cpu.adc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "AND" => {
             cpu.and(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ASL" => {
             cpu.asl(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BCC" => {
             cpu.bcc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BCS" => {
             cpu.bcs(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BEQ" => {
             cpu.beq(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BIT" => {
             cpu.bit(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BMI" => {
             cpu.bmi(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BNE" => {
             cpu.bne(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BPL" => {
             cpu.bpl(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BRK" => {
             cpu.brk(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BVC" => {
             cpu.bvc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "BVS" => {
             cpu.bvs(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CLC" => {
             cpu.clc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CLD" => {
             cpu.cld(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CLI" => {
             cpu.cli(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CLV" => {
             cpu.clv(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CMP" => {
             cpu.cmp(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CPX" => {
             cpu.cpx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "CPY" => {
             cpu.cpy(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "DEC" => {
             cpu.dec(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "DEX" => {
             cpu.dex(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "DEY" => {
             cpu.dey(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "EOR" => {
             cpu.eor(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "INC" => {
             cpu.inc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "INX" => {
             cpu.inx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "INY" => {
             cpu.iny(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "JMP" => {
             cpu.jmp(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "JSR" => {
             cpu.jsr(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LDA" => {
             cpu.lda(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LDX" => {
             cpu.ldx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LDY" => {
             cpu.ldy(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LSR" => {
             cpu.lsr(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "NOP" => {
             cpu.nop(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ORA" => {
             cpu.ora(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "PHA" => {
             cpu.pha(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "PHP" => {
             cpu.php(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "PLA" => {
             cpu.pla(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "PLP" => {
             cpu.plp(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ROL" => {
             cpu.rol(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ROR" => {
             cpu.ror(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "RTI" => {
             cpu.rti(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "RTS" => {
             cpu.rts(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SBC" => {
             cpu.sbc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SEC" => {
             cpu.sec(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SED" => {
             cpu.sed(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SEI" => {
             cpu.sei(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "STA" => {
             cpu.sta(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "STX" => {
             cpu.stx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "STY" => {
             cpu.sty(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "TAX" => {
             cpu.tax(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "TAY" => {
             cpu.tay(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "TSX" => {
             cpu.tsx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "TXA" => {
             cpu.txa(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "TXS" => {
             cpu.txs(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "TYA" => {
             cpu.tya(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ANC" => {
             cpu.anc(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SAX" => {
             cpu.sax(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ARR" => {
             cpu.arr(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ASR" => {
             cpu.asr(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LXA" => {
             cpu.lxa(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SHA" => {
             cpu.sha(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SBX" => {
             cpu.sbx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "DCP" => {
             cpu.dcp(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ISB" => {
             cpu.isb(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "JAM" => {
             cpu.jam(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LAE" => {
             cpu.lae(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "LAX" => {
             cpu.lax(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "RLA" => {
             cpu.rla(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "RRA" => {
             cpu.rra(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SLO" => {
             cpu.slo(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SRE" => {
             cpu.sre(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SHX" => {
             cpu.shx(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "SHY" => {
             cpu.shy(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
         "ANE" => {
             cpu.ane(&op.addressing_mode);
-            cpu.program_counter += op.bytes - 1
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "BRA" => {
+            cpu.bra(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "PHX" => {
+            cpu.phx(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "PHY" => {
+            cpu.phy(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "PLX" => {
+            cpu.plx(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "PLY" => {
+            cpu.ply(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "STZ" => {
+            cpu.stz(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "TRB" => {
+            cpu.trb(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
+        }
+
+        "TSB" => {
+            cpu.tsb(&op.addressing_mode);
+            cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1)
         }
 
-        _ => {
-            todo!()
+        name => {
+            cpu.raise_fault(
+                FaultKind::Unimplemented(name.to_string()),
+                &op.addressing_mode,
+            );
         }
     }
 }
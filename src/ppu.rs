@@ -0,0 +1,238 @@
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+
+// 最小限の PPU。描画はせず、VBlank のタイミングと NMI だけを作る
+// (テスト ROM が $2002 をポーリングして VBlank を待てるように)
+
+const DOTS_PER_SCANLINE: u32 = 341;
+const SCANLINES_PER_FRAME: u32 = 262;
+const DOTS_PER_FRAME: u32 = DOTS_PER_SCANLINE * SCANLINES_PER_FRAME;
+// スキャンライン 241 の 1 ドット目で VBlank が立ち、プリレンダーラインで下りる
+const VBLANK_START: u32 = 241 * DOTS_PER_SCANLINE + 1;
+const VBLANK_END: u32 = 261 * DOTS_PER_SCANLINE + 1;
+
+const STATUS_VBLANK: u8 = 0b1000_0000;
+const CTRL_GENERATE_NMI: u8 = 0b1000_0000;
+
+pub struct Ppu {
+    // フレーム内のドット
+    dot: u32,
+    ctrl: u8,
+    status: u8,
+    nmi_pending: bool,
+    // VBlank に入ったらフレームの終わりとして CPU に知らせる
+    frame_ready: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            dot: 0,
+            ctrl: 0,
+            status: 0,
+            nmi_pending: false,
+            frame_ready: false,
+        }
+    }
+
+    // CPU の 1 サイクルは PPU の 3 ドット
+    pub fn tick(&mut self, cpu_cycles: u32) {
+        for _ in 0..cpu_cycles * 3 {
+            self.dot += 1;
+            if self.dot == DOTS_PER_FRAME {
+                self.dot = 0;
+            }
+            if self.dot == VBLANK_START {
+                self.status |= STATUS_VBLANK;
+                self.frame_ready = true;
+                if self.ctrl & CTRL_GENERATE_NMI != 0 {
+                    self.nmi_pending = true;
+                }
+            } else if self.dot == VBLANK_END {
+                self.status &= !STATUS_VBLANK;
+            }
+        }
+    }
+
+    // $2000-$2007 (ミラーは畳んだアドレス)
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => {
+                // 読むと VBlank フラグが下りる
+                let value = self.status;
+                self.status &= !STATUS_VBLANK;
+                value
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => self.status,
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        if addr == 0x2000 {
+            // VBlank 中に NMI を有効にすると、すぐに NMI が来る
+            if self.ctrl & CTRL_GENERATE_NMI == 0
+                && data & CTRL_GENERATE_NMI != 0
+                && self.status & STATUS_VBLANK != 0
+            {
+                self.nmi_pending = true;
+            }
+            self.ctrl = data;
+        }
+        // それ以外 (スクロール、VRAM など) は描画しないので捨てる
+    }
+
+    // NMI が来ていたら true を返して下ろす
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi_pending, false)
+    }
+
+    pub fn poll_frame(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
+    }
+
+    // (スキャンライン, ドット)
+    pub fn position(&self) -> (u16, u16) {
+        (
+            (self.dot / DOTS_PER_SCANLINE) as u16,
+            (self.dot % DOTS_PER_SCANLINE) as u16,
+        )
+    }
+
+    // 電源投入: VBlank フラグも消えてフレームの先頭から
+    pub fn power_on(&mut self) {
+        *self = Ppu::new();
+    }
+
+    // リセット: PPUCTRL は 0 になるが、VBlank フラグとフレーム内の位置はそのまま
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.nmi_pending = false;
+    }
+}
+
+impl Snapshot for Ppu {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u32(self.dot);
+        w.write_u8(self.ctrl);
+        w.write_u8(self.status);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.frame_ready);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let dot = r.read_u32()?;
+        // 範囲外だと tick でフレームの先頭に戻らなくなる
+        if dot >= DOTS_PER_FRAME {
+            return Err(format!("PPU dot {} is out of range", dot));
+        }
+        self.dot = dot;
+        self.ctrl = r.read_u8()?;
+        self.status = r.read_u8()?;
+        self.nmi_pending = r.read_bool()?;
+        self.frame_ready = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vblank_flag() {
+        let mut ppu = Ppu::new();
+        ppu.tick((VBLANK_START - 1) / 3);
+        assert_eq!(ppu.peek_register(0x2002) & STATUS_VBLANK, 0);
+        ppu.tick(1);
+        assert_eq!(ppu.read_register(0x2002) & STATUS_VBLANK, STATUS_VBLANK);
+        assert_eq!(ppu.position(), (241, 1));
+        assert!(ppu.poll_frame());
+        assert!(!ppu.poll_frame());
+        // 読むと下りる
+        assert_eq!(ppu.read_register(0x2002) & STATUS_VBLANK, 0);
+        // NMI は有効にしていない
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_nmi() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+        ppu.tick(DOTS_PER_FRAME / 3);
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+        // 次のフレームでもう一度
+        ppu.tick(DOTS_PER_FRAME / 3 + 1);
+        assert!(ppu.poll_nmi());
+
+        // VBlank 中に有効にしたらすぐ
+        let mut ppu = Ppu::new();
+        ppu.tick(VBLANK_START / 3 + 1);
+        assert!(!ppu.poll_nmi());
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+        assert!(ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_frame_ready() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+        ppu.tick(VBLANK_START / 3 + 1);
+        let mut w = StateWriter::new();
+        ppu.save(&mut w);
+        let bytes = w.into_bytes();
+
+        let mut loaded = Ppu::new();
+        loaded.load(&mut StateReader::new(&bytes).unwrap()).unwrap();
+        assert_eq!(loaded.position(), ppu.position());
+        assert!(loaded.poll_frame());
+        assert!(loaded.poll_nmi());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_bad_dot() {
+        let mut w = StateWriter::new();
+        let mut ppu = Ppu::new();
+        ppu.dot = DOTS_PER_FRAME;
+        ppu.save(&mut w);
+        let bytes = w.into_bytes();
+
+        let mut loaded = Ppu::new();
+        assert_eq!(
+            loaded.load(&mut StateReader::new(&bytes).unwrap()),
+            Err(format!("PPU dot {} is out of range", DOTS_PER_FRAME))
+        );
+    }
+
+    #[test]
+    fn test_power_on_and_reset() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+        ppu.tick(VBLANK_START / 3 + 1);
+        let position = ppu.position();
+
+        ppu.reset();
+        assert_eq!(ppu.peek_register(0x2002) & STATUS_VBLANK, STATUS_VBLANK);
+        assert_eq!(ppu.position(), position);
+        assert!(!ppu.poll_nmi());
+        // NMI は無効になっている
+        ppu.tick(DOTS_PER_FRAME / 3);
+        assert!(!ppu.poll_nmi());
+
+        ppu.power_on();
+        assert_eq!(ppu.peek_register(0x2002) & STATUS_VBLANK, 0);
+        assert_eq!(ppu.position(), (0, 0));
+    }
+}
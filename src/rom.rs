@@ -62,4 +62,4 @@ impl Rom {
             screen_mirroring: Mirroring::VERTICAL,
         };
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,329 @@
+// セーブステートのフォーマット
+//
+//   "FCSS" + version(u8)
+//   section*: tag([u8; 4]) + length(u32 LE) + payload
+//
+// セクションはコンポーネントごと (CPU, BUS, 将来は PPU/APU/マッパー) に分ける。
+// レイアウトを変えたら STATE_VERSION を上げること。
+
+const STATE_MAGIC: [u8; 4] = [0x46, 0x43, 0x53, 0x53]; // FCSS
+pub const STATE_VERSION: u8 = 7;
+
+pub trait Snapshot {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut buf = STATE_MAGIC.to_vec();
+        buf.push(STATE_VERSION);
+        StateWriter { buf }
+    }
+
+    pub fn section<T: Snapshot>(&mut self, tag: &[u8; 4], component: &T) {
+        self.buf.extend_from_slice(tag);
+        let len_pos = self.buf.len();
+        self.write_u32(0);
+        let start = self.buf.len();
+        component.save(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self, String> {
+        if buf.len() < 5 || buf[0..4] != STATE_MAGIC {
+            return Err("File is not a save state".to_string());
+        }
+        if buf[4] != STATE_VERSION {
+            return Err(format!(
+                "Save state version {} is not supported (expected {})",
+                buf[4], STATE_VERSION
+            ));
+        }
+        Ok(StateReader { buf, pos: 5 })
+    }
+
+    pub fn section<T: Snapshot>(&mut self, tag: &[u8; 4], component: &mut T) -> Result<(), String> {
+        let found = self.take(4)?;
+        if found != tag {
+            return Err(format!(
+                "Expected section {:?} but found {:?}",
+                String::from_utf8_lossy(tag),
+                String::from_utf8_lossy(found)
+            ));
+        }
+        let len = self.read_u32()? as usize;
+        let start = self.pos;
+        self.take(len)?;
+        // セクションの外 (次のセクション) は読めないようにして渡す
+        let mut section = StateReader {
+            buf: &self.buf[..start + len],
+            pos: start,
+        };
+        component.load(&mut section)?;
+        if section.pos != self.pos {
+            return Err(format!(
+                "Section {:?} has {} bytes but {} were read",
+                String::from_utf8_lossy(tag),
+                len,
+                section.pos - start
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        if self.pos != self.buf.len() {
+            return Err(format!(
+                "{} trailing bytes in save state",
+                self.buf.len() - self.pos
+            ));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err("Save state is truncated".to_string());
+        }
+        let data = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(data)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<(), String> {
+        let data = self.read_bytes()?;
+        if data.len() != dest.len() {
+            return Err(format!(
+                "Expected {} bytes but save state has {}",
+                dest.len(),
+                data.len()
+            ));
+        }
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::{Bus, Mem};
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::{trace, Variant, CPU};
+
+    // $10 から 16bit のフィボナッチ数列を書き込んで BRK で止まる
+    const PROGRAM: [u8; 31] = [
+        0xA2, 0x00, // LDX #$00
+        0xA9, 0x01, // LDA #$01
+        0x95, 0x10, // STA $10,X
+        0xA9, 0x00, // LDA #$00
+        0x95, 0x11, // STA $11,X
+        0x18, // loop: CLC
+        0xB5, 0x10, // LDA $10,X
+        0x75, 0x12, // ADC $12,X
+        0x95, 0x14, // STA $14,X
+        0xB5, 0x11, // LDA $11,X
+        0x75, 0x13, // ADC $13,X
+        0x95, 0x15, // STA $15,X
+        0xE8, // INX
+        0xE8, // INX
+        0xE0, 0x40, // CPX #$40
+        0xD0, 0xED, // BNE loop
+        0x00, // BRK
+        0x00,
+    ];
+
+    fn fib_cpu() -> CPU {
+        let mut bus = Bus::new(test_rom());
+        for (i, v) in PROGRAM.iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *v);
+        }
+        // ループ初回の $12,$13 は 1 にしておく
+        bus.mem_write(0x12, 0x01);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    #[test]
+    fn test_save_and_load_continue_identically() {
+        let mut cpu = fib_cpu();
+        let mut count = 0;
+        let mut saved: Option<Vec<u8>> = None;
+        let mut expected: Vec<String> = vec![];
+        while cpu.peek(cpu.program_counter) != 0x00 {
+            count += 1;
+            if count == 100 {
+                saved = Some(cpu.save_state());
+            }
+            if count >= 100 {
+                expected.push(trace(&cpu));
+            }
+            cpu.step();
+        }
+        let expected_end = cpu.save_state();
+
+        // 全く違う状態の CPU に読み込んで続きを実行する
+        let mut restored = CPU::new(Bus::new(test_rom()));
+        restored.register_a = 0x55;
+        restored.stack_pointer = 0x10;
+        restored.load_state(&saved.unwrap()).unwrap();
+
+        let mut actual: Vec<String> = vec![];
+        while restored.peek(restored.program_counter) != 0x00 {
+            actual.push(trace(&restored));
+            restored.step();
+        }
+
+        assert!(expected.len() > 1);
+        assert_eq!(expected, actual);
+        assert_eq!(expected_end, restored.save_state());
+    }
+
+    #[test]
+    fn test_load_rejects_bad_data() {
+        let mut cpu = fib_cpu();
+        let mut state = cpu.save_state();
+
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        assert!(cpu.load_state(b"NES\x1a").is_err());
+
+        state[4] = STATE_VERSION + 1;
+        assert!(cpu.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_load_restores_variant() {
+        let mut cpu = fib_cpu();
+        cpu.variant = Variant::Cmos65C02;
+        let mut state = cpu.save_state();
+
+        let mut restored = fib_cpu();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.variant, Variant::Cmos65C02);
+
+        // ヘッダ 5 + タグ 4 + 長さ 4 + レジスタなど 18 バイトの次
+        assert_eq!(state[31], Variant::Cmos65C02 as u8);
+        state[31] = 3;
+        let mut other = fib_cpu();
+        assert_eq!(
+            other.load_state(&state),
+            Err("unknown CPU variant 3 in save state".to_string())
+        );
+        assert_eq!(other.variant, Variant::Ricoh2A03);
+    }
+
+    // 読めるだけ読むコンポーネント
+    #[derive(Default)]
+    struct Greedy {
+        read: Vec<u8>,
+    }
+
+    impl Snapshot for Greedy {
+        fn save(&self, w: &mut StateWriter) {
+            w.write_u8(0x42);
+        }
+
+        fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+            while let Ok(value) = r.read_u8() {
+                self.read.push(value);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_section_is_bounded() {
+        let mut w = StateWriter::new();
+        w.section(b"ONE ", &Greedy::default());
+        w.section(b"TWO ", &Greedy::default());
+        let bytes = w.into_bytes();
+
+        let mut r = StateReader::new(&bytes).unwrap();
+        let mut one = Greedy::default();
+        r.section(b"ONE ", &mut one).unwrap();
+        // 次のセクションのタグまでは読めない
+        assert_eq!(one.read, [0x42]);
+        let mut two = Greedy::default();
+        r.section(b"TWO ", &mut two).unwrap();
+        assert_eq!(two.read, [0x42]);
+        r.finish().unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+// cc65 (cl65 -g / ld65 --dbgfile) の .dbg の行情報で PC をソースの行に戻す
+//
+//   file  id=0,name="sample2.c",size=1194,mtime=0x5B1D2C80,mod=0
+//   span  id=3,seg=1,start=27,size=3
+//   line  id=5,file=0,line=42,type=1,span=3+4
+//   scope id=1,name="_NesMain",mod=0,type=scope,size=120,parent=0,span=12
+//   csym  id=2,name="i",scope=1,type=0,sc=auto,offs=-31
+//
+// type=1 の行は C のソース。C の行と、cc65 が出したアセンブラの行が重なるときは C を使う。
+// ローカル変数 (sc=auto) は cc65 のソフトウェアスタック (ゼロページの sp / c_sp) からの位置で持つ
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::bus::Mem;
+use crate::cpu::CPU;
+use crate::symbols::{dbg_fields, parse_dbg_number, segment_bank};
+
+// ローカル変数を表示するときの最大バイト数
+const MAX_LOCAL_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    // C のソース (type=1) か
+    pub c: bool,
+}
+
+impl std::fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// 行やスコープが置かれたアドレス範囲
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: u16,
+    size: u16,
+    bank: Option<usize>,
+}
+
+impl Span {
+    fn contains(&self, addr: u16, bank: Option<usize>) -> bool {
+        let inside = addr >= self.start && (addr - self.start) < self.size;
+        inside && (bank.is_none() || self.bank.is_none() || bank == self.bank)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    // 関数に入ったときのスタックからの位置 (引数は 0 以上、ローカル変数は負)
+    pub offset: i32,
+}
+
+// 今のスタックで見たローカル変数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub addr: u16,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Function {
+    name: String,
+    spans: Vec<Span>,
+    locals: Vec<Local>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    // ソースのファイル名はこのディレクトリからの相対パス
+    dir: PathBuf,
+    lines: Vec<SourceLine>,
+    // (スパン, lines の番号)
+    spans: Vec<(Span, usize)>,
+    functions: Vec<Function>,
+    // cc65 のソフトウェアスタックポインタ (ゼロページ)
+    stack_pointer: Option<u16>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut map = Self::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+        map.dir = Path::new(path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Ok(map)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut files = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        // (ファイル, 行, C か, スパン)
+        let mut lines = vec![];
+        // (スコープ, 名前, スパン)
+        let mut scopes = vec![];
+        // (スコープ, ローカル変数)
+        let mut autos = vec![];
+        let mut stack_pointer = None;
+        for (i, line) in text.lines().enumerate() {
+            let (kind, rest) = match line.split_once(char::is_whitespace) {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let fields = dbg_fields(rest);
+            let get = |key: &str| fields.get(key).map(|v| v.as_str());
+            let number = |key: &str| -> Result<usize, String> {
+                get(key)
+                    .and_then(parse_dbg_number)
+                    .ok_or(format!("line {}: bad {}", i + 1, key))
+            };
+            // span=3+4
+            let span_ids = || -> Vec<usize> {
+                get("span")
+                    .map(|v| v.split('+').filter_map(parse_dbg_number).collect())
+                    .unwrap_or_default()
+            };
+            match kind {
+                "file" => {
+                    let name = get("name").ok_or(format!("line {}: file without name", i + 1))?;
+                    files.insert(number("id")?, name.to_string());
+                }
+                "seg" => {
+                    let ooffs = match get("type") {
+                        Some("ro") => get("ooffs").and_then(parse_dbg_number),
+                        _ => None,
+                    };
+                    segments.insert(number("id")?, (number("start")?, ooffs));
+                }
+                "span" => {
+                    spans.insert(
+                        number("id")?,
+                        (number("seg")?, number("start")?, number("size")?),
+                    );
+                }
+                "line" => {
+                    let c = get("type") == Some("1");
+                    lines.push((number("file")?, number("line")?, c, span_ids()));
+                }
+                "scope" => {
+                    let name = get("name").unwrap_or("");
+                    scopes.push((number("id")?, name.to_string(), span_ids()));
+                }
+                "csym" if get("sc") == Some("auto") => {
+                    let offset = get("offs").and_then(|v| v.parse().ok()).unwrap_or(0);
+                    let name = get("name").unwrap_or("").to_string();
+                    autos.push((number("scope")?, Local { name, offset }));
+                }
+                "sym"
+                    if matches!(get("name"), Some("sp") | Some("c_sp"))
+                        && get("addrsize") == Some("zeropage") =>
+                {
+                    if let Some(val) = get("val").and_then(parse_dbg_number) {
+                        stack_pointer = Some(val as u16);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let span = |id: &usize| -> Option<Span> {
+            let &(seg, start, size) = spans.get(id)?;
+            let &(seg_start, ooffs) = segments.get(&seg)?;
+            let addr = seg_start + start;
+            Some(Span {
+                start: addr as u16,
+                size: size as u16,
+                bank: segment_bank(seg_start, ooffs, addr),
+            })
+        };
+
+        let mut map = SourceMap {
+            stack_pointer,
+            ..Default::default()
+        };
+        for (file, line, c, ids) in lines {
+            let file = files
+                .get(&file)
+                .cloned()
+                .ok_or(format!("unknown file id {}", file))?;
+            let index = map.lines.len();
+            map.lines.push(SourceLine { file, line, c });
+            for id in &ids {
+                if let Some(span) = span(id) {
+                    map.spans.push((span, index));
+                }
+            }
+        }
+        for (id, name, ids) in scopes {
+            let mut locals: Vec<Local> = autos
+                .iter()
+                .filter(|(scope, _)| *scope == id)
+                .map(|(_, local)| local.clone())
+                .collect();
+            if locals.is_empty() {
+                continue;
+            }
+            locals.sort_by_key(|local| local.offset);
+            let name = name.strip_prefix('_').unwrap_or(&name).to_string();
+            let spans = ids.iter().filter_map(span).collect();
+            map.functions.push(Function {
+                name,
+                spans,
+                locals,
+            });
+        }
+        Ok(map)
+    }
+
+    // addr を含む行。C の行があればそちらを、なければ一番短いスパンの行を返す
+    pub fn lookup(&self, addr: u16, bank: Option<usize>) -> Option<&SourceLine> {
+        self.spans
+            .iter()
+            .filter(|(span, _)| span.contains(addr, bank))
+            .min_by_key(|(span, index)| (!self.lines[*index].c, span.size))
+            .map(|(_, index)| &self.lines[*index])
+    }
+
+    // addr がその行の (いくつかあるうちの) どれかのスパンの先頭か
+    pub fn is_line_start(&self, addr: u16, bank: Option<usize>) -> bool {
+        match self.lookup(addr, bank) {
+            Some(line) => self.spans.iter().any(|(span, index)| {
+                span.start == addr && span.contains(addr, bank) && self.lines[*index] == *line
+            }),
+            None => false,
+        }
+    }
+
+    // CPU から今見えているバンクで引く
+    pub fn line<B: Mem>(&self, cpu: &CPU<B>, addr: u16) -> Option<&SourceLine> {
+        if self.is_empty() {
+            return None;
+        }
+        self.lookup(addr, cpu.bus.prg_bank(addr))
+    }
+
+    // ソースファイルの該当行 (読めなければ None)
+    pub fn text(&self, line: &SourceLine) -> Option<String> {
+        let bytes = std::fs::read(self.dir.join(&line.file)).ok()?;
+        let text = String::from_utf8_lossy(&bytes);
+        text.lines()
+            .nth(line.line.checked_sub(1)?)
+            .map(|text| text.trim().to_string())
+    }
+
+    // sample2.c:42  for (i = 0; i < 0x10; i ++)
+    pub fn describe<B: Mem>(&self, cpu: &CPU<B>, addr: u16) -> Option<String> {
+        let line = self.line(cpu, addr)?;
+        match self.text(line) {
+            Some(text) => Some(format!("{}  {}", line, text)),
+            None => Some(line.to_string()),
+        }
+    }
+
+    // addr を含む関数の名前とローカル変数 (名前, アドレス, 大きさ)。
+    // 行の先頭ではスタックにローカル変数の分だけ積まれていると考える
+    pub fn locals<B: Mem>(&self, cpu: &CPU<B>, addr: u16) -> Option<(String, Vec<Variable>)> {
+        let sp_addr = self.stack_pointer?;
+        let bank = cpu.bus.prg_bank(addr);
+        let function = self
+            .functions
+            .iter()
+            .filter(|f| f.spans.iter().any(|span| span.contains(addr, bank)))
+            .min_by_key(|f| f.spans.iter().map(|span| span.size).sum::<u16>())?;
+        let sp = u16::from_le_bytes([cpu.peek(sp_addr), cpu.peek(sp_addr.wrapping_add(1))]);
+        let base = function.locals[0].offset.min(0);
+        let locals = function
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(i, local)| {
+                // 次の変数までを大きさとする (最後の引数は分からないので 2 バイト)
+                let next = match function.locals.get(i + 1) {
+                    Some(next) => next.offset,
+                    None if local.offset < 0 => 0,
+                    None => local.offset + 2,
+                };
+                let size = ((next - local.offset).max(1) as usize).min(MAX_LOCAL_SIZE);
+                let addr = sp.wrapping_add((local.offset - base) as u16);
+                Variable {
+                    name: local.name.clone(),
+                    addr,
+                    size,
+                }
+            })
+            .collect();
+        Some((function.name.clone(), locals))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::bus::FlatBus;
+
+    // samples/helloworld/c/sample2.c を cl65 -g でコンパイルしたときの形。
+    // スパンの場所は手で決めた
+    pub const DBG: &str = r#"version	major=2,minor=0
+file	id=0,name="sample2.c",size=1194,mtime=0x5B1D2C80,mod=0
+file	id=1,name="sample2.s",size=5000,mtime=0x5B1D2C80,mod=0
+seg	id=0,name="CODE",start=0x000600,size=0x0040,addrsize=absolute,type=rw
+seg	id=1,name="ZEROPAGE",start=0x000000,size=0x001A,addrsize=zeropage,type=rw
+span	id=0,seg=0,start=0,size=3
+span	id=1,seg=0,start=3,size=4
+span	id=2,seg=0,start=7,size=2
+span	id=3,seg=0,start=16,size=2
+span	id=4,seg=0,start=18,size=2
+span	id=5,seg=0,start=0,size=9
+span	id=6,seg=0,start=16,size=4
+span	id=7,seg=0,start=9,size=1
+line	id=0,file=0,line=51,type=1,span=0
+line	id=1,file=0,line=52,type=1,span=1+7
+line	id=2,file=0,line=55,type=1,span=2
+line	id=3,file=0,line=21,type=1,span=3
+line	id=4,file=0,line=22,type=1,span=4
+line	id=5,file=1,line=300,span=5
+scope	id=0,name="",mod=0,size=64
+scope	id=1,name="_NesMain",mod=0,type=scope,size=9,parent=0,span=5
+scope	id=2,name="_SetScroll",mod=0,type=scope,size=4,parent=0,span=6
+csym	id=0,name="NesMain",scope=1,type=0,sc=ext,sym=1
+csym	id=1,name="palettes",scope=1,type=1,sc=auto,offs=-16
+csym	id=2,name="string",scope=1,type=2,sc=auto,offs=-30
+csym	id=3,name="i",scope=1,type=3,sc=auto,offs=-31
+csym	id=4,name="y",scope=2,type=3,sc=auto,offs=0
+csym	id=5,name="x",scope=2,type=3,sc=auto,offs=1
+sym	id=0,name="sp",addrsize=zeropage,size=2,scope=0,def=0,val=0x0,seg=1,type=lab
+sym	id=1,name="_NesMain",addrsize=absolute,scope=0,def=1,val=0x600,seg=0,type=lab
+"#;
+
+    #[test]
+    fn test_lookup_lines() {
+        let map = SourceMap::parse(DBG).unwrap();
+        // アセンブラの行 (sample2.s) と重なっていても C の行を使う
+        let line = map.lookup(0x0604, None).unwrap();
+        assert_eq!(line.to_string(), "sample2.c:52");
+        assert!(line.c);
+        assert_eq!(map.lookup(0x0612, None).unwrap().line, 22);
+        assert_eq!(map.lookup(0x0620, None), None);
+
+        assert!(map.is_line_start(0x0603, None));
+        assert!(!map.is_line_start(0x0604, None));
+        // 2 つ目のスパン
+        assert!(map.is_line_start(0x0609, None));
+    }
+
+    #[test]
+    fn test_source_text() {
+        let mut map = SourceMap::parse(DBG).unwrap();
+        map.dir = PathBuf::from("samples/helloworld/c");
+        let line = map.lookup(0x0600, None).unwrap().clone();
+        assert_eq!(map.text(&line), Some("SetScroll(0, 0);".to_string()));
+
+        let cpu = CPU::new(FlatBus::new());
+        assert_eq!(
+            map.describe(&cpu, 0x0610),
+            Some("sample2.c:21  *(char*)0x2005 = x;".to_string())
+        );
+    }
+
+    #[test]
+    fn test_locals() {
+        let map = SourceMap::parse(DBG).unwrap();
+        let mut bus = FlatBus::new();
+        // sp = $03E1
+        bus.load(0x0000, &[0xE1, 0x03]);
+        let cpu = CPU::new(bus);
+
+        let summary = |locals: Vec<Variable>| -> Vec<(String, u16, usize)> {
+            locals
+                .into_iter()
+                .map(|v| (v.name, v.addr, v.size))
+                .collect()
+        };
+
+        let (name, locals) = map.locals(&cpu, 0x0603).unwrap();
+        assert_eq!(name, "NesMain");
+        assert_eq!(
+            summary(locals),
+            vec![
+                ("i".to_string(), 0x03E1, 1),
+                ("string".to_string(), 0x03E2, 14),
+                ("palettes".to_string(), 0x03F0, 16),
+            ]
+        );
+        // 引数だけの関数
+        let (name, locals) = map.locals(&cpu, 0x0611).unwrap();
+        assert_eq!(name, "SetScroll");
+        assert_eq!(
+            summary(locals),
+            vec![("y".to_string(), 0x03E1, 1), ("x".to_string(), 0x03E2, 2)]
+        );
+        assert!(map.locals(&cpu, 0x0630).is_none());
+    }
+}
@@ -0,0 +1,366 @@
+// ラベルファイル (ca65/ld65 の .dbg、FCEUX の .nl、Mesen の .mlb) を読んで、
+// アドレスの代わりに Reset や copypal のような名前を出す
+//
+// PRG ROM のラベルはバンク (16KiB 単位) を覚えておき、今そのバンクがマップされているときだけ使う
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::bus::Mem;
+use crate::cpu::CPU;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+// .dbg の ooffs は iNES ヘッダを含めたファイル内の位置
+const INES_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u16,
+    // PRG ROM のバンク。RAM やレジスタなら None
+    pub bank: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    by_addr: BTreeMap<u16, Vec<usize>>,
+    // (バンク, バンク内のオフセット)
+    by_offset: HashMap<(usize, u16), usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn add(&mut self, name: &str, addr: u16, bank: Option<usize>) {
+        let index = self.symbols.len();
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            addr,
+            bank,
+        });
+        self.by_addr.entry(addr).or_default().push(index);
+        if let Some(bank) = bank {
+            // 同じ場所に複数あれば最初のものを使う
+            self.by_offset
+                .entry((bank, addr % PRG_BANK_SIZE as u16))
+                .or_insert(index);
+        }
+    }
+
+    pub fn extend(&mut self, other: SymbolTable) {
+        for symbol in other.symbols {
+            self.add(&symbol.name, symbol.addr, symbol.bank);
+        }
+    }
+
+    // bank は addr に今マップされている PRG ROM のバンク。分からなければ None (アドレスだけで引く)
+    pub fn lookup(&self, addr: u16, bank: Option<usize>) -> Option<&str> {
+        if let (Some(bank), true) = (bank, addr >= 0x8000) {
+            if let Some(&index) = self.by_offset.get(&(bank, addr % PRG_BANK_SIZE as u16)) {
+                return Some(&self.symbols[index].name);
+            }
+        }
+        let indexes = self.by_addr.get(&addr)?;
+        indexes
+            .iter()
+            .map(|&index| &self.symbols[index])
+            .find(|symbol| bank.is_none() || symbol.bank.is_none())
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    // CPU から今見えているバンクで引く
+    pub fn label<B: Mem>(&self, cpu: &CPU<B>, addr: u16) -> Option<&str> {
+        if self.is_empty() {
+            return None;
+        }
+        self.lookup(addr, cpu.bus.prg_bank(addr))
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.addr)
+    }
+
+    // 拡張子で形式を決める。FCEUX の .nl はファイル名 (game.nes.0.nl / game.nes.ram.nl) からバンクを取る
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let result = if path.ends_with(".dbg") {
+            Self::parse_dbg(&text)
+        } else if path.ends_with(".mlb") {
+            Self::parse_mlb(&text)
+        } else if let Some(stem) = path.strip_suffix(".nl") {
+            let bank = stem.rsplit('.').next().and_then(|n| n.parse().ok());
+            Self::parse_nl(&text, bank)
+        } else {
+            Err("unknown symbol file (expected .dbg, .nl or .mlb)".to_string())
+        };
+        result.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // ld65 --dbgfile の出力。ラベル (type=lab) だけを使う
+    //   seg id=0,name="CODE",start=0x008000,...,type=ro,oname="sample1.nes",ooffs=16
+    //   sym id=0,name="Reset",addrsize=absolute,scope=0,def=0,val=0x8000,seg=0,type=lab
+    pub fn parse_dbg(text: &str) -> Result<Self, String> {
+        let mut segments = HashMap::new();
+        let mut labels = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let (kind, rest) = match line.split_once(char::is_whitespace) {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let fields = dbg_fields(rest);
+            let get = |key: &str| fields.get(key).map(|v| v.as_str());
+            let number = |key: &str| -> Result<Option<usize>, String> {
+                get(key)
+                    .map(|v| parse_dbg_number(v).ok_or(format!("line {}: bad {}", i + 1, key)))
+                    .transpose()
+            };
+            match kind {
+                "seg" => {
+                    let id = number("id")?.ok_or(format!("line {}: seg without id", i + 1))?;
+                    // ROM に置かれるセグメントだけファイル内の位置が分かる
+                    let rom = match (get("type"), number("ooffs")?) {
+                        (Some("ro"), Some(ooffs)) => Some(ooffs),
+                        _ => None,
+                    };
+                    segments.insert(id, (number("start")?.unwrap_or(0), rom));
+                }
+                // ローカルラベル (@loop など) は parent を持つ
+                "sym" if get("type") == Some("lab") && get("parent").is_none() => {
+                    let name = get("name").ok_or(format!("line {}: sym without name", i + 1))?;
+                    let value = number("val")?.ok_or(format!("line {}: sym without val", i + 1))?;
+                    labels.push((name.to_string(), value, number("seg")?));
+                }
+                _ => {}
+            }
+        }
+
+        let mut table = SymbolTable::new();
+        for (name, value, segment) in labels {
+            let bank = segment
+                .and_then(|id| segments.get(&id))
+                .and_then(|&(start, ooffs)| segment_bank(start, ooffs, value));
+            table.add(&name, value as u16, bank);
+        }
+        Ok(table)
+    }
+
+    // FCEUX のネームリスト。1 行 1 ラベルで "$C000#Reset#コメント"。"$0200/10#buffer#" は範囲
+    pub fn parse_nl(text: &str, bank: Option<usize>) -> Result<Self, String> {
+        let mut table = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(3, '#');
+            let addr = parts.next().unwrap_or("");
+            let name = parts.next().unwrap_or("").trim();
+            let addr = addr.split('/').next().unwrap_or("");
+            let addr = addr
+                .strip_prefix('$')
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                .ok_or(format!("line {}: bad address {}", i + 1, addr))?;
+            // コメントだけの行もある
+            if name.is_empty() {
+                continue;
+            }
+            let bank = if addr >= 0x8000 { bank } else { None };
+            table.add(name, addr, bank);
+        }
+        Ok(table)
+    }
+
+    // Mesen のラベル。"P:0000:Reset" (PRG ROM のオフセット)、"R:0010:count" (内部 RAM)、
+    // "G:2000:PPUCTRL" (CPU のアドレス)、"S:"/"W:" ($6000 からのオフセット)。
+    // Mesen 2 の "NesPrgRom:" なども読む。範囲 ("P:0010-001F:table") は先頭だけ使う
+    pub fn parse_mlb(text: &str) -> Result<Self, String> {
+        let mut table = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(4, ':');
+            let (kind, offset, name) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(kind), Some(offset), Some(name)) => (kind, offset, name.trim()),
+                _ => return Err(format!("line {}: expected TYPE:ADDR:LABEL", i + 1)),
+            };
+            let offset = offset.split('-').next().unwrap_or("");
+            let offset = usize::from_str_radix(offset, 16)
+                .map_err(|_| format!("line {}: bad address {}", i + 1, offset))?;
+            // コメントだけの行は名前が空
+            if name.is_empty() {
+                continue;
+            }
+            match kind {
+                // バンクの置き場所はマッパー次第なので、NROM と同じく $8000 から並べておく
+                "P" | "NesPrgRom" => table.add(
+                    name,
+                    0x8000 | (offset % 0x8000) as u16,
+                    Some(offset / PRG_BANK_SIZE),
+                ),
+                "R" | "NesInternalRam" => table.add(name, (offset % 0x0800) as u16, None),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    table.add(name, 0x6000 + (offset % 0x2000) as u16, None)
+                }
+                "G" | "NesMemory" => table.add(name, offset as u16, None),
+                // CHR などは CPU から見えない
+                _ => {}
+            }
+        }
+        Ok(table)
+    }
+}
+
+// key=value,key="quoted, value",... を分ける
+pub(crate) fn dbg_fields(text: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut quoted = false;
+    let mut start = 0;
+    let text = text.trim();
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ',')))
+    {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                if let Some((key, value)) = text[start..i].split_once('=') {
+                    fields.insert(key.trim().to_string(), value.trim_matches('"').to_string());
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields
+}
+
+pub(crate) fn parse_dbg_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// ROM に置かれたセグメント (start から始まり、ファイルの ooffs に置かれる) の中の value のバンク
+pub(crate) fn segment_bank(start: usize, ooffs: Option<usize>, value: usize) -> Option<usize> {
+    match ooffs {
+        Some(ooffs) if value >= start && ooffs >= INES_HEADER_SIZE => {
+            Some((ooffs - INES_HEADER_SIZE + value - start) / PRG_BANK_SIZE)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // samples/helloworld/asm を ld65 --dbgfile でリンクしたときの抜粋
+    const DBG: &str = r#"version	major=2,minor=0
+info	csym=0,file=1,lib=0,line=2,mod=1,scope=2,seg=4,span=2,sym=5,type=1
+file	id=0,name="sample1.asm",size=2139,mtime=0x5B1D2C80,mod=0
+seg	id=0,name="HEADER",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname="sample1.nes",ooffs=0
+seg	id=1,name="STARTUP",start=0x008000,size=0x0067,addrsize=absolute,type=ro,oname="sample1.nes",ooffs=16
+seg	id=2,name="VECINFO",start=0x00FFFA,size=0x0006,addrsize=absolute,type=ro,oname="sample1.nes",ooffs=32762
+seg	id=3,name="ZEROPAGE",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+sym	id=0,name="Reset",addrsize=absolute,size=103,scope=0,def=0,ref=1,val=0x8000,seg=1,type=lab
+sym	id=1,name="copypal",addrsize=absolute,scope=1,def=1,val=0x801B,seg=1,type=lab
+sym	id=2,name="@loop",addrsize=absolute,scope=1,def=2,val=0x8020,seg=1,parent=1,type=lab
+sym	id=3,name="count",addrsize=zeropage,scope=0,def=3,val=0x0,seg=3,type=lab
+sym	id=4,name="PPU_CTRL",addrsize=absolute,scope=0,def=4,val=0x2000,type=equ
+"#;
+
+    #[test]
+    fn test_parse_dbg() {
+        let table = SymbolTable::parse_dbg(DBG).unwrap();
+        assert_eq!(
+            table.symbols(),
+            [
+                Symbol {
+                    name: "Reset".to_string(),
+                    addr: 0x8000,
+                    bank: Some(0)
+                },
+                Symbol {
+                    name: "copypal".to_string(),
+                    addr: 0x801B,
+                    bank: Some(0)
+                },
+                Symbol {
+                    name: "count".to_string(),
+                    addr: 0x0000,
+                    bank: None
+                },
+            ]
+        );
+        assert_eq!(table.address_of("copypal"), Some(0x801B));
+        assert_eq!(table.address_of("PPU_CTRL"), None);
+    }
+
+    #[test]
+    fn test_dbg_bank_from_file_offset() {
+        // 2 つ目の 16KiB バンクに置かれたセグメント
+        let dbg = r#"seg	id=0,name="BANK1",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname="game.nes",ooffs=16400
+sym	id=0,name="nmi",addrsize=absolute,scope=0,def=0,val=0xC123,seg=0,type=lab"#;
+        let table = SymbolTable::parse_dbg(dbg).unwrap();
+        assert_eq!(table.symbols()[0].bank, Some(1));
+    }
+
+    #[test]
+    fn test_parse_nl() {
+        let nl = "$C000#Reset#entry point\n$C0A0#copypal#\n$8000##comment only\n";
+        let table = SymbolTable::parse_nl(nl, Some(1)).unwrap();
+        assert_eq!(table.symbols().len(), 2);
+        assert_eq!(table.lookup(0xC000, Some(1)), Some("Reset"));
+
+        let table = SymbolTable::parse_nl("$0010#count#\n$0200/20#buffer#\n", None).unwrap();
+        assert_eq!(table.lookup(0x0200, None), Some("buffer"));
+        assert!(SymbolTable::parse_nl("C000#Reset#", None).is_err());
+    }
+
+    #[test]
+    fn test_parse_mlb() {
+        let mlb = "P:0000:Reset\nP:4010-401F:table\nR:0010:count:loop counter\nG:2000:PPUCTRL\nS:0000:save\nNesPrgRom:001B:copypal\nC:0000:tiles\n";
+        let table = SymbolTable::parse_mlb(mlb).unwrap();
+        assert_eq!(table.lookup(0x8000, Some(0)), Some("Reset"));
+        assert_eq!(table.lookup(0xC010, Some(1)), Some("table"));
+        assert_eq!(table.lookup(0x801B, Some(0)), Some("copypal"));
+        assert_eq!(table.lookup(0x0010, None), Some("count"));
+        assert_eq!(table.lookup(0x2000, None), Some("PPUCTRL"));
+        assert_eq!(table.lookup(0x6000, None), Some("save"));
+        assert_eq!(table.symbols().len(), 6);
+        assert!(SymbolTable::parse_mlb("P:zz:Reset").is_err());
+    }
+
+    #[test]
+    fn test_bank_aware_lookup() {
+        let mut table = SymbolTable::new();
+        table.add("bank0_entry", 0x8000, Some(0));
+        table.add("bank2_entry", 0x8000, Some(2));
+        table.add("ram", 0x0300, None);
+
+        assert_eq!(table.lookup(0x8000, Some(0)), Some("bank0_entry"));
+        assert_eq!(table.lookup(0x8000, Some(2)), Some("bank2_entry"));
+        assert_eq!(table.lookup(0x8000, Some(1)), None);
+        // NROM-128 は $C000 にもバンク 0 が見える
+        assert_eq!(table.lookup(0xC000, Some(0)), Some("bank0_entry"));
+        // バンクが分からなければアドレスだけで引く
+        assert_eq!(table.lookup(0x8000, None), Some("bank0_entry"));
+        assert_eq!(table.lookup(0x0300, Some(0)), Some("ram"));
+    }
+}
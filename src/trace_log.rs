@@ -0,0 +1,371 @@
+// 命令ごとのトレースを出すオブザーバ
+//
+// 出し先はファイル・標準出力・メモリ上のリングバッファ (最後の N 行だけ残す)。
+// PC やフレーム番号で開始/停止でき、PC のアドレス範囲で絞れる
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use crate::bus::Mem;
+use crate::cpu::{trace_with_symbols, CPU};
+use crate::observer::Observer;
+use crate::symbols::SymbolTable;
+
+// nestest の形式の後ろに付ける列
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TraceFormat {
+    // PPU:241,  1 (nestest.log と同じ)
+    pub ppu: bool,
+    // CYC:7
+    pub cycles: bool,
+    // BANK:0
+    pub bank: bool,
+}
+
+// --trace-format=nestest / full / ppu,cyc,bank
+impl std::str::FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut format = TraceFormat::default();
+        match s {
+            "nestest" => return Ok(format),
+            "full" => {
+                return Ok(TraceFormat {
+                    ppu: true,
+                    cycles: true,
+                    bank: true,
+                })
+            }
+            _ => {}
+        }
+        for column in s.split(',') {
+            match column {
+                "ppu" => format.ppu = true,
+                "cyc" => format.cycles = true,
+                "bank" => format.bank = true,
+                _ => return Err(format!("unknown trace column: {}", column)),
+            }
+        }
+        Ok(format)
+    }
+}
+
+impl TraceFormat {
+    pub fn format<B: Mem>(&self, cpu: &CPU<B>, symbols: &SymbolTable) -> String {
+        let mut line = trace_with_symbols(cpu, symbols);
+        if self.ppu {
+            match cpu.bus.ppu_position() {
+                Some((scanline, dot)) => line += &format!(" PPU:{:>3},{:>3}", scanline, dot),
+                None => line += " PPU:  -,  -",
+            }
+        }
+        if self.cycles {
+            line += &format!(" CYC:{}", cpu.cycles);
+        }
+        if self.bank {
+            match cpu.bus.prg_bank(cpu.program_counter) {
+                Some(bank) => line += &format!(" BANK:{}", bank),
+                None => line += " BANK:-",
+            }
+        }
+        line
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    Pc(u16),
+    // frame_complete が呼ばれた回数
+    Frame(u64),
+}
+
+// pc:C000 / frame:10
+impl std::str::FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(pc) = s.strip_prefix("pc:") {
+            return u16::from_str_radix(pc.trim_start_matches('$'), 16)
+                .map(Trigger::Pc)
+                .map_err(|_| format!("invalid PC: {}", pc));
+        }
+        if let Some(frame) = s.strip_prefix("frame:") {
+            return frame
+                .parse()
+                .map(Trigger::Frame)
+                .map_err(|_| format!("invalid frame: {}", frame));
+        }
+        Err(format!("unknown trigger: {}", s))
+    }
+}
+
+// 8000-BFFF
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |v: &str| {
+        u16::from_str_radix(v.trim_start_matches('$'), 16)
+            .map_err(|_| format!("invalid address: {}", v))
+    };
+    match s.split_once('-') {
+        Some((start, end)) => Ok(parse(start)?..=parse(end)?),
+        None => {
+            let addr = parse(s)?;
+            Ok(addr..=addr)
+        }
+    }
+}
+
+// リングバッファはロガーを CPU に登録した後も読めるように共有する
+#[derive(Clone)]
+pub struct TraceRing {
+    lines: Rc<RefCell<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl TraceRing {
+    pub fn new(capacity: usize) -> Result<Self, String> {
+        if capacity == 0 {
+            return Err("ring size must be at least 1".to_string());
+        }
+        Ok(TraceRing {
+            lines: Rc::new(RefCell::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        })
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.lines.borrow_mut();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.borrow().iter().cloned().collect()
+    }
+}
+
+pub enum TraceSink {
+    Stdout,
+    File(BufWriter<File>),
+    Ring(TraceRing),
+}
+
+impl TraceSink {
+    pub fn file(path: &str) -> Result<Self, String> {
+        File::create(path)
+            .map(|file| TraceSink::File(BufWriter::new(file)))
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn write(&mut self, line: String) {
+        match self {
+            TraceSink::Stdout => println!("{}", line),
+            // 書けなくなってもエミュレーションは止めない
+            TraceSink::File(file) => {
+                let _ = writeln!(file, "{}", line);
+            }
+            TraceSink::Ring(ring) => ring.push(line),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let TraceSink::File(file) = self {
+            let _ = file.flush();
+        }
+    }
+}
+
+pub struct TraceLogger {
+    sink: TraceSink,
+    pub format: TraceFormat,
+    // None なら最初から出す
+    pub start: Option<Trigger>,
+    pub stop: Option<Trigger>,
+    // 空なら全部。PC がどれかに入っている命令だけ出す
+    pub ranges: Vec<RangeInclusive<u16>>,
+    // オペランドをラベルで出す
+    pub symbols: SymbolTable,
+    active: Option<bool>,
+    frame: u64,
+}
+
+impl TraceLogger {
+    pub fn new(sink: TraceSink) -> Self {
+        TraceLogger {
+            sink,
+            format: TraceFormat::default(),
+            start: None,
+            stop: None,
+            ranges: vec![],
+            symbols: SymbolTable::new(),
+            active: None,
+            frame: 0,
+        }
+    }
+
+    fn fired(&self, trigger: Option<Trigger>, pc: u16) -> bool {
+        match trigger {
+            Some(Trigger::Pc(addr)) => addr == pc,
+            Some(Trigger::Frame(frame)) => self.frame >= frame,
+            None => false,
+        }
+    }
+
+    fn update(&mut self, pc: u16) {
+        let mut active = self.active.unwrap_or(self.start.is_none());
+        if !active && self.fired(self.start, pc) {
+            active = true;
+            // フレームでの開始は 1 回だけ (PC なら通るたびに始まる)
+            if let Some(Trigger::Frame(_)) = self.start {
+                self.start = Some(Trigger::Frame(u64::MAX));
+            }
+        }
+        if active && self.fired(self.stop, pc) {
+            active = false;
+            self.sink.flush();
+        }
+        self.active = Some(active);
+    }
+}
+
+impl<B: Mem> Observer<B> for TraceLogger {
+    fn before_instruction(&mut self, cpu: &mut CPU<B>) {
+        let pc = cpu.program_counter;
+        self.update(pc);
+        if self.active != Some(true) {
+            return;
+        }
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|range| range.contains(&pc)) {
+            return;
+        }
+        let line = self.format.format(cpu, &self.symbols);
+        self.sink.write(line);
+    }
+
+    fn frame_complete(&mut self, _cpu: &mut CPU<B>) {
+        self.frame += 1;
+        self.sink.flush();
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        self.sink.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::{Bus, FlatBus};
+    use crate::cartridge::test::test_rom;
+
+    // INX を並べて、最後は JMP $8000
+    fn looping_cpu(ring: &TraceRing, setup: impl FnOnce(&mut TraceLogger)) -> CPU<FlatBus> {
+        let mut bus = FlatBus::new();
+        bus.load(0x8000, &[0xE8, 0xE8, 0xE8, 0x4C, 0x00, 0x80]);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x8000;
+        let mut logger = TraceLogger::new(TraceSink::Ring(ring.clone()));
+        setup(&mut logger);
+        cpu.add_observer(Box::new(logger));
+        cpu
+    }
+
+    fn pcs(ring: &TraceRing) -> Vec<String> {
+        ring.lines()
+            .iter()
+            .map(|line| line[..4].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let ring = TraceRing::new(3).unwrap();
+        let mut cpu = looping_cpu(&ring, |_| {});
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(pcs(&ring), ["8002", "8003", "8000"]);
+        assert!(ring.lines()[1].starts_with("8003  4C 00 80  JMP $8000"));
+        assert!(TraceRing::new(0).is_err());
+    }
+
+    #[test]
+    fn test_pc_triggers_and_range() {
+        let ring = TraceRing::new(100).unwrap();
+        let mut cpu = looping_cpu(&ring, |logger| {
+            logger.start = Some(Trigger::Pc(0x8002));
+            logger.stop = Some(Trigger::Pc(0x8001));
+        });
+        for _ in 0..8 {
+            cpu.step();
+        }
+        // 8002 で始まり 8001 で止まる。PC の開始は通るたびに効く
+        assert_eq!(pcs(&ring), ["8002", "8003", "8000", "8002", "8003"]);
+
+        let ring = TraceRing::new(100).unwrap();
+        let mut cpu = looping_cpu(&ring, |logger| {
+            logger.ranges = vec![
+                parse_range("8001-8002").unwrap(),
+                parse_range("8003").unwrap(),
+            ];
+        });
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(pcs(&ring), ["8001", "8002", "8003"]);
+    }
+
+    #[test]
+    fn test_frame_triggers() {
+        let ring = TraceRing::new(100).unwrap();
+        let mut cpu = looping_cpu(&ring, |logger| {
+            logger.start = Some(Trigger::Frame(1));
+            logger.stop = Some(Trigger::Frame(2));
+        });
+        cpu.step();
+        cpu.frame_complete();
+        cpu.step();
+        cpu.step();
+        cpu.frame_complete();
+        cpu.step();
+        cpu.frame_complete();
+        cpu.step();
+        assert_eq!(pcs(&ring), ["8001", "8002"]);
+    }
+
+    #[test]
+    fn test_format_columns() {
+        let ring = TraceRing::new(10).unwrap();
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.program_counter = 0xC000;
+        cpu.cycles = 7;
+        let mut logger = TraceLogger::new(TraceSink::Ring(ring.clone()));
+        logger.format = "full".parse().unwrap();
+        cpu.add_observer(Box::new(logger));
+        cpu.step();
+        assert_eq!(
+            ring.lines()[0],
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:7 BANK:0"
+        );
+
+        assert_eq!(
+            "ppu,cyc".parse(),
+            Ok(TraceFormat {
+                ppu: true,
+                cycles: true,
+                bank: false
+            })
+        );
+        assert!("foo".parse::<TraceFormat>().is_err());
+        assert_eq!("pc:C000".parse(), Ok(Trigger::Pc(0xC000)));
+        assert_eq!("frame:3".parse(), Ok(Trigger::Frame(3)));
+    }
+}
@@ -0,0 +1,333 @@
+// trace() の出力 (nestest.log と同じ形式) を 2 つ並べて比べる
+//
+//   C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+//
+// PPU と CYC は片方にしかなければ比べない (mynes.log にはない)。
+// トレース以外の行 (ログの出力など) は読み飛ばす
+
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Pc,
+    Bytes,
+    Disasm,
+    // "= 00" や "@ 45 = 0432 = AA" の部分
+    Annotation,
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    Ppu,
+    Cyc,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Field::Pc => "PC",
+            Field::Bytes => "bytes",
+            Field::Disasm => "disasm",
+            Field::Annotation => "annotation",
+            Field::A => "A",
+            Field::X => "X",
+            Field::Y => "Y",
+            Field::P => "P",
+            Field::Sp => "SP",
+            Field::Ppu => "PPU",
+            Field::Cyc => "CYC",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const PC: Range<usize> = 0..4;
+const BYTES: Range<usize> = 6..15;
+const ASM: Range<usize> = 15..48;
+const REGISTERS: usize = 48;
+const REGISTER_KEYS: [(Field, &str); 5] = [
+    (Field::A, "A:"),
+    (Field::X, "X:"),
+    (Field::Y, "Y:"),
+    (Field::P, "P:"),
+    (Field::Sp, "SP:"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceLine {
+    // ファイルの行番号 (1 から)
+    pub line: usize,
+    pub text: String,
+    // フィールドごとの値と text の中の位置
+    fields: Vec<(Field, String, Range<usize>)>,
+}
+
+impl TraceLine {
+    pub fn get(&self, field: Field) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(f, _, _)| *f == field)
+            .map(|(_, value, _)| value.as_str())
+    }
+
+    fn span(&self, field: Field) -> Option<Range<usize>> {
+        self.fields
+            .iter()
+            .find(|(f, _, _)| *f == field)
+            .map(|(_, _, span)| span.clone())
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// 前後の空白を除いた値と、その位置
+fn trimmed(text: &str, range: Range<usize>) -> (String, Range<usize>) {
+    let raw = &text[range.clone()];
+    let start = range.start + (raw.len() - raw.trim_start().len());
+    let value = raw.trim();
+    (value.to_string(), start..start + value.len())
+}
+
+pub fn parse_line(line: usize, text: &str) -> Result<TraceLine, String> {
+    let error = |what: &str| format!("line {}: {}: {}", line, what, text);
+    if !text.is_ascii() || text.len() < REGISTERS || !is_hex(&text[PC]) || &text[4..6] != "  " {
+        return Err(error("not a trace line"));
+    }
+
+    let mut fields = vec![(Field::Pc, text[PC].to_string(), PC)];
+    let (bytes, span) = trimmed(text, BYTES);
+    fields.push((Field::Bytes, bytes, span));
+
+    // 逆アセンブルと注釈は最初の " @ " か " = " で分ける
+    let (asm, span) = trimmed(text, ASM);
+    let split = [" @ ", " = "]
+        .iter()
+        .filter_map(|sep| asm.find(sep))
+        .min()
+        .unwrap_or(asm.len());
+    fields.push((
+        Field::Disasm,
+        asm[..split].to_string(),
+        span.start..span.start + split,
+    ));
+    let (annotation, annotation_span) = trimmed(text, span.start + split..span.end);
+    fields.push((Field::Annotation, annotation, annotation_span));
+
+    let rest = &text[REGISTERS..];
+    for (field, key) in REGISTER_KEYS {
+        let start = rest
+            .find(&format!(" {}", key))
+            .map(|i| i + 1)
+            .or(if rest.starts_with(key) { Some(0) } else { None })
+            .ok_or_else(|| error(&format!("no {}", key)))?;
+        let value = start + key.len();
+        let end = value + 2;
+        if end > rest.len() || !is_hex(&rest[value..end]) {
+            return Err(error(&format!("bad {}", key)));
+        }
+        fields.push((
+            field,
+            rest[value..end].to_string(),
+            REGISTERS + start..REGISTERS + end,
+        ));
+    }
+
+    let ppu = rest.find("PPU:");
+    let cyc = rest.find("CYC:");
+    if let Some(start) = ppu {
+        let end = cyc.filter(|&end| end > start).unwrap_or(rest.len());
+        let (value, span) = trimmed(text, REGISTERS + start + 4..REGISTERS + end);
+        fields.push((Field::Ppu, value, span));
+    }
+    if let Some(start) = cyc {
+        let (value, span) = trimmed(text, REGISTERS + start + 4..text.len());
+        if value.parse::<u64>().is_err() {
+            return Err(error("bad CYC"));
+        }
+        fields.push((Field::Cyc, value, span));
+    }
+
+    Ok(TraceLine {
+        line,
+        text: text.to_string(),
+        fields,
+    })
+}
+
+// トレースとして読めない行は飛ばす
+pub fn parse_log(log: &str) -> Vec<TraceLine> {
+    log.lines()
+        .enumerate()
+        .filter_map(|(i, text)| parse_line(i + 1, text.trim_end()).ok())
+        .collect()
+}
+
+// 違うフィールド。片方にしかないフィールドは比べない
+pub fn compare(expected: &TraceLine, actual: &TraceLine) -> Vec<Field> {
+    expected
+        .fields
+        .iter()
+        .filter_map(|(field, value, _)| match actual.get(*field) {
+            Some(other) if other != value => Some(*field),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // 何命令目か (0 から)
+    pub index: usize,
+    pub fields: Vec<Field>,
+}
+
+// 命令の順番で並べて比べ、最初の limit 個の違いを返す
+pub fn diff(expected: &[TraceLine], actual: &[TraceLine], limit: usize) -> Vec<Divergence> {
+    expected
+        .iter()
+        .zip(actual)
+        .enumerate()
+        .filter_map(|(index, (expected, actual))| {
+            let fields = compare(expected, actual);
+            if fields.is_empty() {
+                None
+            } else {
+                Some(Divergence { index, fields })
+            }
+        })
+        .take(limit)
+        .collect()
+}
+
+// 違うフィールドの下に ^ を付ける
+fn markers(line: &TraceLine, fields: &[Field]) -> String {
+    let mut marks = vec![b' '; line.text.len()];
+    for field in fields {
+        if let Some(span) = line.span(*field) {
+            // 空の注釈などは 1 文字分だけ印を付ける
+            let end = span.end.max(span.start + 1).min(marks.len());
+            for mark in &mut marks[span.start.min(end)..end] {
+                *mark = b'^';
+            }
+        }
+    }
+    String::from_utf8(marks).unwrap().trim_end().to_string()
+}
+
+// 違いごとに、直前の context 命令と期待値/実際の行を出す
+pub fn report(
+    expected: &[TraceLine],
+    actual: &[TraceLine],
+    divergences: &[Divergence],
+    context: usize,
+) -> String {
+    let mut out = String::new();
+    for divergence in divergences {
+        let index = divergence.index;
+        let names: Vec<String> = divergence.fields.iter().map(|f| f.to_string()).collect();
+        out += &format!(
+            "instruction {} (expected line {}, actual line {}): {}\n",
+            index + 1,
+            expected[index].line,
+            actual[index].line,
+            names.join(", ")
+        );
+        for line in &actual[index.saturating_sub(context)..index] {
+            out += &format!("  {}\n", line.text);
+        }
+        out += &format!("- {}\n", expected[index].text);
+        out += &format!("+ {}\n", actual[index].text);
+        out += &format!("  {}\n\n", markers(&actual[index], &divergence.fields));
+    }
+    if expected.len() != actual.len() {
+        out += &format!(
+            "expected has {} instructions, actual has {}\n",
+            expected.len(),
+            actual.len()
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NESTEST: &str =
+        "E56B  A3 40    *LAX ($40,X) @ 45 = 0432 = AA    A:00 X:05 Y:33 P:26 SP:FB PPU:134,269 CYC:15321";
+    const MYNES: &str = "E56B  A3 40    *LAX ($40,X) @ 45 = 0432 = AA    A:00 X:05 Y:33 P:26 SP:FB";
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line(1, NESTEST).unwrap();
+        assert_eq!(line.get(Field::Pc), Some("E56B"));
+        assert_eq!(line.get(Field::Bytes), Some("A3 40"));
+        assert_eq!(line.get(Field::Disasm), Some("*LAX ($40,X)"));
+        assert_eq!(line.get(Field::Annotation), Some("@ 45 = 0432 = AA"));
+        assert_eq!(line.get(Field::X), Some("05"));
+        assert_eq!(line.get(Field::Sp), Some("FB"));
+        assert_eq!(line.get(Field::Ppu), Some("134,269"));
+        assert_eq!(line.get(Field::Cyc), Some("15321"));
+
+        let line = parse_line(1, MYNES).unwrap();
+        assert_eq!(line.get(Field::Ppu), None);
+        assert_eq!(line.get(Field::Cyc), None);
+
+        let line = parse_line(
+            1,
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD",
+        )
+        .unwrap();
+        assert_eq!(line.get(Field::Disasm), Some("JMP $C5F5"));
+        assert_eq!(line.get(Field::Annotation), Some(""));
+
+        assert!(parse_line(1, "Ignoreing mem access at 16405").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let expected = parse_line(1, NESTEST).unwrap();
+        // PPU と CYC は片方にしかないので比べない
+        assert_eq!(compare(&expected, &parse_line(1, MYNES).unwrap()), vec![]);
+
+        let actual = parse_line(1, &MYNES.replace("P:26", "P:A4").replace("0432", "0433")).unwrap();
+        assert_eq!(
+            compare(&expected, &actual),
+            vec![Field::Annotation, Field::P]
+        );
+        assert_eq!(
+            markers(&actual, &[Field::P]),
+            format!("{}^^^^", " ".repeat(63))
+        );
+    }
+
+    #[test]
+    fn test_diff_logs() {
+        let expected = parse_log(&std::fs::read_to_string("tests/logs/nestest.log").unwrap());
+        let actual = parse_log(&std::fs::read_to_string("tests/logs/mynes.log").unwrap());
+        // mynes.log の "Ignoreing mem access" は読み飛ばす。最後の RTS の後も 1 命令多い
+        assert_eq!(expected.len() + 1, actual.len());
+
+        let divergences = diff(&expected, &actual, 100);
+        // APU がないので $4015 などの値だけが違う
+        assert!(!divergences.is_empty());
+        for divergence in &divergences {
+            assert_eq!(divergence.fields, vec![Field::Annotation]);
+            assert!(expected[divergence.index].text.contains("STA $40"));
+        }
+
+        let report = report(&expected, &actual, &divergences[..1], 2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "instruction 8981 (expected line 8981, actual line 8982): annotation"
+        );
+        assert!(lines[3].starts_with("- C68B  8D 15 40  STA $4015 = FF"));
+        assert!(lines[4].starts_with("+ C68B  8D 15 40  STA $4015 = 00"));
+        assert_eq!(lines[5].trim(), "^^^^");
+    }
+}
@@ -0,0 +1,142 @@
+use famicom_project::bus::{Bus, Mem, RamInit};
+use famicom_project::cartridge::load_rom;
+use famicom_project::cpu::{StepResult, CPU};
+use famicom_project::rom::Rom;
+
+// blargg のテスト ROM を画面なしで動かす。
+// 新しい ROM は $6000 に状態、$6001-$6003 に DE B0 61、$6004 から結果の文字列を書く
+//   $80     実行中
+//   $81     リセットして欲しい (100ms 以上待ってからリセットする)
+//   $00-$7F 終了 (0 なら成功、それ以外は失敗の番号)
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT: u16 = 0x6004;
+const RUNNING: u8 = 0x80;
+const RESET_REQUESTED: u8 = 0x81;
+// $6000 を使わない古い ROM は結果を $F8 に書いて JMP * で止まる (1 なら成功、それ以外は失敗した番号)
+const LEGACY_RESULT: u16 = 0x00F8;
+
+// NTSC の CPU は 1.79MHz
+const CYCLES_PER_SECOND: u64 = 1_789_773;
+const RESET_DELAY: u64 = CYCLES_PER_SECOND / 10;
+// 60 秒で終わらなければ失敗にする
+const MAX_CYCLES: u64 = 60 * CYCLES_PER_SECOND;
+
+fn signature(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.peek(STATUS + 1 + i) == SIGNATURE[i as usize])
+}
+
+fn text(cpu: &CPU) -> String {
+    let mut text = String::new();
+    let mut addr = TEXT;
+    while addr < 0x8000 && cpu.peek(addr) != 0 {
+        text.push(cpu.peek(addr) as char);
+        addr += 1;
+    }
+    text.trim_end().to_string()
+}
+
+// 成功したら結果の文字列、失敗したらその理由を返す
+fn run_rom(rom: Rom) -> Result<String, String> {
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.power_on(RamInit::default());
+
+    let mut reset_at = None;
+    // PRG RAM の中身が残っていても終わったと思わないように、一度 $80 になるまで待つ
+    let mut started = false;
+    while cpu.cycles < MAX_CYCLES {
+        let pc = cpu.program_counter;
+        match cpu.step() {
+            StepResult::Executed { .. } if cpu.program_counter == pc && !signature(&cpu) => {
+                return match cpu.peek(LEGACY_RESULT) {
+                    1 => Ok("PASSED".to_string()),
+                    code => Err(format!("failed #{} (${:04X})", code, LEGACY_RESULT)),
+                };
+            }
+            StepResult::Executed { .. } | StepResult::Interrupt { .. } => {}
+            result => {
+                return Err(format!(
+                    "stopped at ${:04X}: {:?}",
+                    cpu.program_counter, result
+                ))
+            }
+        }
+        if !signature(&cpu) {
+            continue;
+        }
+        match cpu.peek(STATUS) {
+            RUNNING => started = true,
+            _ if !started => {}
+            RESET_REQUESTED => match reset_at {
+                None => reset_at = Some(cpu.cycles + RESET_DELAY),
+                Some(at) if cpu.cycles >= at => {
+                    reset_at = None;
+                    cpu.reset();
+                }
+                Some(_) => {}
+            },
+            0 => return Ok(text(&cpu)),
+            code => return Err(format!("failed with ${:02X}:\n{}", code, text(&cpu))),
+        }
+    }
+    if signature(&cpu) {
+        Err(format!("timed out:\n{}", text(&cpu)))
+    } else {
+        Err(format!(
+            "timed out without writing the signature to ${:04X}",
+            STATUS + 1
+        ))
+    }
+}
+
+macro_rules! blargg_test {
+    ($name:ident, $path:expr) => {
+        #[test]
+        fn $name() {
+            if let Err(e) = run_rom(load_rom($path)) {
+                panic!("{}: {}", $path, e);
+            }
+        }
+    };
+}
+
+blargg_test!(test_branch_basics, "tests/roms/1.Branch_Basics.nes");
+
+// $6000 のプロトコルで結果を書くだけの NROM。1 回目はリセットを要求する
+fn protocol_rom(code: u8) -> Rom {
+    #[rustfmt::skip]
+    let program = [
+        // LDA #$80 / STA $6000、シグネチャと "ok"
+        0xA9, 0x80, 0x8D, 0x00, 0x60,
+        0xA9, 0xDE, 0x8D, 0x01, 0x60,
+        0xA9, 0xB0, 0x8D, 0x02, 0x60,
+        0xA9, 0x61, 0x8D, 0x03, 0x60,
+        0xA9, b'o', 0x8D, 0x04, 0x60,
+        0xA9, b'k', 0x8D, 0x05, 0x60,
+        0xA9, 0x00, 0x8D, 0x06, 0x60,
+        // INC $10 / LDA $10 / CMP #2 / BCS done (RAM はリセットで消えない)
+        0xE6, 0x10, 0xA5, 0x10, 0xC9, 0x02, 0xB0, 0x07,
+        // LDA #$81 / STA $6000 / BNE *
+        0xA9, 0x81, 0x8D, 0x00, 0x60, 0xD0, 0xFE,
+        // done: LDA #code / STA $6000 / JMP *
+        0xA9, code, 0x8D, 0x00, 0x60, 0x4C, 0x37, 0xC0,
+    ];
+    let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00];
+    raw.resize(16, 0);
+    let mut prg = vec![0xFF; 0x4000];
+    prg[..program.len()].copy_from_slice(&program);
+    // リセットベクタ -> $C000
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0xC0;
+    raw.extend(prg);
+    Rom::new(&raw).unwrap()
+}
+
+#[test]
+fn test_status_protocol() {
+    assert_eq!(run_rom(protocol_rom(0)), Ok("ok".to_string()));
+    assert_eq!(
+        run_rom(protocol_rom(3)),
+        Err("failed with $03:\nok".to_string())
+    );
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+use famicom_project::bus::{FlatBus, Mem};
+use famicom_project::cpu::{StepResult, Variant, CPU};
+use famicom_project::disasm::decode;
+
+// Klaus Dormann の 6502_functional_test.bin (64K のイメージ、GPL-3)
+// https://github.com/Klaus2m5/6502_65C02_functional_tests
+// bin_files/ にある as65 でデフォルトの設定 (code_segment = $400, disable_decimal = 0,
+// report = 0) でアセンブルしたものを使う。START と SUCCESS は同じ場所の .lst で確かめられる
+const ROM: &str = "tests/roms/6502_functional_test.bin";
+const START: u16 = 0x0400;
+// 全部通ると JMP * で止まるアドレス (別の設定でアセンブルしたら変わる)
+const SUCCESS: u16 = 0x3469;
+// 1 億命令で止まらなければ失敗にする (通常は 3000 万命令くらい)
+const MAX_INSTRUCTIONS: u64 = 100_000_000;
+const TAIL: usize = 32;
+
+struct Line {
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    sp: u8,
+}
+
+fn format_line(cpu: &CPU<FlatBus>, line: &Line) -> String {
+    let asm = decode(cpu.variant, |addr| Some(cpu.peek(addr)), line.pc)
+        .map(|instruction| instruction.asm())
+        .unwrap_or_default();
+    format!(
+        "{:04X} {:<16} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        line.pc, asm, line.a, line.x, line.y, line.p, line.sp
+    )
+}
+
+// PC が自分自身にトラップしたら止める。トラップした PC を返す
+fn run(cpu: &mut CPU<FlatBus>, tail: &mut VecDeque<Line>) -> Result<u16, String> {
+    for _ in 0..MAX_INSTRUCTIONS {
+        let pc = cpu.program_counter;
+        if tail.len() == TAIL {
+            tail.pop_front();
+        }
+        tail.push_back(Line {
+            pc,
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            p: cpu.status,
+            sp: cpu.stack_pointer,
+        });
+        match cpu.step() {
+            StepResult::Executed { .. } if cpu.program_counter == pc => return Ok(pc),
+            StepResult::Executed { .. } | StepResult::Interrupt { .. } => {}
+            result => return Err(format!("stopped at ${:04X}: {:?}", pc, result)),
+        }
+    }
+    Err(format!("did not trap in {} instructions", MAX_INSTRUCTIONS))
+}
+
+// TODO: バイナリ (と .lst) をまだ tests/roms に入れられていない。
+// 入れたら #[ignore] を外す。それまでは置いてから cargo test --test klaus -- --ignored で動かす
+#[test]
+#[ignore = "needs tests/roms/6502_functional_test.bin"]
+fn test_klaus_functional() {
+    let image = std::fs::read(ROM).unwrap_or_else(|e| panic!("{}: {}", ROM, e));
+    assert_eq!(image.len(), 0x10000, "{} は 64K のイメージ", ROM);
+
+    let mut bus = FlatBus::new();
+    bus.load(0x0000, &image);
+    let mut cpu = CPU::new(bus);
+    // BCD のテストも入っているので NMOS として動かす
+    cpu.variant = Variant::Nmos6502;
+    cpu.program_counter = START;
+
+    let mut tail = VecDeque::new();
+    let result = run(&mut cpu, &mut tail);
+    if result != Ok(SUCCESS) {
+        let lines: Vec<String> = tail.iter().map(|line| format_line(&cpu, line)).collect();
+        let reason = match result {
+            Ok(pc) => format!("trapped at ${:04X} (success is ${:04X})", pc, SUCCESS),
+            Err(e) => e,
+        };
+        panic!("{}\n{}", reason, lines.join("\n"));
+    }
+}
@@ -0,0 +1,29 @@
+use famicom_project::bus::{Bus, RamInit};
+use famicom_project::cartridge::test::test_rom;
+use famicom_project::cpu::{trace, StepResult, CPU};
+
+// nestest.log の CYC と 1 命令ずつ比べる
+#[test]
+fn test_nestest_cycles() {
+    let log = std::fs::read_to_string("tests/logs/nestest.log").unwrap();
+    let mut cpu = CPU::new(Bus::new(test_rom()));
+    cpu.power_on(RamInit::default());
+    cpu.program_counter = 0xC000;
+
+    for (i, line) in log.lines().enumerate() {
+        let expected: u64 = line.rsplit("CYC:").next().unwrap().trim().parse().unwrap();
+        let actual = trace(&cpu);
+        if line[16..].starts_with("STA $40") {
+            // APU はまだないので "= XX" の値は比べない
+            assert_eq!(&line[..26], &actual[..26], "line {}", i + 1);
+            assert_eq!(&line[48..73], &actual[48..], "line {}", i + 1);
+        } else {
+            assert_eq!(&line[..73], actual, "line {}", i + 1);
+        }
+        assert_eq!(expected, cpu.cycles, "line {}: {}", i + 1, line);
+        match cpu.step() {
+            StepResult::Executed { .. } => {}
+            other => panic!("line {}: {:?}", i + 1, other),
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::path::Path;
+
+use famicom_project::bus::Mem;
+use famicom_project::cpu::{StepResult, Variant, CPU};
+use serde_json::Value;
+
+// 命令 1 つずつのテストベクタ (SingleStepTests/ProcessorTests の形式) を流す。
+// https://github.com/SingleStepTests/65x02
+//
+// PROCESSOR_TESTS_DIR     テストのディレクトリ (00.json ... ff.json)。デフォルトは tests/processor_tests
+// PROCESSOR_TESTS_VARIANT 2a03 (nes6502 のテスト) / nmos (6502) / 65c02。デフォルトは 2a03
+// PROCESSOR_TESTS_CYCLES  設定するとサイクルごとのバスアクセスも比べる
+// PROCESSOR_TESTS_OPCODES a9,b1 のようにオペコードを絞る
+const DEFAULT_DIR: &str = "tests/processor_tests";
+
+// 64K のフラットなメモリ。バスアクセスを (アドレス, 値, 種類) で記録する
+struct TestBus {
+    memory: Vec<u8>,
+    cycles: Vec<(u16, u8, &'static str)>,
+}
+
+impl Mem for TestBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let value = self.memory[addr as usize];
+        self.cycles.push((addr, value, "read"));
+        value
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+        self.cycles.push((addr, data, "write"));
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+}
+
+fn number(state: &Value, key: &str) -> Result<u64, String> {
+    state[key]
+        .as_u64()
+        .ok_or_else(|| format!("missing \"{}\"", key))
+}
+
+fn ram(state: &Value) -> Result<Vec<(u16, u8)>, String> {
+    let entries = state["ram"].as_array().ok_or("missing \"ram\"")?;
+    entries
+        .iter()
+        .map(|entry| match (entry[0].as_u64(), entry[1].as_u64()) {
+            (Some(addr), Some(value)) => Ok((addr as u16, value as u8)),
+            _ => Err(format!("invalid ram entry: {}", entry)),
+        })
+        .collect()
+}
+
+fn registers(cpu: &CPU<TestBus>) -> [(&'static str, u64); 6] {
+    [
+        ("pc", cpu.program_counter as u64),
+        ("s", cpu.stack_pointer as u64),
+        ("a", cpu.register_a as u64),
+        ("x", cpu.register_x as u64),
+        ("y", cpu.register_y as u64),
+        ("p", cpu.status as u64),
+    ]
+}
+
+// 記録したバスアクセスを期待値と 1 サイクルずつ比べる
+fn compare_bus(actual: &[(u16, u8, &'static str)], expected: &[Value]) -> Result<(), String> {
+    for (i, expected) in expected.iter().enumerate() {
+        let expected = (
+            expected[0].as_u64().unwrap_or(0) as u16,
+            expected[1].as_u64().unwrap_or(0) as u8,
+            expected[2].as_str().unwrap_or(""),
+        );
+        let actual = actual.get(i).copied();
+        if actual != Some(expected) {
+            return Err(format!(
+                "cycle {}: expected {:?}, got {:?}",
+                i + 1,
+                expected,
+                actual
+            ));
+        }
+    }
+    if actual.len() != expected.len() {
+        return Err(format!(
+            "bus accesses: expected {}, got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+    Ok(())
+}
+
+// 1 ケース実行して、最初に見つかった違いを返す
+fn run_case(case: &Value, variant: Variant, compare_cycles: bool) -> Result<(), String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+
+    let mut bus = TestBus {
+        memory: vec![0; 0x10000],
+        cycles: vec![],
+    };
+    for (addr, value) in ram(initial)? {
+        bus.memory[addr as usize] = value;
+    }
+    let mut cpu = CPU::new(bus);
+    cpu.variant = variant;
+    cpu.program_counter = number(initial, "pc")? as u16;
+    cpu.stack_pointer = number(initial, "s")? as u8;
+    cpu.register_a = number(initial, "a")? as u8;
+    cpu.register_x = number(initial, "x")? as u8;
+    cpu.register_y = number(initial, "y")? as u8;
+    cpu.status = number(initial, "p")? as u8;
+
+    let result = cpu.step();
+    let cycles = match result {
+        StepResult::Executed { cycles, .. } => cycles as usize,
+        result => return Err(format!("{:?}", result)),
+    };
+
+    for (name, actual) in registers(&cpu) {
+        let expected = number(expected, name)?;
+        if actual != expected {
+            return Err(format!(
+                "{}: expected {:02X}, got {:02X}",
+                name, expected, actual
+            ));
+        }
+    }
+    for (addr, value) in ram(expected)? {
+        let actual = cpu.peek(addr);
+        if actual != value {
+            return Err(format!(
+                "${:04X}: expected {:02X}, got {:02X}",
+                addr, value, actual
+            ));
+        }
+    }
+
+    let expected_cycles = case["cycles"].as_array().ok_or("missing \"cycles\"")?;
+    if cycles != expected_cycles.len() {
+        return Err(format!(
+            "cycles: expected {}, got {}",
+            expected_cycles.len(),
+            cycles
+        ));
+    }
+    if compare_cycles {
+        compare_bus(&cpu.bus.cycles, expected_cycles)?;
+    }
+    Ok(())
+}
+
+// オペコードごとに失敗した数と最初の失敗を返す
+fn run_file(path: &Path, variant: Variant, compare_cycles: bool) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let cases: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let cases = cases.as_array().ok_or("not an array")?;
+
+    let mut failed = 0;
+    let mut first = None;
+    for case in cases {
+        if let Err(e) = run_case(case, variant, compare_cycles) {
+            failed += 1;
+            if first.is_none() {
+                first = Some(format!(
+                    "\"{}\": {}",
+                    case["name"].as_str().unwrap_or("?"),
+                    e
+                ));
+            }
+        }
+    }
+    match first {
+        None => Ok(()),
+        Some(first) => Err(format!(
+            "{}/{} failed, first {}",
+            failed,
+            cases.len(),
+            first
+        )),
+    }
+}
+
+// テストベクタは大きいのでリポジトリには入れていない。
+// ディレクトリを置いて cargo test --test processor_tests -- --ignored で流す
+#[test]
+#[ignore = "needs tests/processor_tests or PROCESSOR_TESTS_DIR"]
+fn test_processor_tests() {
+    let dir = std::env::var("PROCESSOR_TESTS_DIR").unwrap_or(DEFAULT_DIR.to_string());
+    assert!(Path::new(&dir).is_dir(), "{} がない", dir);
+    let variant: Variant = std::env::var("PROCESSOR_TESTS_VARIANT")
+        .map(|name| name.parse().unwrap())
+        .unwrap_or(Variant::Ricoh2A03);
+    let compare_cycles = std::env::var("PROCESSOR_TESTS_CYCLES").is_ok();
+    let only: Option<Vec<u8>> = std::env::var("PROCESSOR_TESTS_OPCODES").ok().map(|list| {
+        list.split(',')
+            .map(|code| u8::from_str_radix(code.trim(), 16).unwrap())
+            .collect()
+    });
+
+    let mut failures = vec![];
+    for op in variant.opcodes() {
+        // JAM は止まるだけなのでバスアクセスが合わない
+        if op.name == "*JAM" {
+            continue;
+        }
+        if let Some(only) = &only {
+            if !only.contains(&op.code) {
+                continue;
+            }
+        }
+        let path = Path::new(&dir).join(format!("{:02x}.json", op.code));
+        if !path.exists() {
+            continue;
+        }
+        if let Err(e) = run_file(&path, variant, compare_cycles) {
+            failures.push(format!("{:02X} {}: {}", op.code, op.name, e));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// ハーネス自体のテスト (テストベクタがなくても動く)
+#[test]
+fn test_run_case() {
+    // LDA ($10),Y (ページをまたぐ)
+    let case: Value = serde_json::from_str(
+        r#"{
+            "name": "b1 10",
+            "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 1, "p": 36,
+                        "ram": [[512, 177], [513, 16], [16, 255], [17, 18], [4864, 128]]},
+            "final": {"pc": 514, "s": 253, "a": 128, "x": 0, "y": 1, "p": 164,
+                      "ram": [[512, 177], [513, 16], [16, 255], [17, 18], [4864, 128]]},
+            "cycles": [[512, 177, "read"], [513, 16, "read"], [16, 255, "read"],
+                       [17, 18, "read"], [4608, 0, "read"], [4864, 128, "read"]]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(run_case(&case, Variant::Ricoh2A03, true), Ok(()));
+
+    let mut wrong = case.clone();
+    wrong["final"]["a"] = Value::from(0x7F);
+    assert_eq!(
+        run_case(&wrong, Variant::Ricoh2A03, false),
+        Err("a: expected 7F, got 80".to_string())
+    );
+
+    let mut wrong = case.clone();
+    wrong["cycles"][4][0] = Value::from(0x1300);
+    assert_eq!(
+        run_case(&wrong, Variant::Ricoh2A03, true),
+        Err("cycle 5: expected (4864, 0, \"read\"), got Some((4608, 0, \"read\"))".to_string())
+    );
+
+    // 期待より多いバスアクセスも違いとして扱う
+    let expected = case["cycles"].as_array().unwrap();
+    let mut actual = expected
+        .iter()
+        .map(|cycle| {
+            (
+                cycle[0].as_u64().unwrap() as u16,
+                cycle[1].as_u64().unwrap() as u8,
+                "read",
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(compare_bus(&actual, expected), Ok(()));
+    actual.push((0x1300, 0x80, "read"));
+    assert_eq!(
+        compare_bus(&actual, expected),
+        Err("bus accesses: expected 6, got 7".to_string())
+    );
+}